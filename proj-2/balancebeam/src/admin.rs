@@ -0,0 +1,328 @@
+use crate::circuit_breaker::CircuitState;
+use crate::config::{UpstreamConfig, DEFAULT_POOL};
+use crate::metrics::UpstreamGauges;
+use crate::stream::HttpStream;
+use crate::{request, response, ConfigLock, ProxyState};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// How an operator has asked balancebeam to treat an upstream, independently of its health.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamStatus {
+    /// In the rotation whenever it is healthy
+    #[default]
+    Enabled,
+    /// Out of the rotation for new requests, but requests that are already being forwarded to it
+    /// are allowed to finish
+    Draining,
+    /// Out of the rotation, and requests and tunnels that are still being forwarded to it are cut
+    /// off, closing their client connections
+    Disabled,
+}
+
+/// One entry in the GET /upstreams listing
+#[derive(Serialize)]
+struct UpstreamInfo {
+    address: String,
+    /// Pool the upstream belongs to
+    pool: String,
+    /// Whether the upstream is currently in live_upstream_addresses
+    live: bool,
+    status: UpstreamStatus,
+    /// Whether the circuit breaker has ejected the upstream
+    circuit: CircuitState,
+    /// Number of requests currently being forwarded to the upstream
+    active_connections: usize,
+    /// Number of idle keep-alive connections to the upstream in the pool
+    idle_connections: usize,
+}
+
+#[derive(Serialize)]
+struct UpstreamList {
+    upstreams: Vec<UpstreamInfo>,
+    live_upstreams: Vec<String>,
+}
+
+/// Body of a POST /upstreams request
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddUpstream {
+    address: String,
+    #[serde(default)]
+    weight: Option<usize>,
+    #[serde(default)]
+    pool: Option<String>,
+}
+
+/// Accepts connections on the admin listener. Changes made through the admin API take effect by
+/// swapping a new ProxyState into state_sender, the same way a config reload does (and holding
+/// config_lock while they do, the same way too).
+pub async fn serve(
+    listener: TcpListener,
+    state_sender: watch::Sender<Arc<ProxyState>>,
+    config_lock: ConfigLock,
+) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let state_sender = state_sender.clone();
+            let config_lock = config_lock.clone();
+            tokio::spawn(async move {
+                handle_connection(stream, &state_sender, &config_lock).await;
+            });
+        }
+    }
+}
+
+async fn handle_connection(
+    conn: TcpStream,
+    state_sender: &watch::Sender<Arc<ProxyState>>,
+    config_lock: &ConfigLock,
+) {
+    let mut conn = HttpStream::new(conn);
+    loop {
+        let request = match request::read_from_stream(&mut conn).await {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) => return,
+            Err(error) => {
+                log::debug!("Error reading admin request: {}", error);
+                return;
+            }
+        };
+        let response = handle_request(&request, state_sender, config_lock).await;
+        log::info!(
+            "admin: {} -> {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+async fn handle_request(
+    request: &http::Request<Vec<u8>>,
+    state_sender: &watch::Sender<Arc<ProxyState>>,
+    config_lock: &ConfigLock,
+) -> http::Response<Vec<u8>> {
+    let path = request.uri().path().trim_end_matches('/');
+    let Some(segments) = path
+        .split('/')
+        .skip(1)
+        .map(percent_decode)
+        .collect::<Option<Vec<String>>>()
+    else {
+        return error_response(http::StatusCode::BAD_REQUEST, "malformed path");
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let method = request.method();
+
+    match segments.as_slice() {
+        ["metrics"] if method == http::Method::GET => {
+            let state = state_sender.borrow().clone();
+            metrics_response(&state).await
+        }
+        ["upstreams"] if method == http::Method::GET => {
+            let state = state_sender.borrow().clone();
+            json_response(http::StatusCode::OK, &list_upstreams(&state).await)
+        }
+        ["upstreams"] if method == http::Method::POST => {
+            let body: AddUpstream = match serde_json::from_slice(request.body()) {
+                Ok(body) => body,
+                Err(err) => return error_response(http::StatusCode::BAD_REQUEST, &err.to_string()),
+            };
+            let added = update_config(state_sender, config_lock, |config| {
+                if config.upstream_addresses().contains(&body.address) {
+                    return Err((
+                        http::StatusCode::CONFLICT,
+                        "upstream already exists".to_string(),
+                    ));
+                }
+                config.upstreams.push(UpstreamConfig {
+                    address: body.address.clone(),
+                    weight: body.weight.unwrap_or(1),
+                    health_check: Default::default(),
+                    pool: body
+                        .pool
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_POOL.to_string()),
+                });
+                Ok(())
+            })
+            .await;
+            if let Err((status, message)) = added {
+                return error_response(status, &message);
+            }
+            log::info!("admin: added upstream {}", body.address);
+            let state = state_sender.borrow().clone();
+            json_response(http::StatusCode::CREATED, &list_upstreams(&state).await)
+        }
+        ["upstreams", address] if method == http::Method::DELETE => {
+            let removed = update_config(state_sender, config_lock, |config| {
+                let len = config.upstreams.len();
+                config
+                    .upstreams
+                    .retain(|upstream| upstream.address != *address);
+                if config.upstreams.len() == len {
+                    Err((http::StatusCode::NOT_FOUND, "no such upstream".to_string()))
+                } else if config.upstreams.is_empty() {
+                    Err((
+                        http::StatusCode::CONFLICT,
+                        "cannot remove the last upstream".to_string(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            })
+            .await;
+            if let Err((status, message)) = removed {
+                return error_response(status, &message);
+            }
+            log::info!("admin: removed upstream {}", address);
+            let state = state_sender.borrow().clone();
+            json_response(http::StatusCode::OK, &list_upstreams(&state).await)
+        }
+        ["upstreams", address, action] if method == http::Method::POST => {
+            let status = match *action {
+                "enable" => UpstreamStatus::Enabled,
+                "drain" => UpstreamStatus::Draining,
+                "disable" => UpstreamStatus::Disabled,
+                _ => return error_response(http::StatusCode::NOT_FOUND, "unknown action"),
+            };
+            if !is_known_upstream(state_sender, address) {
+                return error_response(http::StatusCode::NOT_FOUND, "no such upstream");
+            }
+            let state = state_sender.borrow().clone();
+            state.set_upstream_status(address, status).await;
+            log::info!("admin: upstream {} is now {:?}", address, status);
+            json_response(http::StatusCode::OK, &list_upstreams(&state).await)
+        }
+        ["metrics"] | ["upstreams"] | ["upstreams", _] | ["upstreams", _, _] => {
+            error_response(http::StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => error_response(http::StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+async fn list_upstreams(state: &ProxyState) -> UpstreamList {
+    let live_upstreams = state.live_upstream_addresses.read().await.clone();
+    let upstreams = state
+        .upstream_addresses
+        .iter()
+        .map(|address| UpstreamInfo {
+            address: address.clone(),
+            pool: state.upstream_pools[address].clone(),
+            live: live_upstreams.contains(address),
+            status: state.upstream_status(address),
+            circuit: state.circuit_breaker.state(address),
+            active_connections: state.active_connections.get(address),
+            idle_connections: state.connection_pool.idle_count(address),
+        })
+        .collect();
+    UpstreamList {
+        upstreams,
+        live_upstreams,
+    }
+}
+
+async fn metrics_response(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let live_upstreams = state.live_upstream_addresses.read().await.clone();
+    let upstreams: Vec<UpstreamGauges> = state
+        .upstream_addresses
+        .iter()
+        .map(|address| UpstreamGauges {
+            address: address.clone(),
+            live: live_upstreams.contains(address),
+            active_connections: state.active_connections.get(address),
+            idle_connections: state.connection_pool.idle_count(address),
+        })
+        .collect();
+    let body = state.metrics.render(&upstreams).into_bytes();
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+/// Decodes the %XX escapes in a path segment, so that upstreams such as https://host:port can be
+/// named as https%3A%2F%2Fhost%3Aport. Returns None if an escape is malformed.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            decoded.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn is_known_upstream(state_sender: &watch::Sender<Arc<ProxyState>>, address: &str) -> bool {
+    state_sender
+        .borrow()
+        .upstream_addresses
+        .iter()
+        .any(|upstream| upstream == address)
+}
+
+/// Applies a change to the current config and swaps in a ProxyState built from the changed config.
+/// config_lock is held throughout, so that a concurrent change or reload can't undo this one.
+/// If change refuses the change, the status and message it returns are passed on; if the changed
+/// config is invalid or the state can't be built from it, the result is a 400 saying why.
+async fn update_config<F>(
+    state_sender: &watch::Sender<Arc<ProxyState>>,
+    config_lock: &ConfigLock,
+    change: F,
+) -> Result<(), (http::StatusCode, String)>
+where
+    F: FnOnce(&mut crate::config::Config) -> Result<(), (http::StatusCode, String)>,
+{
+    let _config_lock = config_lock.lock().await;
+    let previous = state_sender.borrow().clone();
+    let mut config = previous.config.clone();
+    change(&mut config)?;
+    if let Err(err) = config.validate() {
+        log::warn!("admin: refusing change: {}", err);
+        return Err((http::StatusCode::BAD_REQUEST, err.to_string()));
+    }
+    match ProxyState::from_config(&config, Some(&previous)).await {
+        Ok(new_state) => {
+            state_sender.send_replace(Arc::new(new_state));
+            Ok(())
+        }
+        Err(err) => {
+            log::error!("admin: could not apply change: {}", err);
+            Err((http::StatusCode::BAD_REQUEST, err.to_string()))
+        }
+    }
+}
+
+fn json_response<T: Serialize>(status: http::StatusCode, value: &T) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(value).unwrap();
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+fn error_response(status: http::StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
@@ -0,0 +1,95 @@
+use crate::balancer::hash_of;
+use crate::config::SessionAffinityConfig;
+
+/// What a request is pinned to an upstream by
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    /// The token of the upstream named by the request's affinity cookie, or None if it didn't have
+    /// one (in which case it gets one with the response)
+    Cookie(Option<String>),
+    /// The hash of the application cookie or header value that the request carries. Requests with
+    /// the same hash go to the same upstream for as long as it is available.
+    Hash(u64),
+}
+
+/// Works out which upstream each request is pinned to, according to the [session_affinity]
+/// settings
+pub struct SessionAffinity {
+    config: SessionAffinityConfig,
+}
+
+impl SessionAffinity {
+    pub fn new(config: &SessionAffinityConfig) -> SessionAffinity {
+        SessionAffinity {
+            config: config.clone(),
+        }
+    }
+
+    /// Returns what the request is pinned by, or None if it should be balanced as usual
+    pub fn key(&self, request: &http::Request<Vec<u8>>) -> Option<Key> {
+        match &self.config {
+            SessionAffinityConfig::None => None,
+            SessionAffinityConfig::Cookie { name, .. } => Some(Key::Cookie(
+                cookie(request.headers(), name).map(str::to_string),
+            )),
+            SessionAffinityConfig::AppCookie { name } => {
+                cookie(request.headers(), name).map(|value| Key::Hash(hash_of(value.as_bytes())))
+            }
+            SessionAffinityConfig::Header { name } => request
+                .headers()
+                .get(name)
+                .map(|value| Key::Hash(hash_of(value.as_bytes()))),
+        }
+    }
+
+    /// Returns the Set-Cookie header that pins the client to the upstream that answered it, if the
+    /// affinity cookie it sent (if any) names a different one
+    pub fn set_cookie(&self, key: &Key, upstream: &str) -> Option<http::HeaderValue> {
+        let SessionAffinityConfig::Cookie { name, max_age } = &self.config else {
+            return None;
+        };
+        let token = token(upstream);
+        if *key == Key::Cookie(Some(token.clone())) {
+            return None;
+        }
+        let mut cookie = format!("{}={}; Path=/; HttpOnly", name, token);
+        if let Some(max_age) = max_age {
+            cookie += &format!("; Max-Age={}", max_age);
+        }
+        http::HeaderValue::from_str(&cookie).ok()
+    }
+}
+
+/// Returns the index of the upstream that a request with the given key is pinned to, out of the
+/// ones it can be sent to, or None if it should be balanced as usual. Hashes are spread over the
+/// upstreams with rendezvous hashing, so when an upstream goes away only the requests that were
+/// pinned to it move.
+pub fn choose(key: &Key, upstreams: &[String]) -> Option<usize> {
+    match key {
+        Key::Cookie(None) => None,
+        Key::Cookie(Some(pinned)) => upstreams
+            .iter()
+            .position(|upstream| token(upstream) == *pinned),
+        Key::Hash(hash) => {
+            (0..upstreams.len()).max_by_key(|&idx| hash_of(&(&upstreams[idx], hash)))
+        }
+    }
+}
+
+/// Identifies an upstream in affinity cookies without giving its address away
+fn token(upstream: &str) -> String {
+    format!("{:016x}", hash_of(upstream))
+}
+
+/// Returns the value of a cookie sent with a request
+fn cookie<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (pair_name, value) = pair.trim().split_once('=')?;
+            (pair_name == name).then_some(value)
+        })
+}
@@ -0,0 +1,248 @@
+use fnv::FnvHasher;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Number of points each upstream gets on the consistent hashing ring. More points give a more even
+/// spread of clients across upstreams at the cost of a bigger ring.
+const VIRTUAL_NODES_PER_UPSTREAM: usize = 160;
+
+/// The algorithm used to pick an upstream for each request.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Pick a live upstream uniformly at random
+    #[default]
+    Random,
+    /// Cycle through the live upstreams in order
+    RoundRobin,
+    /// Pick the upstream with the fewest active connections
+    LeastConnections,
+    /// Cycle through the live upstreams, visiting each one in proportion to its weight
+    WeightedRoundRobin,
+    /// Pick two random upstreams and use the one with fewer active connections
+    PowerOfTwoChoices,
+    /// Hash the client IP onto a ring of upstreams so that a client keeps hitting the same one
+    ConsistentHash,
+}
+
+/// Keeps track of how many requests are currently being forwarded to each upstream (i.e. how many
+/// upstream connections are busy). Strategies such as least-connections use these counts to make
+/// their decisions. The same counter is also used to count each client IP's open connections.
+#[derive(Default)]
+pub struct ConnectionCounter {
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl ConnectionCounter {
+    /// Returns the number of requests that are currently in flight to the given upstream
+    pub fn get(&self, upstream: &str) -> usize {
+        self.counts.lock().get(upstream).copied().unwrap_or(0)
+    }
+
+    /// Records a new request to the given upstream. The request is counted until the returned guard
+    /// is dropped.
+    pub fn track(self: &Arc<Self>, upstream: &str) -> ConnectionGuard {
+        *self.counts.lock().entry(upstream.to_string()).or_insert(0) += 1;
+        ConnectionGuard {
+            counter: self.clone(),
+            upstream: upstream.to_string(),
+        }
+    }
+
+    /// Like track, but only if fewer than limit are already being counted under the given key
+    pub fn try_track(self: &Arc<Self>, key: &str, limit: usize) -> Option<ConnectionGuard> {
+        let mut counts = self.counts.lock();
+        let count = counts.entry(key.to_string()).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            counter: self.clone(),
+            upstream: key.to_string(),
+        })
+    }
+}
+
+/// Decrements the connection count of an upstream when dropped.
+pub struct ConnectionGuard {
+    counter: Arc<ConnectionCounter>,
+    upstream: String,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counter.counts.lock();
+        if let Some(count) = counts.get_mut(&self.upstream) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.upstream);
+            }
+        }
+    }
+}
+
+/// A load-balancing strategy. Implementations hold whatever state they need between decisions
+/// (counters, weights, hash rings) and must be safe to share between connection tasks.
+pub trait Balancer: Send + Sync {
+    /// Picks one of the (non-empty) list of live upstreams for a request from client_ip, and
+    /// returns its index.
+    fn choose(
+        &self,
+        upstreams: &[String],
+        client_ip: IpAddr,
+        connections: &ConnectionCounter,
+    ) -> usize;
+}
+
+/// Creates the balancer for the given strategy. weights maps upstream addresses to their weight for
+/// weighted round robin; upstreams that are missing from the map get a weight of 1.
+pub fn new_balancer(strategy: Strategy, weights: HashMap<String, usize>) -> Arc<dyn Balancer> {
+    match strategy {
+        Strategy::Random => Arc::new(RandomBalancer),
+        Strategy::RoundRobin => Arc::new(RoundRobinBalancer::default()),
+        Strategy::LeastConnections => Arc::new(LeastConnectionsBalancer::default()),
+        Strategy::WeightedRoundRobin => Arc::new(WeightedRoundRobinBalancer {
+            weights,
+            current_weights: Mutex::new(HashMap::new()),
+        }),
+        Strategy::PowerOfTwoChoices => Arc::new(PowerOfTwoChoicesBalancer),
+        Strategy::ConsistentHash => Arc::new(ConsistentHashBalancer::default()),
+    }
+}
+
+struct RandomBalancer;
+
+impl Balancer for RandomBalancer {
+    fn choose(&self, upstreams: &[String], _: IpAddr, _: &ConnectionCounter) -> usize {
+        rand::thread_rng().gen_range(0..upstreams.len())
+    }
+}
+
+#[derive(Default)]
+struct RoundRobinBalancer {
+    next: AtomicUsize,
+}
+
+impl Balancer for RoundRobinBalancer {
+    fn choose(&self, upstreams: &[String], _: IpAddr, _: &ConnectionCounter) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len()
+    }
+}
+
+#[derive(Default)]
+struct LeastConnectionsBalancer {
+    /// Where to start scanning the upstream list. This rotates so that ties (e.g. when every
+    /// upstream is idle) don't all go to the first upstream.
+    offset: AtomicUsize,
+}
+
+impl Balancer for LeastConnectionsBalancer {
+    fn choose(&self, upstreams: &[String], _: IpAddr, connections: &ConnectionCounter) -> usize {
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed);
+        (0..upstreams.len())
+            .map(|i| (offset + i) % upstreams.len())
+            .min_by_key(|&idx| connections.get(&upstreams[idx]))
+            .unwrap()
+    }
+}
+
+/// Smooth weighted round robin (the algorithm nginx uses): every upstream's current weight grows by
+/// its configured weight on each pick, and the winner's current weight is reduced by the total.
+/// This interleaves upstreams instead of sending bursts to the heaviest one.
+struct WeightedRoundRobinBalancer {
+    weights: HashMap<String, usize>,
+    current_weights: Mutex<HashMap<String, i64>>,
+}
+
+impl Balancer for WeightedRoundRobinBalancer {
+    fn choose(&self, upstreams: &[String], _: IpAddr, _: &ConnectionCounter) -> usize {
+        let mut current_weights = self.current_weights.lock();
+        // Forget upstreams that have left the rotation so that they start fresh if they come back
+        current_weights.retain(|upstream, _| upstreams.contains(upstream));
+
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for (idx, upstream) in upstreams.iter().enumerate() {
+            let weight = *self.weights.get(upstream).unwrap_or(&1) as i64;
+            let current = current_weights.entry(upstream.clone()).or_insert(0);
+            *current += weight;
+            total += weight;
+            if best.is_none_or(|(_, best_weight)| *current > best_weight) {
+                best = Some((idx, *current));
+            }
+        }
+        let (idx, _) = best.unwrap();
+        *current_weights.get_mut(&upstreams[idx]).unwrap() -= total;
+        idx
+    }
+}
+
+struct PowerOfTwoChoicesBalancer;
+
+impl Balancer for PowerOfTwoChoicesBalancer {
+    fn choose(&self, upstreams: &[String], _: IpAddr, connections: &ConnectionCounter) -> usize {
+        if upstreams.len() == 1 {
+            return 0;
+        }
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..upstreams.len());
+        // Pick a second upstream that is different from the first
+        let second = (first + rng.gen_range(1..upstreams.len())) % upstreams.len();
+        if connections.get(&upstreams[second]) < connections.get(&upstreams[first]) {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+/// Points on a consistent hashing ring, sorted by hash. Each point maps to an index into the list of
+/// upstreams the ring was built for.
+type Ring = Vec<(u64, usize)>;
+
+#[derive(Default)]
+struct ConsistentHashBalancer {
+    /// The ring built for the most recently seen list of upstreams. The live upstream list rarely
+    /// changes, so we only rebuild the ring when it does.
+    ring: Mutex<Option<(Vec<String>, Ring)>>,
+}
+
+/// Hashes a value with FNV-1a. Unlike std's DefaultHasher, whose algorithm may change between Rust
+/// releases, FNV has a fixed definition, so the ring and the affinity cookies we hand out stay the
+/// same across upgrades and between balancebeam instances.
+pub fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = FnvHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Balancer for ConsistentHashBalancer {
+    fn choose(&self, upstreams: &[String], client_ip: IpAddr, _: &ConnectionCounter) -> usize {
+        let mut ring = self.ring.lock();
+        if ring
+            .as_ref()
+            .is_none_or(|(built_for, _)| built_for != upstreams)
+        {
+            let mut points = Vec::with_capacity(upstreams.len() * VIRTUAL_NODES_PER_UPSTREAM);
+            for (idx, upstream) in upstreams.iter().enumerate() {
+                for replica in 0..VIRTUAL_NODES_PER_UPSTREAM {
+                    points.push((hash_of(&(upstream, replica)), idx));
+                }
+            }
+            points.sort_unstable();
+            *ring = Some((upstreams.to_vec(), points));
+        }
+        let (_, points) = ring.as_ref().unwrap();
+
+        // Walk clockwise from the client's position to the next point on the ring
+        let client_hash = hash_of(&client_ip);
+        let pos = points.partition_point(|&(point, _)| point < client_hash);
+        points[pos % points.len()].1
+    }
+}
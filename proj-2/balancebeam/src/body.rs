@@ -0,0 +1,317 @@
+use crate::stream::HttpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the buffer used to copy bodies. At most this much of a body is held in memory at a time,
+/// no matter how big the body is.
+const COPY_BUFFER_SIZE: usize = 16384;
+/// Maximum length of a chunk-size line (including any chunk extensions) or a trailer field
+const MAX_LINE_SIZE: usize = 4096;
+/// Maximum total size of the trailer fields after a chunked body
+const MAX_TRAILERS_SIZE: usize = 8000;
+
+/// How the end of a message body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    /// The message has no body
+    Empty,
+    /// The body is exactly this many bytes long (from the Content-Length header)
+    Fixed(usize),
+    /// The body continues until the sender closes the connection. Only responses can be delimited
+    /// this way.
+    UntilClose,
+    /// The body is sent in chunks, each preceded by its size, and ends with a zero-sized chunk
+    /// optionally followed by trailer fields (Transfer-Encoding: chunked)
+    Chunked,
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    /// Encountered an I/O error when reading the body from the sender
+    ReadError(std::io::Error),
+    /// The sender hung up before sending the whole body. Contains the number of bytes that were
+    /// copied before it hung up
+    Truncated(usize),
+    /// Encountered an I/O error when writing the body to the receiver
+    WriteError(std::io::Error),
+    /// The body is bigger than the maximum size we were asked to enforce
+    TooLarge,
+    /// The body is chunked, but a chunk-size line, the end of a chunk, or a trailer field is
+    /// malformed
+    InvalidChunk,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ReadError(err) => write!(f, "error reading body: {}", err),
+            Error::Truncated(bytes_copied) => {
+                write!(f, "body ended early after {} bytes", bytes_copied)
+            }
+            Error::WriteError(err) => write!(f, "error writing body: {}", err),
+            Error::TooLarge => write!(f, "body too large"),
+            Error::InvalidChunk => write!(f, "malformed chunked encoding"),
+        }
+    }
+}
+
+/// Looks at the Transfer-Encoding header of a message. Returns None if there is no such header,
+/// Some(true) if chunked is the last (outermost) coding applied, or Some(false) otherwise.
+pub fn is_chunked(headers: &http::HeaderMap) -> Option<bool> {
+    let mut last_coding = None;
+    for value in headers.get_all("transfer-encoding") {
+        let value = value.to_str().unwrap_or("");
+        last_coding = value.split(',').map(str::trim).next_back();
+    }
+    last_coding.map(|coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
+/// Sets the headers that say where a message's body ends to match the way it is forwarded, whatever
+/// the sender or the header rules left in them: exactly one Content-Length for a fixed-length body,
+/// and a Transfer-Encoding ending in chunked (and no Content-Length) for a chunked one. Bodies that
+/// run until the connection closes have no Content-Length. Empty bodies are left alone, since
+/// responses to HEAD requests and 304 responses describe the body they would have had.
+pub fn set_length_headers(headers: &mut http::HeaderMap, length: BodyLength) {
+    match length {
+        BodyLength::Empty => {}
+        BodyLength::Fixed(content_length) => {
+            headers.remove(http::header::TRANSFER_ENCODING);
+            headers.insert(http::header::CONTENT_LENGTH, content_length.into());
+        }
+        BodyLength::UntilClose => {
+            headers.remove(http::header::CONTENT_LENGTH);
+        }
+        BodyLength::Chunked => {
+            headers.remove(http::header::CONTENT_LENGTH);
+            if is_chunked(headers) != Some(true) {
+                headers.insert(
+                    http::header::TRANSFER_ENCODING,
+                    http::HeaderValue::from_static("chunked"),
+                );
+            }
+        }
+    }
+}
+
+/// Copies a body of the given length from one stream to another as it arrives, without buffering
+/// the whole thing. Each chunk is written out before the next one is read, so a slow receiver
+/// slows down how fast we read from the sender. If max_size is given, fails with TooLarge as soon
+/// as the body is known to be bigger than that (before anything is copied, if the length is known
+/// upfront).
+///
+/// Chunked bodies are decoded and re-encoded on the way through, so chunk extensions are dropped
+/// and chunk boundaries may move, but trailer fields are passed on. max_size counts the decoded
+/// size of a chunked body.
+///
+/// Returns the number of bytes of body data copied.
+pub async fn forward<R, W>(
+    from: &mut HttpStream<R>,
+    to: &mut W,
+    length: BodyLength,
+    max_size: Option<usize>,
+) -> Result<usize, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let bytes_copied = match length {
+        BodyLength::Empty => 0,
+        BodyLength::Fixed(content_length) => {
+            if max_size.is_some_and(|max_size| content_length > max_size) {
+                return Err(Error::TooLarge);
+            }
+            copy(from, to, Some(content_length), 0, max_size).await?
+        }
+        BodyLength::UntilClose => copy(from, to, None, 0, max_size).await?,
+        BodyLength::Chunked => copy_chunked(from, to, max_size, true).await?,
+    };
+    to.flush().await.map_err(Error::WriteError)?;
+    Ok(bytes_copied)
+}
+
+/// Copies a chunked body from one stream to another as it arrives, like forward, but decodes it on
+/// the way for receivers that don't understand chunked encoding. Trailer fields are dropped.
+///
+/// Returns the number of bytes of body data copied.
+pub async fn forward_decoded<R, W>(
+    from: &mut HttpStream<R>,
+    to: &mut W,
+    max_size: Option<usize>,
+) -> Result<usize, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let bytes_copied = copy_chunked(from, to, max_size, false).await?;
+    to.flush().await.map_err(Error::WriteError)?;
+    Ok(bytes_copied)
+}
+
+/// Reads a whole body into memory. Chunked bodies are decoded, and their trailer fields dropped.
+/// This is meant for small messages (e.g. admin API requests and health check responses); max_size
+/// should always be given so that a misbehaving peer can't make us run out of memory.
+pub async fn read_to_end<R>(
+    from: &mut HttpStream<R>,
+    length: BodyLength,
+    max_size: Option<usize>,
+) -> Result<Vec<u8>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut body = Vec::new();
+    match length {
+        BodyLength::Chunked => {
+            copy_chunked(from, &mut body, max_size, false).await?;
+        }
+        length => {
+            forward(from, &mut body, length, max_size).await?;
+        }
+    }
+    Ok(body)
+}
+
+/// Copies body data until remaining bytes have been copied, or until the sender closes the
+/// connection if remaining is None. bytes_copied is how much of the body has already been copied
+/// (for chunked bodies), and is counted against max_size. Returns the new total of bytes copied.
+async fn copy<R, W>(
+    from: &mut HttpStream<R>,
+    to: &mut W,
+    remaining: Option<usize>,
+    mut bytes_copied: usize,
+    max_size: Option<usize>,
+) -> Result<usize, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let end = remaining.map(|remaining| bytes_copied + remaining);
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    while end.is_none_or(|end| bytes_copied < end) {
+        // Don't read past the end of the body; anything after it belongs to the next message
+        let want = match end {
+            Some(end) => std::cmp::min(buffer.len(), end - bytes_copied),
+            None => buffer.len(),
+        };
+        let bytes_read = from
+            .read(&mut buffer[..want])
+            .await
+            .map_err(Error::ReadError)?;
+        if bytes_read == 0 {
+            if end.is_none() {
+                // The sender closed the connection, which marks the end of the body
+                break;
+            }
+            return Err(Error::Truncated(bytes_copied));
+        }
+        bytes_copied += bytes_read;
+        if max_size.is_some_and(|max_size| bytes_copied > max_size) {
+            return Err(Error::TooLarge);
+        }
+        to.write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::WriteError)?;
+    }
+    Ok(bytes_copied)
+}
+
+/// Decodes a chunked body, writing the data in it to the receiver. If reencode is true, the data is
+/// written in chunked form, along with the trailer fields; otherwise only the data is written.
+/// Returns the number of bytes of data copied.
+async fn copy_chunked<R, W>(
+    from: &mut HttpStream<R>,
+    to: &mut W,
+    max_size: Option<usize>,
+    reencode: bool,
+) -> Result<usize, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut bytes_copied = 0;
+    loop {
+        let line = read_line(from, bytes_copied).await?;
+        let chunk_size = parse_chunk_size(&line)?;
+        if chunk_size == 0 {
+            break;
+        }
+        let total = bytes_copied
+            .checked_add(chunk_size)
+            .ok_or(Error::TooLarge)?;
+        if max_size.is_some_and(|max_size| total > max_size) {
+            return Err(Error::TooLarge);
+        }
+        if reencode {
+            to.write_all(format!("{:x}\r\n", chunk_size).as_bytes())
+                .await
+                .map_err(Error::WriteError)?;
+        }
+        bytes_copied = copy(from, to, Some(chunk_size), bytes_copied, max_size).await?;
+        // The chunk data is followed by a line break
+        if !read_line(from, bytes_copied).await?.is_empty() {
+            return Err(Error::InvalidChunk);
+        }
+        if reencode {
+            to.write_all(b"\r\n").await.map_err(Error::WriteError)?;
+        }
+    }
+
+    // The last chunk is followed by zero or more trailer fields and then a blank line
+    let mut trailers = Vec::new();
+    loop {
+        let line = read_line(from, bytes_copied).await?;
+        if line.is_empty() {
+            break;
+        }
+        if !line.contains(&b':') || trailers.len() + line.len() > MAX_TRAILERS_SIZE {
+            return Err(Error::InvalidChunk);
+        }
+        trailers.extend_from_slice(&line);
+        trailers.extend_from_slice(b"\r\n");
+    }
+    if reencode {
+        to.write_all(b"0\r\n").await.map_err(Error::WriteError)?;
+        to.write_all(&trailers).await.map_err(Error::WriteError)?;
+        to.write_all(b"\r\n").await.map_err(Error::WriteError)?;
+    }
+    Ok(bytes_copied)
+}
+
+/// Reads a line from the sender and returns it without the line break. Lines should end in CRLF,
+/// but a bare LF is accepted too. bytes_copied is only used to report how much of the body made it
+/// through if the sender hangs up.
+async fn read_line<R>(from: &mut HttpStream<R>, bytes_copied: usize) -> Result<Vec<u8>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(end) = from.buffered().iter().position(|&byte| byte == b'\n') {
+            let mut line = from.buffered()[..end].to_vec();
+            from.consume(end + 1);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return Ok(line);
+        }
+        if from.buffered().len() > MAX_LINE_SIZE {
+            return Err(Error::InvalidChunk);
+        }
+        if from.fill().await.map_err(Error::ReadError)? == 0 {
+            return Err(Error::Truncated(bytes_copied));
+        }
+    }
+}
+
+/// Parses a chunk-size line, ignoring any chunk extensions after the size
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let size = match line.iter().position(|&byte| byte == b';') {
+        Some(extensions_start) => &line[..extensions_start],
+        None => line,
+    };
+    let size = std::str::from_utf8(size)
+        .map_err(|_| Error::InvalidChunk)?
+        .trim_matches(|c| c == ' ' || c == '\t');
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Error::InvalidChunk);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| Error::InvalidChunk)
+}
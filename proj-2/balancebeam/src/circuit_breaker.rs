@@ -0,0 +1,182 @@
+use crate::config::CircuitBreakerConfig;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Whether requests are being sent to an upstream, as reported by the admin API
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Requests are sent to the upstream as usual
+    Closed,
+    /// The upstream has been ejected, and gets no requests until the ejection is over
+    Open,
+    /// The ejection is over, and a single trial request decides whether the upstream is restored
+    /// or ejected again
+    HalfOpen,
+}
+
+/// What the breaker remembers about an upstream
+struct Circuit {
+    state: CircuitState,
+    /// Failures in a row while closed
+    failures: usize,
+    /// When an open circuit goes half-open
+    ejected_until: Instant,
+    /// Number of times the upstream has been ejected since it last recovered, which decides how
+    /// long the next ejection lasts
+    ejections: u32,
+    /// When the trial request of a half-open circuit was let through, if it is still in flight
+    trial_started: Option<Instant>,
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            ejected_until: Instant::now(),
+            ejections: 0,
+            trial_started: None,
+        }
+    }
+}
+
+/// Tracks the outcome of the requests forwarded to each upstream, and ejects upstreams that fail
+/// too many of them in a row. An ejected upstream gets no requests for a while, then a single trial
+/// request: if that succeeds, the upstream is restored, and if not, it is ejected again for twice
+/// as long (up to a maximum).
+pub struct CircuitBreaker {
+    config: Mutex<CircuitBreakerConfig>,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config: Mutex::new(config),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the settings (e.g. after a reload). Upstreams that are already ejected stay ejected
+    /// for as long as they were ejected for.
+    pub fn set_config(&self, config: CircuitBreakerConfig) {
+        *self.config.lock() = config;
+    }
+
+    /// Returns how long an ejection lasts after the given number of earlier ones
+    fn ejection_time(&self, ejections: u32) -> Duration {
+        let config = self.config.lock();
+        let seconds = config
+            .base_ejection_time
+            .saturating_mul(1 << ejections.min(31))
+            .min(config.max_ejection_time);
+        Duration::from_secs(seconds)
+    }
+
+    /// How long a trial request may take before another one is let through in its place, in case
+    /// its outcome was never recorded (e.g. because the client went away)
+    fn trial_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.lock().base_ejection_time)
+    }
+
+    /// Returns whether a request could be sent to the upstream right now
+    pub fn is_available(&self, upstream: &str) -> bool {
+        let circuits = self.circuits.lock();
+        let Some(circuit) = circuits.get(upstream) else {
+            return true;
+        };
+        let now = Instant::now();
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => now >= circuit.ejected_until,
+            CircuitState::HalfOpen => circuit
+                .trial_started
+                .is_none_or(|started| now.duration_since(started) >= self.trial_timeout()),
+        }
+    }
+
+    /// Claims the right to send a request to the upstream. For a half-open upstream, only the first
+    /// caller gets to send the trial request; everyone else is turned away until its outcome is
+    /// known.
+    pub fn acquire(&self, upstream: &str) -> bool {
+        if !self.is_available(upstream) {
+            return false;
+        }
+        let mut circuits = self.circuits.lock();
+        let Some(circuit) = circuits.get_mut(upstream) else {
+            return true;
+        };
+        if circuit.state != CircuitState::Closed {
+            if circuit.state == CircuitState::Open {
+                log::info!("Sending a trial request to ejected upstream {}", upstream);
+            }
+            circuit.state = CircuitState::HalfOpen;
+            circuit.trial_started = Some(Instant::now());
+        }
+        true
+    }
+
+    /// Records a request that the upstream handled fine
+    pub fn record_success(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock();
+        let Some(circuit) = circuits.get_mut(upstream) else {
+            return;
+        };
+        if circuit.state == CircuitState::HalfOpen {
+            log::info!(
+                "Upstream {} passed its trial request, restoring it",
+                upstream
+            );
+            *circuit = Circuit::default();
+        } else {
+            circuit.failures = 0;
+        }
+    }
+
+    /// Records a request that the upstream failed. Returns whether this ejected the upstream.
+    pub fn record_failure(&self, upstream: &str) -> bool {
+        let mut circuits = self.circuits.lock();
+        let circuit = circuits.entry(upstream.to_string()).or_default();
+        match circuit.state {
+            CircuitState::Open => return false,
+            CircuitState::Closed => {
+                circuit.failures += 1;
+                if circuit.failures < self.config.lock().consecutive_failures {
+                    return false;
+                }
+            }
+            CircuitState::HalfOpen => {}
+        }
+        let ejection_time = self.ejection_time(circuit.ejections);
+        log::warn!(
+            "Ejecting upstream {} for {}s after {} failure(s) in a row",
+            upstream,
+            ejection_time.as_secs(),
+            circuit.failures.max(1)
+        );
+        circuit.state = CircuitState::Open;
+        circuit.failures = 0;
+        circuit.ejected_until = Instant::now() + ejection_time;
+        circuit.ejections = circuit.ejections.saturating_add(1);
+        circuit.trial_started = None;
+        true
+    }
+
+    /// Returns the state of an upstream's circuit
+    pub fn state(&self, upstream: &str) -> CircuitState {
+        self.circuits
+            .lock()
+            .get(upstream)
+            .map_or(CircuitState::Closed, |circuit| circuit.state)
+    }
+
+    /// Forgets about upstreams that are no longer configured
+    pub fn retain_upstreams(&self, upstreams: &[String]) {
+        self.circuits
+            .lock()
+            .retain(|upstream, _| upstreams.contains(upstream));
+    }
+}
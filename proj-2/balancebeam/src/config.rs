@@ -0,0 +1,916 @@
+use crate::balancer::Strategy;
+use crate::rate_limit::{Algorithm, Key, Limit};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Pool of the upstreams that don't name one, which gets the requests that no route matches
+pub const DEFAULT_POOL: &str = "default";
+
+/// The full configuration of balancebeam. This can either be built from command-line flags or
+/// loaded from a TOML/YAML file passed with --config, in which case it is reloaded whenever the file
+/// changes or the process receives SIGHUP.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// IP/port pairs to accept client connections on
+    #[serde(default)]
+    pub listeners: Vec<String>,
+    /// Serve TLS on all listeners, instead of plaintext HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// IP/port pair for the admin API. The admin API is disabled if this is not set. Changes to
+    /// this setting only take effect after a restart.
+    #[serde(default)]
+    pub admin_listener: Option<String>,
+    /// Whether client connections carry HTTP requests or arbitrary TCP traffic
+    #[serde(default)]
+    pub mode: Mode,
+    /// Upstream servers to forward requests to
+    pub upstreams: Vec<UpstreamConfig>,
+    /// Which pool of upstreams each request goes to
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub upstream_tls: UpstreamTlsConfig,
+    /// Load-balancing strategy used to pick an upstream for each request
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub session_affinity: SessionAffinityConfig,
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub headers: HeadersConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub connection_pool: ConnectionPoolConfig,
+    /// Reject requests with a body bigger than this many bytes with 413 Payload Too Large. Request
+    /// bodies are streamed to the upstream, so there is no limit if this is not set.
+    #[serde(default)]
+    pub max_request_body_size: Option<usize>,
+}
+
+/// What balancebeam proxies
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Parse HTTP requests and forward each one to an upstream
+    #[default]
+    Http,
+    /// Pick an upstream for each client connection and copy bytes between them without parsing
+    /// them
+    Tcp,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// host:port to connect to. Prefix with https:// to connect over TLS.
+    pub address: String,
+    /// Relative share of traffic for weighted round robin
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+    /// Pool the upstream belongs to. Requests are only balanced between the upstreams of the pool
+    /// they are routed to.
+    #[serde(default = "default_pool")]
+    pub pool: String,
+    /// Health check settings for this upstream that differ from the [health_check] section
+    #[serde(default)]
+    pub health_check: HealthCheckOverrides,
+}
+
+/// Sends the requests that match all of the given conditions to a pool of upstreams. Routes are
+/// tried in order, and requests that match none of them go to the default pool.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Pool of upstreams to send matching requests to
+    pub pool: String,
+    /// Host the request is for (ignoring case and any port). "*.example.com" matches any
+    /// subdomain of example.com.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Start of the request path
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    /// Regular expression that the request path (without the query string) has to match
+    #[serde(default)]
+    pub path_regex: Option<String>,
+    /// Request methods to match (any method if empty)
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Headers that the request has to have, with these exact values
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Remove path_prefix from the start of the path before forwarding the request
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Replace the part of the path that path_regex matched with this before forwarding the
+    /// request. $1, $name etc. refer to the regex's capture groups.
+    #[serde(default)]
+    pub rewrite_path: Option<String>,
+}
+
+impl RouteConfig {
+    fn validate(&self, upstreams: &[UpstreamConfig]) -> Result<(), Error> {
+        if !upstreams.iter().any(|upstream| upstream.pool == self.pool) {
+            return Err(Error::Invalid(format!(
+                "route to pool {} which has no upstreams",
+                self.pool
+            )));
+        }
+        if let Some(path_regex) = &self.path_regex {
+            regex::Regex::new(path_regex).map_err(|err| {
+                Error::Invalid(format!("invalid route path_regex {}: {}", path_regex, err))
+            })?;
+        }
+        for method in &self.methods {
+            if http::Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(Error::Invalid(format!("invalid route method {}", method)));
+            }
+        }
+        for name in self.headers.keys() {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(Error::Invalid(format!(
+                    "invalid route header name {}",
+                    name
+                )));
+            }
+        }
+        if self.strip_prefix && self.path_prefix.is_none() {
+            return Err(Error::Invalid(
+                "routes with strip_prefix need a path_prefix".to_string(),
+            ));
+        }
+        if self.rewrite_path.is_some() && self.path_regex.is_none() {
+            return Err(Error::Invalid(
+                "routes with rewrite_path need a path_regex".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How to connect to upstreams whose address starts with https://
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM file of CA certificates to verify upstream certificates with, instead of the Mozilla
+    /// root certificates
+    pub ca_bundle: Option<PathBuf>,
+    /// Accept any certificate from upstreams. Only meant for testing!
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Perform active health checks on this interval (in seconds)
+    pub interval: usize,
+    /// Path to send request to for active health checks
+    pub path: String,
+    /// Method to send the request with
+    pub method: String,
+    /// Extra headers to send with the request. The Host header defaults to the upstream address.
+    pub headers: BTreeMap<String, String>,
+    /// Response statuses that count as healthy, each either a single code (200), a range
+    /// ("200-399") or a class ("2xx")
+    pub expected_status: Vec<StatusRange>,
+    /// Regular expression that the response body has to match to count as healthy
+    pub body: Option<String>,
+    /// How long to wait for the whole check, from connecting to reading the response body (in
+    /// milliseconds)
+    pub timeout_ms: u64,
+    /// Number of checks in a row that an unhealthy upstream has to pass to be put back in the
+    /// rotation
+    pub rise: usize,
+    /// Number of checks in a row that a healthy upstream has to fail to be taken out of the
+    /// rotation
+    pub fall: usize,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            interval: 10,
+            path: "/".to_string(),
+            method: "GET".to_string(),
+            headers: BTreeMap::new(),
+            expected_status: vec![StatusRange {
+                start: 200,
+                end: 200,
+            }],
+            body: None,
+            timeout_ms: 2000,
+            rise: 1,
+            fall: 1,
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    /// Returns these settings with the ones an upstream overrides replaced
+    pub fn with_overrides(&self, overrides: &HealthCheckOverrides) -> HealthCheckConfig {
+        let overrides = overrides.clone();
+        HealthCheckConfig {
+            interval: self.interval,
+            path: overrides.path.unwrap_or_else(|| self.path.clone()),
+            method: overrides.method.unwrap_or_else(|| self.method.clone()),
+            headers: overrides.headers.unwrap_or_else(|| self.headers.clone()),
+            expected_status: overrides
+                .expected_status
+                .unwrap_or_else(|| self.expected_status.clone()),
+            body: overrides.body.or_else(|| self.body.clone()),
+            timeout_ms: overrides.timeout_ms.unwrap_or(self.timeout_ms),
+            rise: overrides.rise.unwrap_or(self.rise),
+            fall: overrides.fall.unwrap_or(self.fall),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if http::Method::from_bytes(self.method.as_bytes()).is_err() {
+            return Err(Error::Invalid(format!(
+                "invalid health check method {}",
+                self.method
+            )));
+        }
+        if !self.path.starts_with('/') || self.path.parse::<http::Uri>().is_err() {
+            return Err(Error::Invalid(format!(
+                "invalid health check path {}",
+                self.path
+            )));
+        }
+        for (name, value) in &self.headers {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || http::HeaderValue::from_str(value).is_err()
+            {
+                return Err(Error::Invalid(format!(
+                    "invalid health check header {}: {}",
+                    name, value
+                )));
+            }
+        }
+        if self.expected_status.is_empty() {
+            return Err(Error::Invalid(
+                "health checks need at least one expected status".to_string(),
+            ));
+        }
+        if let Some(body) = &self.body {
+            regex::Regex::new(body).map_err(|err| {
+                Error::Invalid(format!("invalid health check body pattern: {}", err))
+            })?;
+        }
+        if self.timeout_ms == 0 || self.rise == 0 || self.fall == 0 {
+            return Err(Error::Invalid(
+                "health check timeout_ms, rise and fall must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Health check settings for a single upstream. Settings that aren't given are taken from the
+/// [health_check] section.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckOverrides {
+    pub path: Option<String>,
+    pub method: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub expected_status: Option<Vec<StatusRange>>,
+    pub body: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub rise: Option<usize>,
+    pub fall: Option<usize>,
+}
+
+/// An inclusive range of HTTP status codes
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "StatusSpec")]
+pub struct StatusRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl StatusRange {
+    pub fn contains(&self, status: http::StatusCode) -> bool {
+        (self.start..=self.end).contains(&status.as_u16())
+    }
+}
+
+/// How a status range can be written in a config file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusSpec {
+    Code(u16),
+    Text(String),
+}
+
+impl TryFrom<StatusSpec> for StatusRange {
+    type Error = String;
+
+    fn try_from(spec: StatusSpec) -> Result<StatusRange, String> {
+        let invalid = |text: &str| format!("invalid status range {:?}", text);
+        let (start, end) = match spec {
+            StatusSpec::Code(code) => (code, code),
+            StatusSpec::Text(text) => {
+                if let Some(class) = text.strip_suffix("xx") {
+                    let class: u16 = class.parse().map_err(|_| invalid(&text))?;
+                    (class * 100, class * 100 + 99)
+                } else if let Some((start, end)) = text.split_once('-') {
+                    (
+                        start.trim().parse().map_err(|_| invalid(&text))?,
+                        end.trim().parse().map_err(|_| invalid(&text))?,
+                    )
+                } else {
+                    let code = text.trim().parse().map_err(|_| invalid(&text))?;
+                    (code, code)
+                }
+            }
+        };
+        if !(100..=999).contains(&start) || !(100..=999).contains(&end) || start > end {
+            return Err(format!("invalid status range {}-{}", start, end));
+        }
+        Ok(StatusRange { start, end })
+    }
+}
+
+/// Passive health checking: upstreams that fail too many requests in a row are ejected from the
+/// rotation for a while, without waiting for the active health checks to notice
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Number of failures in a row (5xx responses, connection errors and timeouts) that eject an
+    /// upstream
+    pub consecutive_failures: usize,
+    /// How long an upstream is ejected for the first time (in seconds). Each time it is ejected
+    /// again without having recovered in between, this doubles.
+    pub base_ejection_time: u64,
+    /// Longest an upstream is ever ejected for (in seconds)
+    pub max_ejection_time: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            consecutive_failures: 5,
+            base_ejection_time: 30,
+            max_ejection_time: 300,
+        }
+    }
+}
+
+/// Which requests are sent again to a different upstream when the first one fails them. Only
+/// requests that are safe to send twice are retried: those with an idempotent method (GET, HEAD,
+/// PUT, DELETE, OPTIONS or TRACE), and those with one of the safe_headers.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Maximum number of times a request is retried (0 = never)
+    pub max_retries: usize,
+    /// How long to wait before the first retry (in milliseconds). This doubles with each further
+    /// retry of the same request.
+    pub backoff_ms: u64,
+    /// Upstream response statuses that are retried. Requests are always retried if they can't be
+    /// sent to the upstream, or its response can't be read.
+    pub retry_on: Vec<StatusRange>,
+    /// Requests with any of these headers are retried whatever their method is
+    pub safe_headers: Vec<String>,
+    /// Request bodies up to this many bytes are kept in memory so that they can be sent again.
+    /// Requests with bigger (or chunked) bodies are streamed to the upstream and never retried.
+    pub max_body_size: usize,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 0,
+            backoff_ms: 25,
+            retry_on: Vec::new(),
+            safe_headers: vec!["idempotency-key".to_string()],
+            max_body_size: 65536,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Maximum number of requests to accept per IP per minute (0 = unlimited)
+    pub max_requests_per_minute: usize,
+    /// Maximum number of requests to accept per IP per second (0 = unlimited). Only one of this and
+    /// max_requests_per_minute may be set.
+    pub max_requests_per_second: usize,
+    pub algorithm: Algorithm,
+    /// How many requests a client can send at once with the token bucket algorithm (defaults to
+    /// the per-minute or per-second limit)
+    pub burst: Option<usize>,
+    /// More limits, keyed on something other than the client IP or only applying to some paths.
+    /// A request has to be within every limit that applies to it.
+    pub rules: Vec<RateLimitRule>,
+    /// Where request counts are kept. Changes to this setting only take effect after a restart.
+    pub store: RateLimitStoreConfig,
+    /// Maximum number of connections each IP can have open at once (0 = unlimited). Connections
+    /// over the limit are closed straight away.
+    pub max_connections_per_ip: usize,
+}
+
+/// Where rate limit counts are kept. With a shared store, several instances of balancebeam behind
+/// the same address enforce one limit between them rather than one limit each.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RateLimitStoreConfig {
+    /// Counts are kept in memory and not shared
+    #[default]
+    Local,
+    /// Counts are kept in a Redis server (or anything else that speaks its protocol). If the server
+    /// can't be reached, requests are counted locally until it is back.
+    Redis {
+        /// host:port of the server
+        address: String,
+        /// Password to AUTH with, if the server requires one
+        #[serde(default)]
+        password: Option<String>,
+        /// How long to wait for the server before counting a request locally (in milliseconds)
+        #[serde(default = "default_store_timeout")]
+        timeout_ms: u64,
+    },
+    /// Each instance counts requests locally and regularly sends its counts to its peers over UDP.
+    /// Peers can be briefly out of date, so clients may get a little more than the limit through.
+    /// Messages aren't authenticated, so the address should only be reachable by the peers.
+    Gossip {
+        /// IP/port to send and receive counts on
+        bind: String,
+        /// Addresses that the other instances are bound to. Messages from anywhere else are ignored.
+        peers: Vec<String>,
+        /// How often to send counts that have changed to the peers (in milliseconds)
+        #[serde(default = "default_gossip_interval")]
+        interval_ms: u64,
+    },
+}
+
+impl RateLimitConfig {
+    /// Returns all of the rules to apply: the per-IP limit set directly in this section (if any),
+    /// followed by the configured rules
+    pub fn all_rules(&self) -> Vec<RateLimitRule> {
+        let per_ip = RateLimitRule {
+            max_requests_per_minute: self.max_requests_per_minute,
+            max_requests_per_second: self.max_requests_per_second,
+            algorithm: self.algorithm,
+            burst: self.burst,
+            ..Default::default()
+        };
+        per_ip
+            .limit()
+            .map(|_| per_ip)
+            .into_iter()
+            .chain(self.rules.iter().cloned())
+            .collect()
+    }
+}
+
+/// A rate limit applied separately to each group of requests with the same key
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitRule {
+    /// What requests are counted together
+    pub key: Key,
+    /// Header to key on, for key = "header" (e.g. an API key header). Requests without the header
+    /// aren't limited by this rule.
+    pub header: Option<String>,
+    /// For key = "forwarded-for": the number of trusted proxies in front of balancebeam that each
+    /// append to X-Forwarded-For. The client address is taken from that many entries from the end,
+    /// since anything before that could have been made up by the client. Defaults to 1.
+    pub trusted_hops: Option<usize>,
+    /// Only apply the limit to requests whose path starts with this
+    pub path_prefix: Option<String>,
+    /// Maximum number of requests per key per minute. Exactly one of this and
+    /// max_requests_per_second must be set.
+    pub max_requests_per_minute: usize,
+    /// Maximum number of requests per key per second
+    pub max_requests_per_second: usize,
+    pub algorithm: Algorithm,
+    /// How many requests can be sent at once with the token bucket algorithm (defaults to the
+    /// per-minute or per-second limit)
+    pub burst: Option<usize>,
+}
+
+impl RateLimitRule {
+    /// Returns the limit this rule applies, or None if no limit is set
+    pub fn limit(&self) -> Option<Limit> {
+        let (requests, period) = if self.max_requests_per_second > 0 {
+            (self.max_requests_per_second, Duration::from_secs(1))
+        } else if self.max_requests_per_minute > 0 {
+            (self.max_requests_per_minute, Duration::from_secs(60))
+        } else {
+            return None;
+        };
+        Some(Limit {
+            algorithm: self.algorithm,
+            requests,
+            period,
+            burst: self.burst.unwrap_or(requests),
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.limit().is_none() {
+            return Err(Error::Invalid(
+                "every rate limit rule needs a limit".to_string(),
+            ));
+        }
+        if self.max_requests_per_minute > 0 && self.max_requests_per_second > 0 {
+            return Err(Error::Invalid(
+                "only one of max_requests_per_minute and max_requests_per_second may be set"
+                    .to_string(),
+            ));
+        }
+        if self.burst == Some(0) {
+            return Err(Error::Invalid(
+                "rate limit burst must be at least 1".to_string(),
+            ));
+        }
+        if self.key == Key::Header
+            && self
+                .header
+                .as_ref()
+                .is_none_or(|header| http::HeaderName::from_bytes(header.as_bytes()).is_err())
+        {
+            return Err(Error::Invalid(
+                "rate limit rules with key = \"header\" need a valid header name".to_string(),
+            ));
+        }
+        if self.trusted_hops == Some(0) {
+            return Err(Error::Invalid(
+                "rate limit trusted_hops must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How long balancebeam waits on clients and upstreams before giving up on them, in milliseconds
+/// (0 = no limit). Clients that are too slow to send a request get 408 Request Timeout, and
+/// upstreams that are too slow to connect or respond get the client a 504 Gateway Timeout.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Connecting to an upstream, including the TLS handshake for https:// upstreams
+    pub connect_ms: u64,
+    /// Receiving a client's request headers once it has started sending them, and receiving an
+    /// upstream's response headers once the request has been sent to it
+    pub header_read_ms: u64,
+    /// Waiting for more of a request or response body to arrive
+    pub body_read_ms: u64,
+    /// Waiting for the next request on a keep-alive client connection
+    pub idle_ms: u64,
+    /// Handling a request from start to finish, once its headers have been received. If the
+    /// response has already started when this runs out, the client connection is closed.
+    pub request_ms: u64,
+    /// Waiting for the connections that are still open to finish after SIGTERM or SIGINT, before
+    /// exiting anyway
+    pub shutdown_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            connect_ms: 5000,
+            header_read_ms: 30000,
+            body_read_ms: 30000,
+            idle_ms: 60000,
+            request_ms: 0,
+            shutdown_ms: 30000,
+        }
+    }
+}
+
+/// Changes made to requests on their way to upstreams and to responses on their way back. Header
+/// values in the rules can refer to ${client_ip}, ${scheme}, ${host}, ${method}, ${path},
+/// ${request_id}, ${upstream} and ${pool} ("$$" for a literal "$").
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// Add X-Forwarded-Proto, X-Forwarded-Host and Forwarded (RFC 7239) headers to requests.
+    /// X-Forwarded-For is always added.
+    pub forwarded: bool,
+    /// Add balancebeam to the Via header of requests and responses
+    pub via: bool,
+    /// Header to pass the request ID to upstreams and back to clients in. A request ID the client
+    /// sent in this header is kept; otherwise a random one is generated.
+    pub request_id_header: Option<String>,
+    pub request: HeaderRulesConfig,
+    pub response: HeaderRulesConfig,
+}
+
+impl HeadersConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(name) = &self.request_id_header {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(Error::Invalid(format!(
+                    "invalid request ID header name {}",
+                    name
+                )));
+            }
+        }
+        self.request.validate()?;
+        self.response.validate()
+    }
+}
+
+/// Headers to change in requests or responses. Headers are removed first, then set, then added.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderRulesConfig {
+    /// Headers to remove
+    pub remove: Vec<String>,
+    /// Headers to set, replacing any existing values
+    pub set: BTreeMap<String, String>,
+    /// Headers to add, keeping any existing values
+    pub add: BTreeMap<String, String>,
+}
+
+impl HeaderRulesConfig {
+    fn validate(&self) -> Result<(), Error> {
+        let names = self
+            .remove
+            .iter()
+            .chain(self.set.keys())
+            .chain(self.add.keys());
+        for name in names {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(Error::Invalid(format!("invalid header name {}", name)));
+            }
+        }
+        for value in self.set.values().chain(self.add.values()) {
+            crate::headers::Template::parse(value)
+                .map_err(|err| Error::Invalid(format!("invalid header value: {}", err)))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionPoolConfig {
+    /// Maximum number of idle keep-alive connections to keep open to each upstream (0 = no pooling)
+    pub max_idle_per_upstream: usize,
+    /// Close idle upstream connections after this many seconds
+    pub idle_timeout: u64,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        ConnectionPoolConfig {
+            max_idle_per_upstream: 16,
+            idle_timeout: 60,
+        }
+    }
+}
+
+/// Keeps a client's requests on the same upstream, even across connections. If that upstream is
+/// down (or ejected, or has already failed the request), the request is balanced as usual.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum SessionAffinityConfig {
+    /// Every request is balanced on its own
+    #[default]
+    None,
+    /// balancebeam sets a cookie naming the upstream that answered, and sends requests that carry
+    /// it back to the same upstream
+    Cookie {
+        #[serde(default = "default_affinity_cookie")]
+        name: String,
+        /// How long clients should keep the cookie for (in seconds). The cookie lasts until the
+        /// browser is closed if this is not set.
+        #[serde(default)]
+        max_age: Option<u64>,
+    },
+    /// Requests with the same value for one of the application's own cookies (e.g. a session ID)
+    /// go to the same upstream. Requests without it are balanced as usual.
+    AppCookie { name: String },
+    /// Requests with the same value for a header go to the same upstream. Requests without it are
+    /// balanced as usual.
+    Header { name: String },
+}
+
+impl SessionAffinityConfig {
+    fn validate(&self) -> Result<(), Error> {
+        match self {
+            SessionAffinityConfig::None => {}
+            SessionAffinityConfig::Cookie { name, .. }
+            | SessionAffinityConfig::AppCookie { name } => {
+                // Cookie names are tokens (RFC 6265 section 4.1.1), as header names are
+                if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    return Err(Error::Invalid(format!(
+                        "invalid session affinity cookie name {}",
+                        name
+                    )));
+                }
+            }
+            SessionAffinityConfig::Header { name } => {
+                if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    return Err(Error::Invalid(format!(
+                        "invalid session affinity header name {}",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificates to present to clients. Each client gets the first one that is valid for the
+    /// server name it asks for (SNI), or the first one overall if none match. The files are read
+    /// again whenever the config is reloaded.
+    pub certificates: Vec<CertificateConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    /// PEM file containing the certificate chain, starting with the server's certificate
+    pub cert: PathBuf,
+    /// PEM file containing the certificate's private key
+    pub key: PathBuf,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+fn default_pool() -> String {
+    DEFAULT_POOL.to_string()
+}
+
+fn default_affinity_cookie() -> String {
+    "balancebeam_upstream".to_string()
+}
+
+fn default_store_timeout() -> u64 {
+    250
+}
+
+fn default_gossip_interval() -> u64 {
+    100
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file could not be read
+    Io(std::io::Error),
+    /// The config file is not valid TOML, or doesn't match the expected structure
+    Toml(toml::de::Error),
+    /// The config file is not valid YAML, or doesn't match the expected structure
+    Yaml(serde_yaml::Error),
+    /// The config file parsed, but the values in it don't make sense
+    Invalid(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "could not read config file: {}", err),
+            Error::Toml(err) => write!(f, "invalid TOML config: {}", err),
+            Error::Yaml(err) => write!(f, "invalid YAML config: {}", err),
+            Error::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl Config {
+    /// Loads a config file. Files ending in .yaml or .yml are parsed as YAML; anything else is
+    /// parsed as TOML.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path).map_err(Error::Io)?;
+        let config: Config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(Error::Yaml)?,
+            _ => toml::from_str(&contents).map_err(Error::Toml)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks for values that deserialize fine but that balancebeam can't run with
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.upstreams.is_empty() {
+            return Err(Error::Invalid(
+                "at least one upstream server must be specified".to_string(),
+            ));
+        }
+        if self.health_check.interval == 0 {
+            return Err(Error::Invalid(
+                "health check interval must be at least 1 second".to_string(),
+            ));
+        }
+        self.health_check.validate()?;
+        if self.circuit_breaker.consecutive_failures == 0
+            || self.circuit_breaker.base_ejection_time == 0
+        {
+            return Err(Error::Invalid(
+                "circuit breaker consecutive_failures and base_ejection_time must be at least 1"
+                    .to_string(),
+            ));
+        }
+        if self.circuit_breaker.max_ejection_time < self.circuit_breaker.base_ejection_time {
+            return Err(Error::Invalid(
+                "circuit breaker max_ejection_time must be at least base_ejection_time".to_string(),
+            ));
+        }
+        for name in &self.retry.safe_headers {
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(Error::Invalid(format!(
+                    "invalid retry safe header name {}",
+                    name
+                )));
+            }
+        }
+        self.headers.validate()?;
+        self.session_affinity.validate()?;
+        for upstream in &self.upstreams {
+            self.health_check
+                .with_overrides(&upstream.health_check)
+                .validate()?;
+        }
+        for rule in self.rate_limit.all_rules() {
+            rule.validate()?;
+            if rule.algorithm == Algorithm::TokenBucket
+                && self.rate_limit.store != RateLimitStoreConfig::Local
+            {
+                return Err(Error::Invalid(
+                    "only the sliding-window rate limit algorithm can use a shared store"
+                        .to_string(),
+                ));
+            }
+        }
+        match &self.rate_limit.store {
+            RateLimitStoreConfig::Redis { timeout_ms: 0, .. } => {
+                return Err(Error::Invalid(
+                    "rate limit store timeout must be at least 1 millisecond".to_string(),
+                ))
+            }
+            RateLimitStoreConfig::Gossip { interval_ms: 0, .. } => {
+                return Err(Error::Invalid(
+                    "rate limit gossip interval must be at least 1 millisecond".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        for route in &self.routes {
+            route.validate(&self.upstreams)?;
+        }
+        if self.mode == Mode::Tcp {
+            if !self.routes.is_empty() {
+                return Err(Error::Invalid(
+                    "routes can't be used in tcp mode".to_string(),
+                ));
+            }
+            if let Some(upstream) = self.upstreams.iter().find(|u| u.pool != DEFAULT_POOL) {
+                return Err(Error::Invalid(format!(
+                    "upstream {} is in pool {}, but all upstreams are in the default pool in tcp mode",
+                    upstream.address, upstream.pool
+                )));
+            }
+            if self.session_affinity != SessionAffinityConfig::None {
+                return Err(Error::Invalid(
+                    "session affinity can't be used in tcp mode".to_string(),
+                ));
+            }
+            if !self.rate_limit.all_rules().is_empty() {
+                return Err(Error::Invalid(
+                    "request rate limits can't be used in tcp mode (max_connections_per_ip can)"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if tls.certificates.is_empty() {
+                return Err(Error::Invalid(
+                    "at least one TLS certificate must be specified".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the addresses of all configured upstreams, in order
+    pub fn upstream_addresses(&self) -> Vec<String> {
+        self.upstreams
+            .iter()
+            .map(|upstream| upstream.address.clone())
+            .collect()
+    }
+}
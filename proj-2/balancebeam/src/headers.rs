@@ -0,0 +1,338 @@
+use crate::config::{HeaderRulesConfig, HeadersConfig};
+use rand::Rng;
+
+/// Pseudonym balancebeam adds to Via headers
+const VIA_PSEUDONYM: &str = "balancebeam";
+
+/// Something a header value template can refer to as ${name}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Variable {
+    ClientIp,
+    Scheme,
+    Host,
+    Method,
+    Path,
+    RequestId,
+    Upstream,
+    Pool,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Variable> {
+        Some(match name {
+            "client_ip" => Variable::ClientIp,
+            "scheme" => Variable::Scheme,
+            "host" => Variable::Host,
+            "method" => Variable::Method,
+            "path" => Variable::Path,
+            "request_id" => Variable::RequestId,
+            "upstream" => Variable::Upstream,
+            "pool" => Variable::Pool,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+/// A header value with ${variable} references in it, filled in for each request
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses a header value template. "$$" stands for a literal "$".
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(index) = rest.find('$') {
+            literal.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = after;
+                continue;
+            }
+            let Some((name, after)) = rest.strip_prefix('{').and_then(|rest| rest.split_once('}'))
+            else {
+                return Err(format!("unterminated variable in {}", template));
+            };
+            let variable = Variable::from_name(name)
+                .ok_or_else(|| format!("unknown variable ${{{}}} in {}", name, template))?;
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Variable(variable));
+            rest = after;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    fn render(&self, variables: &Variables) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.as_str(),
+                Part::Variable(variable) => variables.get(*variable),
+            })
+            .collect()
+    }
+}
+
+/// What the variables in header value templates stand for, for one request
+pub struct Variables<'a> {
+    pub client_ip: &'a str,
+    /// "http" or "https", depending on how the client connected
+    pub scheme: &'a str,
+    /// Host the request is for, from the request target or the Host header
+    pub host: &'a str,
+    pub method: &'a str,
+    /// Path (and query) of the request, after any rewriting by its route
+    pub path: &'a str,
+    pub request_id: &'a str,
+    pub upstream: &'a str,
+    pub pool: &'a str,
+}
+
+impl Variables<'_> {
+    fn get(&self, variable: Variable) -> &str {
+        match variable {
+            Variable::ClientIp => self.client_ip,
+            Variable::Scheme => self.scheme,
+            Variable::Host => self.host,
+            Variable::Method => self.method,
+            Variable::Path => self.path,
+            Variable::RequestId => self.request_id,
+            Variable::Upstream => self.upstream,
+            Variable::Pool => self.pool,
+        }
+    }
+}
+
+/// One direction's worth of header rules, built from its (validated) config
+struct Rules {
+    remove: Vec<http::HeaderName>,
+    set: Vec<(http::HeaderName, Template)>,
+    add: Vec<(http::HeaderName, Template)>,
+}
+
+impl Rules {
+    fn new(config: &HeaderRulesConfig) -> Rules {
+        let name = |name: &String| {
+            http::HeaderName::from_bytes(name.as_bytes())
+                .expect("Header rule name should have been validated")
+        };
+        let template = |value: &String| {
+            Template::parse(value).expect("Header rule value should have been validated")
+        };
+        Rules {
+            remove: config.remove.iter().map(name).collect(),
+            set: config
+                .set
+                .iter()
+                .map(|(header, value)| (name(header), template(value)))
+                .collect(),
+            add: config
+                .add
+                .iter()
+                .map(|(header, value)| (name(header), template(value)))
+                .collect(),
+        }
+    }
+
+    /// Removes headers, then sets (replacing any existing values), then adds (keeping them)
+    fn apply(&self, headers: &mut http::HeaderMap, variables: &Variables) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, template) in &self.set {
+            if let Some(value) = header_value(template, variables) {
+                headers.insert(name.clone(), value);
+            }
+        }
+        for (name, template) in &self.add {
+            if let Some(value) = header_value(template, variables) {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
+}
+
+/// Renders a template as a header value. Variables come from the request, so the result may not
+/// be a valid header value, in which case the header is skipped.
+fn header_value(template: &Template, variables: &Variables) -> Option<http::HeaderValue> {
+    let value = template.render(variables);
+    match http::HeaderValue::from_str(&value) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            log::warn!("Skipping header with invalid value {:?}", value);
+            None
+        }
+    }
+}
+
+/// Changes made to requests on their way to upstreams and to responses on their way back, built
+/// from the (validated) [headers] config
+pub struct HeaderRewriter {
+    forwarded: bool,
+    via: bool,
+    request_id_header: Option<http::HeaderName>,
+    request: Rules,
+    response: Rules,
+}
+
+impl HeaderRewriter {
+    pub fn new(config: &HeadersConfig) -> HeaderRewriter {
+        HeaderRewriter {
+            forwarded: config.forwarded,
+            via: config.via,
+            request_id_header: config.request_id_header.as_ref().map(|name| {
+                http::HeaderName::from_bytes(name.as_bytes())
+                    .expect("Request ID header should have been validated")
+            }),
+            request: Rules::new(&config.request),
+            response: Rules::new(&config.response),
+        }
+    }
+
+    /// Returns the ID of a request: the one the client passed in the request ID header, if any,
+    /// otherwise a new random one
+    pub fn request_id(&self, request: &http::Request<Vec<u8>>) -> String {
+        self.request_id_header
+            .as_ref()
+            .and_then(|name| request.headers().get(name))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{:032x}", rand::thread_rng().gen::<u128>()))
+    }
+
+    /// Adds the headers that tell the upstream about the client and the proxies in between:
+    /// X-Forwarded-For (always), X-Forwarded-Proto, X-Forwarded-Host, Forwarded and Via, along
+    /// with the request ID header
+    pub fn add_proxy_headers(&self, request: &mut http::Request<Vec<u8>>, variables: &Variables) {
+        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+        // (We're the ones connecting directly to the upstream server, so without this header, the
+        // upstream server will only know our IP, not the client's.)
+        crate::request::extend_header_value(request, "x-forwarded-for", variables.client_ip);
+        if self.forwarded {
+            let headers = request.headers_mut();
+            if let Ok(scheme) = http::HeaderValue::from_str(variables.scheme) {
+                headers.insert("x-forwarded-proto", scheme);
+            }
+            if let Ok(host) = http::HeaderValue::from_str(variables.host) {
+                if !variables.host.is_empty() {
+                    headers.insert("x-forwarded-host", host);
+                }
+            }
+            crate::request::extend_header_value(
+                request,
+                "forwarded",
+                &forwarded_element(variables),
+            );
+        }
+        if self.via {
+            let via = format!("{} {}", protocol_version(request.version()), VIA_PSEUDONYM);
+            crate::request::extend_header_value(request, "via", &via);
+        }
+        if let Some(name) = &self.request_id_header {
+            if let Ok(request_id) = http::HeaderValue::from_str(variables.request_id) {
+                request.headers_mut().insert(name.clone(), request_id);
+            }
+        }
+    }
+
+    /// Applies the [headers.request] rules to a request that is about to be sent to an upstream
+    pub fn rewrite_request(&self, request: &mut http::Request<Vec<u8>>, variables: &Variables) {
+        self.request.apply(request.headers_mut(), variables);
+    }
+
+    /// Adds balancebeam to the Via header of a response and applies the [headers.response] rules
+    pub fn rewrite_response(&self, response: &mut http::Response<Vec<u8>>, variables: &Variables) {
+        if self.via {
+            let via = format!("{} {}", protocol_version(response.version()), VIA_PSEUDONYM);
+            let value = match response.headers().get(http::header::VIA) {
+                Some(existing) => [existing.as_bytes(), b", ", via.as_bytes()].concat(),
+                None => via.into_bytes(),
+            };
+            response.headers_mut().insert(
+                http::header::VIA,
+                http::HeaderValue::from_bytes(&value).unwrap(),
+            );
+        }
+        if let Some(name) = &self.request_id_header {
+            if let Ok(request_id) = http::HeaderValue::from_str(variables.request_id) {
+                response.headers_mut().insert(name.clone(), request_id);
+            }
+        }
+        self.response.apply(response.headers_mut(), variables);
+    }
+}
+
+/// Returns the host (and port, if any) a request is for: from the request target if it is in
+/// absolute form, otherwise from the Host header. Returns an empty string if neither says.
+pub fn request_host(request: &http::Request<Vec<u8>>) -> String {
+    match request.uri().authority() {
+        Some(authority) => authority.to_string(),
+        None => request
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("")
+            .to_string(),
+    }
+}
+
+/// Returns the host a request is for, lowercased and without any port, for matching against host
+/// names. Returns None if the request doesn't say, or the host it gives is malformed.
+pub fn request_hostname(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let authority: http::uri::Authority = request_host(request).parse().ok()?;
+    Some(authority.host().to_ascii_lowercase())
+}
+
+/// Returns the received-protocol part of a Via entry for a message with this version
+fn protocol_version(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/// Returns the element this proxy adds to the Forwarded header (RFC 7239)
+fn forwarded_element(variables: &Variables) -> String {
+    // IPv6 addresses have to be quoted and bracketed
+    let client = if variables.client_ip.contains(':') {
+        format!("\"[{}]\"", variables.client_ip)
+    } else {
+        variables.client_ip.to_string()
+    };
+    let mut element = format!("for={};proto={}", client, variables.scheme);
+    if !variables.host.is_empty() {
+        element += &format!(";host={}", forwarded_value(variables.host));
+    }
+    element
+}
+
+/// Quotes a Forwarded parameter value unless it is a valid token as it is
+fn forwarded_value(value: &str) -> String {
+    let is_token = value
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::{HealthCheckConfig, Mode, StatusRange};
+use crate::stream::HttpStream;
+use crate::{request, response, upstream};
+use parking_lot::Mutex;
+use regex::Regex;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio_rustls::TlsConnector;
+
+/// An active health check for one upstream, built from its (validated) config
+pub struct HealthCheck {
+    path: String,
+    method: http::Method,
+    headers: http::HeaderMap,
+    expected_status: Vec<StatusRange>,
+    body: Option<Regex>,
+    timeout: Duration,
+    /// Only check that a connection can be made, without sending a request (in tcp mode, where
+    /// upstreams needn't speak HTTP)
+    connect_only: bool,
+    /// Number of passed checks in a row needed to become healthy again
+    pub rise: usize,
+    /// Number of failed checks in a row needed to become unhealthy
+    pub fall: usize,
+}
+
+impl HealthCheck {
+    pub fn new(config: &HealthCheckConfig, mode: Mode) -> HealthCheck {
+        HealthCheck {
+            path: config.path.clone(),
+            method: http::Method::from_bytes(config.method.as_bytes())
+                .expect("Health check method should have been validated"),
+            headers: config
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        http::HeaderName::from_bytes(name.as_bytes())
+                            .expect("Health check header should have been validated"),
+                        http::HeaderValue::from_str(value)
+                            .expect("Health check header should have been validated"),
+                    )
+                })
+                .collect(),
+            expected_status: config.expected_status.clone(),
+            body: config.body.as_ref().map(|body| {
+                Regex::new(body).expect("Health check body pattern should have been validated")
+            }),
+            timeout: Duration::from_millis(config.timeout_ms),
+            connect_only: mode == Mode::Tcp,
+            rise: config.rise,
+            fall: config.fall,
+        }
+    }
+
+    /// Sends the check to an upstream over a new connection (or in tcp mode, just opens one).
+    /// Returns why the upstream failed the check, if it did.
+    pub async fn probe(&self, upstream: &str, tls_connector: &TlsConnector) -> Result<(), String> {
+        tokio::time::timeout(self.timeout, self.send(upstream, tls_connector))
+            .await
+            .unwrap_or_else(|_| Err(format!("no response within {}ms", self.timeout.as_millis())))
+    }
+
+    async fn send(&self, upstream: &str, tls_connector: &TlsConnector) -> Result<(), String> {
+        let conn = upstream::connect(upstream, tls_connector)
+            .await
+            .map_err(|err| format!("failed to connect: {}", err))?;
+        if self.connect_only {
+            return Ok(());
+        }
+        let mut request = http::Request::builder()
+            .method(self.method.clone())
+            .uri(&self.path)
+            .header("Host", upstream::authority(upstream))
+            .body(Vec::new())
+            .unwrap();
+        for (name, value) in &self.headers {
+            request.headers_mut().insert(name, value.clone());
+        }
+        let mut conn = HttpStream::new(conn);
+        request::write_to_stream(&request, &mut conn)
+            .await
+            .map_err(|err| format!("failed to send request: {}", err))?;
+        let response = response::read_from_stream(&mut conn, request.method())
+            .await
+            .map_err(|err| format!("error reading response: {:?}", err))?;
+        if !self
+            .expected_status
+            .iter()
+            .any(|range| range.contains(response.status()))
+        {
+            return Err(format!("unexpected status {}", response.status().as_u16()));
+        }
+        if let Some(body) = &self.body {
+            if !body.is_match(&String::from_utf8_lossy(response.body())) {
+                return Err(format!("response body does not match {}", body));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether an upstream is healthy, and how many checks in a row have disagreed with that
+struct HealthState {
+    healthy: bool,
+    streak: usize,
+}
+
+/// Keeps track of which upstreams are healthy according to the active health checks. An upstream
+/// only changes between healthy and unhealthy after `fall` or `rise` checks in a row say so, so
+/// that a single bad (or lucky) check doesn't flap it in and out of the rotation. Upstreams start
+/// out healthy.
+#[derive(Default)]
+pub struct HealthStates {
+    states: Mutex<HashMap<String, HealthState>>,
+}
+
+impl HealthStates {
+    /// Records the result of a check, and returns whether the upstream is now healthy
+    pub fn record(&self, upstream: &str, passed: bool, check: &HealthCheck) -> bool {
+        let mut states = self.states.lock();
+        let state = states.entry(upstream.to_string()).or_insert(HealthState {
+            healthy: true,
+            streak: 0,
+        });
+        if passed == state.healthy {
+            state.streak = 0;
+            return state.healthy;
+        }
+        state.streak += 1;
+        let threshold = if state.healthy {
+            check.fall
+        } else {
+            check.rise
+        };
+        if state.streak >= threshold {
+            state.healthy = passed;
+            state.streak = 0;
+            if passed {
+                log::info!("Upstream {} is healthy again", upstream);
+            } else {
+                log::warn!("Upstream {} is unhealthy", upstream);
+            }
+        }
+        state.healthy
+    }
+
+    /// Forgets about upstreams that are no longer configured
+    pub fn retain_upstreams(&self, upstreams: &[String]) {
+        self.states
+            .lock()
+            .retain(|upstream, _| upstreams.contains(upstream));
+    }
+}
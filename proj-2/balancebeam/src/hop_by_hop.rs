@@ -0,0 +1,122 @@
+/// Headers that only mean something for a single connection (RFC 7230 section 6.1), so a proxy
+/// must not pass them on. Transfer-Encoding is handled separately, since bodies are re-framed as
+/// they are forwarded.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "upgrade",
+];
+
+/// Returns whether the Connection header of a message lists the given option
+pub fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .any(|value| {
+            value
+                .to_str()
+                .unwrap_or("")
+                .split(',')
+                .any(|listed| listed.trim().eq_ignore_ascii_case(option))
+        })
+}
+
+/// Returns whether the sender of a message wants to keep its connection open afterwards. HTTP/1.1
+/// connections are persistent unless closed with "Connection: close", while HTTP/1.0 ones are
+/// only persistent with "Connection: keep-alive".
+pub fn keep_alive(version: http::Version, headers: &http::HeaderMap) -> bool {
+    if has_connection_option(headers, "close") {
+        return false;
+    }
+    version >= http::Version::HTTP_11 || has_connection_option(headers, "keep-alive")
+}
+
+/// Headers that the Connection header isn't allowed to remove. Without Content-Length or
+/// Transfer-Encoding, the body would be forwarded without anything saying where it ends, so the
+/// receiver could take part of it for the next message on the connection.
+const PROTECTED_HEADERS: [http::HeaderName; 3] = [
+    http::header::CONTENT_LENGTH,
+    http::header::TRANSFER_ENCODING,
+    http::header::HOST,
+];
+
+/// Removes the hop-by-hop headers from a message, along with any other headers its Connection
+/// header names (other than the PROTECTED_HEADERS)
+pub fn remove_headers(headers: &mut http::HeaderMap) {
+    let listed: Vec<http::HeaderName> = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| http::HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .filter(|name| !PROTECTED_HEADERS.contains(name))
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Returns the protocols a message asks to switch to (or, for a 101 response, is switching to): its
+/// Upgrade header, if its Connection header lists it. Only HTTP/1.1 has upgrades.
+pub fn upgrade(version: http::Version, headers: &http::HeaderMap) -> Option<http::HeaderValue> {
+    if version < http::Version::HTTP_11 || !has_connection_option(headers, "upgrade") {
+        return None;
+    }
+    headers.get(http::header::UPGRADE).cloned()
+}
+
+/// Puts the Upgrade and Connection headers for switching to the given protocols back into a message
+/// whose hop-by-hop headers have been removed
+pub fn set_upgrade(headers: &mut http::HeaderMap, protocols: http::HeaderValue) {
+    headers.insert(http::header::UPGRADE, protocols);
+    headers.insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("upgrade"),
+    );
+}
+
+/// What a client asked of its connection in a request, taken before the hop-by-hop headers are
+/// removed
+#[derive(Clone, Copy, Debug)]
+pub struct ClientConnection {
+    /// HTTP version the client speaks
+    pub version: http::Version,
+    /// Whether the client wants to send more requests on the connection
+    pub keep_alive: bool,
+    /// Whether the client is waiting for a 100 Continue before it sends the request body
+    pub expects_continue: bool,
+}
+
+impl ClientConnection {
+    pub fn new(request: &http::Request<Vec<u8>>) -> ClientConnection {
+        // HTTP/1.0 clients don't know about 100 Continue, so their Expect headers are ignored
+        let expects_continue = request.version() >= http::Version::HTTP_11
+            && request
+                .headers()
+                .get(http::header::EXPECT)
+                .is_some_and(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+        ClientConnection {
+            version: request.version(),
+            keep_alive: keep_alive(request.version(), request.headers()),
+            expects_continue,
+        }
+    }
+}
+
+/// Returns whether a request has an Expect header that balancebeam can't meet (anything but
+/// 100-continue), which has to be answered with 417 Expectation Failed
+pub fn has_unsupported_expectation(request: &http::Request<Vec<u8>>) -> bool {
+    request.version() >= http::Version::HTTP_11
+        && request
+            .headers()
+            .get_all(http::header::EXPECT)
+            .iter()
+            .any(|expect| !expect.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
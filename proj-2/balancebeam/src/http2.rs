@@ -0,0 +1,442 @@
+use crate::body::{self, BodyLength};
+use crate::stream::HttpStream;
+use crate::{hop_by_hop, request, response, timeouts};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinSet;
+
+/// What an HTTP/2 client sends first, so that servers that don't speak HTTP/2 fail right away
+/// (RFC 7540 section 3.5)
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Size of the header at the start of every frame
+const FRAME_HEADER_SIZE: usize = 9;
+/// Smallest maximum frame size, which every peer accepts before it has seen any SETTINGS
+const MAX_FRAME_SIZE: usize = 16384;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+/// How many requests a client may have in flight on one connection
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+/// Size of the in-memory pipe each stream is passed to the proxy through
+const BRIDGE_BUFFER_SIZE: usize = 16384;
+
+/// Reads from a new connection until it is clear whether it starts with the HTTP/2 connection
+/// preface, which clients that know the server speaks HTTP/2 send straight away ("prior
+/// knowledge"). Nothing is consumed, so an HTTP/1 request can still be read afterwards.
+pub async fn has_preface<S>(conn: &mut HttpStream<S>) -> io::Result<bool>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let buffered = conn.buffered();
+        let compared = buffered.len().min(PREFACE.len());
+        if buffered[..compared] != PREFACE[..compared] {
+            return Ok(false);
+        }
+        if compared == PREFACE.len() {
+            return Ok(true);
+        }
+        if conn.fill().await? == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+/// Returns whether a request asks to upgrade its connection to HTTP/2 over cleartext (RFC 7540
+/// section 3.2), and if so, the HEADERS frame that carries it on as stream 1. Only requests without
+/// a body are upgraded, and only if their header block fits in a single frame; None means the
+/// request should be handled as plain HTTP/1.1.
+///
+/// The client's HTTP2-Settings are ignored, since it sends the same settings again in its preface.
+pub fn h2c_upgrade(request: &http::Request<Vec<u8>>) -> Option<Vec<u8>> {
+    let protocols = hop_by_hop::upgrade(request.version(), request.headers())?;
+    let wants_h2c = protocols
+        .to_str()
+        .ok()?
+        .split(',')
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"));
+    if !wants_h2c
+        || !hop_by_hop::has_connection_option(request.headers(), "http2-settings")
+        || request.headers().get_all("http2-settings").iter().count() != 1
+    {
+        return None;
+    }
+
+    let mut block = Vec::new();
+    encode_field(&mut block, b":method", request.method().as_str().as_bytes());
+    encode_field(&mut block, b":scheme", b"http");
+    if let Some(host) = request.headers().get(http::header::HOST) {
+        encode_field(&mut block, b":authority", host.as_bytes());
+    }
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    encode_field(&mut block, b":path", path.as_bytes());
+    let mut headers = request.headers().clone();
+    hop_by_hop::remove_headers(&mut headers);
+    headers.remove(http::header::HOST);
+    headers.remove(http::header::TRANSFER_ENCODING);
+    for (name, value) in &headers {
+        encode_field(&mut block, name.as_str().as_bytes(), value.as_bytes());
+    }
+    if block.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + block.len());
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    frame.push(FRAME_HEADERS);
+    frame.push(FLAG_END_STREAM | FLAG_END_HEADERS);
+    frame.extend_from_slice(&1_u32.to_be_bytes());
+    frame.extend_from_slice(&block);
+    Some(frame)
+}
+
+/// Appends a header field to an HPACK header block as a literal that isn't added to the dynamic
+/// table (RFC 7541 section 6.2.2), so that the table stays as the client left it
+fn encode_field(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    for string in [name, value] {
+        encode_integer(block, string.len(), 7);
+        block.extend_from_slice(string);
+    }
+}
+
+/// Appends an HPACK integer with an n-bit prefix (RFC 7541 section 5.1)
+fn encode_integer(block: &mut Vec<u8>, mut value: usize, prefix_bits: u32) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max_prefix as u8);
+    value -= max_prefix;
+    while value >= 128 {
+        block.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    block.push(value as u8);
+}
+
+/// Slips the HEADERS frame of the request that upgraded a connection in behind the client's
+/// preface and first SETTINGS frame, so that the HTTP/2 server sees it as stream 1, which is where
+/// the client expects its response
+async fn splice_upgraded_request<S>(conn: &mut HttpStream<S>, frame: &[u8]) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let settings_end = loop {
+        let buffered = conn.buffered();
+        if buffered.len() >= PREFACE.len() + FRAME_HEADER_SIZE {
+            let settings = &buffered[PREFACE.len()..];
+            if !buffered.starts_with(PREFACE) || settings[3] != FRAME_SETTINGS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "client did not start with the HTTP/2 preface",
+                ));
+            }
+            let length = u32::from_be_bytes([0, settings[0], settings[1], settings[2]]) as usize;
+            let end = PREFACE.len() + FRAME_HEADER_SIZE + length;
+            if buffered.len() >= end {
+                break end;
+            }
+        }
+        if conn.fill().await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    };
+    let start = conn.buffered()[..settings_end].to_vec();
+    conn.consume(settings_end);
+    conn.unread(&[&start[..], frame].concat());
+    Ok(())
+}
+
+/// Serves an HTTP/2 connection. Each stream is handed to proxy as an HTTP/1.1 connection of its
+/// own carrying just that request, so streams are proxied independently of one another (and to
+/// HTTP/1 upstreams), and the response read back from it is sent on the stream.
+///
+/// upgraded is the HEADERS frame from h2c_upgrade, if the connection was upgraded from HTTP/1.1
+/// (and the 101 Switching Protocols response has been sent). The connection is closed once it has
+/// had no streams open for idle_timeout (if given). Once shutdown completes, the client is sent a
+/// GOAWAY frame, and the connection is closed when the streams already open have finished.
+pub async fn serve<S, F, Fut>(
+    mut conn: HttpStream<S>,
+    upgraded: Option<Vec<u8>>,
+    idle_timeout: Option<Duration>,
+    shutdown: impl Future<Output = ()>,
+    proxy: F,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(DuplexStream) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    // Idle connections are closed below, rather than by timing out reads
+    conn.set_read_timeout(None);
+    if let Some(frame) = upgraded {
+        timeouts::run(idle_timeout, splice_upgraded_request(&mut conn, &frame))
+            .await
+            .ok_or(io::ErrorKind::TimedOut)??;
+    }
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake::<_, Bytes>(conn);
+    let mut connection = timeouts::run(idle_timeout, handshake)
+        .await
+        .ok_or(io::ErrorKind::TimedOut)?
+        .map_err(io::Error::other)?;
+
+    let mut streams = JoinSet::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+    let mut shutting_down = false;
+    loop {
+        let idle_timeout = if streams.is_empty() {
+            idle_timeout
+        } else {
+            None
+        };
+        tokio::select! {
+            accepted = timeouts::run(idle_timeout, connection.accept()) => match accepted {
+                None => {
+                    log::debug!("HTTP/2 connection has been idle for too long; closing it");
+                    return Ok(());
+                }
+                Some(None) => return Ok(()),
+                Some(Some(Err(error))) => return Err(io::Error::other(error)),
+                Some(Some(Ok((request, respond)))) => {
+                    let (client_side, proxy_side) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+                    let proxying = proxy(proxy_side);
+                    streams.spawn(async move {
+                        tokio::join!(proxying, proxy_stream(request, respond, client_side));
+                    });
+                }
+            },
+            Some(_) = streams.join_next() => {}
+            _ = &mut shutdown, if !shutting_down => {
+                log::debug!("Shutting down; sending GOAWAY");
+                connection.graceful_shutdown();
+                shutting_down = true;
+            }
+        }
+    }
+}
+
+/// Sends the request from a stream over conn as HTTP/1.1, and the response that comes back on the
+/// stream. The stream is reset if no response comes back.
+async fn proxy_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    conn: DuplexStream,
+) {
+    if request.method() == http::Method::CONNECT {
+        log::debug!("Refusing CONNECT over HTTP/2");
+        let response = http::Response::builder()
+            .status(http::StatusCode::NOT_IMPLEMENTED)
+            .body(())
+            .unwrap();
+        let _ = respond.send_response(response, true);
+        return;
+    }
+    let method = request.method().clone();
+    let (head, body) = request.into_parts();
+    let (request, chunked) = to_http1(head, !body.is_end_stream());
+    let (reader, mut writer) = tokio::io::split(conn);
+    let mut reader = HttpStream::new(reader);
+
+    let result = {
+        let sending = async {
+            let result = send_request(&request, body, chunked, &mut writer).await;
+            // The proxy reads up to the end of the body, so it is told there will be no more
+            // requests
+            let _ = writer.shutdown().await;
+            result
+        };
+        let receiving = receive_response(&mut reader, &method, &mut respond);
+        tokio::pin!(sending);
+        tokio::pin!(receiving);
+        let mut sent = false;
+        loop {
+            tokio::select! {
+                result = &mut sending, if !sent => {
+                    if let Err(error) = result {
+                        // The proxy may have answered without reading the whole body
+                        log::debug!("Stopped sending HTTP/2 request body: {}", error);
+                    }
+                    sent = true;
+                }
+                result = &mut receiving => break result,
+            }
+        }
+    };
+    if let Err(error) = result {
+        log::debug!("Error forwarding HTTP/2 response: {}", error);
+        respond.send_reset(h2::Reason::INTERNAL_ERROR);
+    }
+}
+
+/// Turns the head of a request from an HTTP/2 stream into an HTTP/1.1 request. Returns whether its
+/// body has to be sent chunked, which is when there is a body but no Content-Length.
+fn to_http1(head: http::request::Parts, has_body: bool) -> (http::Request<Vec<u8>>, bool) {
+    let mut request = http::Request::from_parts(head, Vec::new());
+    *request.version_mut() = http::Version::HTTP_11;
+    let authority = request.uri().authority().cloned();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .to_string();
+    *request.uri_mut() = path.parse().unwrap_or_else(|_| http::Uri::from_static("/"));
+    let headers = request.headers_mut();
+    hop_by_hop::remove_headers(headers);
+    headers.remove(http::header::TRANSFER_ENCODING);
+    if let Some(authority) = authority {
+        if !headers.contains_key(http::header::HOST) {
+            if let Ok(host) = http::HeaderValue::from_str(authority.as_str()) {
+                headers.insert(http::header::HOST, host);
+            }
+        }
+    }
+    // HTTP/2 clients may split cookies into several fields, which HTTP/1.1 doesn't allow (RFC 7540
+    // section 8.1.2.5)
+    let cookies: Vec<&[u8]> = headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|value| value.as_bytes())
+        .collect();
+    if cookies.len() > 1 {
+        if let Ok(cookie) = http::HeaderValue::from_bytes(&cookies.join(&b"; "[..])) {
+            headers.insert(http::header::COOKIE, cookie);
+        }
+    }
+    let chunked = has_body && !headers.contains_key(http::header::CONTENT_LENGTH);
+    if chunked {
+        headers.insert(
+            http::header::TRANSFER_ENCODING,
+            http::HeaderValue::from_static("chunked"),
+        );
+    }
+    (request, chunked)
+}
+
+/// Writes an HTTP/1.1 request, with the body read from an HTTP/2 stream
+async fn send_request<W>(
+    request: &http::Request<Vec<u8>>,
+    mut body: RecvStream,
+    chunked: bool,
+    to: &mut W,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    request::write_headers(request, to).await?;
+    while let Some(data) = body.data().await {
+        let data = data.map_err(io::Error::other)?;
+        if data.is_empty() {
+            continue;
+        }
+        if chunked {
+            to.write_all(format!("{:x}\r\n", data.len()).as_bytes())
+                .await?;
+        }
+        to.write_all(&data).await?;
+        if chunked {
+            to.write_all(b"\r\n").await?;
+        }
+        // The client may send more once this has been passed on
+        let _ = body.flow_control().release_capacity(data.len());
+    }
+    if chunked {
+        to.write_all(b"0\r\n\r\n").await?;
+    }
+    to.flush().await
+}
+
+/// Reads an HTTP/1.1 response and sends it on an HTTP/2 stream, streaming the body. Interim
+/// responses are dropped, and the hop-by-hop headers removed, since HTTP/2 doesn't have them.
+async fn receive_response<R>(
+    from: &mut HttpStream<R>,
+    method: &http::Method,
+    respond: &mut SendResponse<Bytes>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let response = loop {
+        let response = response::read_headers(from)
+            .await
+            .map_err(|error| io::Error::other(format!("{:?}", error)))?;
+        if !response.status().is_informational() {
+            break response;
+        }
+    };
+    let length = response::body_length(&response, method)
+        .map_err(|error| io::Error::other(format!("{:?}", error)))?;
+    let (head, _) = response.into_parts();
+    let mut response = http::Response::from_parts(head, ());
+    *response.version_mut() = http::Version::HTTP_2;
+    hop_by_hop::remove_headers(response.headers_mut());
+    response
+        .headers_mut()
+        .remove(http::header::TRANSFER_ENCODING);
+    let mut send = respond
+        .send_response(response, length == BodyLength::Empty)
+        .map_err(io::Error::other)?;
+    if length == BodyLength::Empty {
+        return Ok(());
+    }
+    let mut to = DataWriter(&mut send);
+    let forwarded = match length {
+        BodyLength::Chunked => body::forward_decoded(from, &mut to, None).await,
+        length => body::forward(from, &mut to, length, None).await,
+    };
+    forwarded.map_err(|error| io::Error::other(error.to_string()))?;
+    send.send_data(Bytes::new(), true).map_err(io::Error::other)
+}
+
+/// Writes to an HTTP/2 stream as DATA frames, waiting for the client to let more through when
+/// flow control says it has had enough
+struct DataWriter<'a>(&'a mut SendStream<Bytes>);
+
+impl AsyncWrite for DataWriter<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.0.reserve_capacity(buf.len());
+        loop {
+            let capacity = match ready!(self.0.poll_capacity(cx)) {
+                Some(capacity) => capacity.map_err(io::Error::other)?,
+                None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            };
+            if capacity == 0 {
+                continue;
+            }
+            let n = capacity.min(buf.len());
+            self.0
+                .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                .map_err(io::Error::other)?;
+            return Poll::Ready(Ok(n));
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
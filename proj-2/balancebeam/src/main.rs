@@ -1,16 +1,72 @@
+mod admin;
+mod affinity;
+mod balancer;
+mod body;
+mod circuit_breaker;
+mod config;
+mod headers;
+mod health;
+mod hop_by_hop;
+mod http2;
+mod metrics;
+mod pool;
+mod rate_limit;
+mod rate_limit_store;
 mod request;
 mod response;
+mod retry;
+mod router;
+mod shutdown;
+mod stream;
+mod timeouts;
+mod tls;
+mod tunnel;
+mod upstream;
 
 use clap::Parser;
 
-use rand::{Rng, SeedableRng};
+use admin::UpstreamStatus;
+use affinity::SessionAffinity;
+use balancer::{Balancer, ConnectionCounter, Strategy};
+use body::BodyLength;
+use circuit_breaker::CircuitBreaker;
+use config::{
+    CertificateConfig, Config, ConnectionPoolConfig, HealthCheckConfig, Mode, RateLimitConfig,
+    TlsConfig, UpstreamConfig, UpstreamTlsConfig,
+};
+use headers::{HeaderRewriter, Variables};
+use health::{HealthCheck, HealthStates};
+use hop_by_hop::ClientConnection;
+use metrics::Metrics;
+use pool::ConnectionPool;
+use rate_limit::RateLimits;
+use retry::RetryPolicy;
+use router::Router;
+use shutdown::Shutdown;
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
+use std::future::Future;
+use std::io::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use stream::HttpStream;
+use timeouts::Timeouts;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{watch, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::time::sleep;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use upstream::UpstreamStream;
+
+/// How often to check whether the config file has been modified (in seconds)
+const CONFIG_POLL_INTERVAL: u64 = 1;
+/// How often to close idle upstream connections that have timed out (in seconds)
+const IDLE_CONNECTION_EVICTION_INTERVAL: u64 = 1;
+/// How often to forget about clients that are back to their full rate limit allowance (in seconds)
+const RATE_LIMITER_EVICTION_INTERVAL: u64 = 60;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
@@ -20,9 +76,15 @@ struct CmdOptions {
     /// "IP/port to bind to"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
-    /// "Upstream host to forward requests to"
+    /// "Upstream host to forward requests to (prefix with https:// to connect over TLS)"
     #[arg(short, long)]
     upstream: Vec<String>,
+    /// "PEM file of CA certificates to verify https:// upstreams with (default: Mozilla's roots)"
+    #[arg(long)]
+    upstream_ca_bundle: Option<PathBuf>,
+    /// "Don't verify the certificates of https:// upstreams (for testing only!)"
+    #[arg(long)]
+    upstream_insecure_skip_verify: bool,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
@@ -32,26 +94,381 @@ struct CmdOptions {
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Maximum number of requests to accept per IP per second (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_requests_per_second: usize,
+    /// "Algorithm used to enforce the rate limit"
+    #[arg(long, value_enum, default_value = "sliding-window")]
+    rate_limit_algorithm: rate_limit::Algorithm,
+    /// "How many requests a client can send at once with the token bucket (default: the limit)"
+    #[arg(long)]
+    rate_limit_burst: Option<usize>,
+    /// "Load-balancing strategy used to pick an upstream for each request"
+    #[arg(long, value_enum, default_value = "random")]
+    strategy: Strategy,
+    /// "Weight of each upstream for weighted-round-robin, in the same order as --upstream (default 1)"
+    #[arg(long)]
+    weight: Vec<usize>,
+    /// "TOML or YAML config file to load instead of the flags above (reloaded on change or SIGHUP)"
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// "IP/port to serve the admin API on (disabled if not given)"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "Reject requests with bodies bigger than this many bytes (unlimited if not given)"
+    #[arg(long)]
+    max_request_body_size: Option<usize>,
+    /// "Maximum number of idle keep-alive connections to keep open to each upstream (0 = no pooling)"
+    #[arg(long, default_value = "16")]
+    max_idle_connections: usize,
+    /// "Close idle upstream connections after this many seconds"
+    #[arg(long, default_value = "60")]
+    idle_connection_timeout: u64,
+    /// "PEM certificate chain to serve TLS with (repeat with --tls-key for SNI; the first is the default)"
+    #[arg(long)]
+    tls_cert: Vec<PathBuf>,
+    /// "PEM private key for each --tls-cert, in the same order"
+    #[arg(long)]
+    tls_key: Vec<PathBuf>,
+    /// "Proxy HTTP requests, or copy raw TCP connections to upstreams without parsing them"
+    #[arg(long, value_enum, default_value = "http")]
+    mode: Mode,
+    /// "Maximum number of connections to accept from each IP at once (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connections_per_ip: usize,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
 ///
 /// You should add fields to this struct in later milestones.
-#[derive(Clone)]
 struct ProxyState {
     /// How frequently we check whether upstream servers are alive
     active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute
-    max_requests_per_minute: usize,
+    /// The active health check for each upstream
+    health_checks: HashMap<String, HealthCheck>,
+    /// Which upstreams the active health checks consider healthy
+    health: Arc<HealthStates>,
+    /// Ejects upstreams whose requests keep failing, independently of the active health checks
+    circuit_breaker: Arc<CircuitBreaker>,
+    /// Which requests are retried on another upstream when the first one fails them
+    retry_policy: RetryPolicy,
+    /// How long to wait on clients and upstreams
+    timeouts: Timeouts,
+    /// Changes made to the headers of requests and responses
+    header_rewriter: HeaderRewriter,
+    /// Maximum size of a request body, if any
+    max_request_body_size: Option<usize>,
     /// Addresses of servers that we are proxying to
     upstream_addresses: Vec<String>,
     /// Addresses of servers that are alive
     live_upstream_addresses: Arc<RwLock<Vec<String>>>,
-    /// Rate limiting counter
-    rate_limiting_counter: Arc<Mutex<HashMap<String, usize>>>,
+    /// Keeps track of how many requests each client has made
+    rate_limits: Arc<RateLimits>,
+    /// Decides which pool of upstreams each request goes to
+    router: Router,
+    /// Pool each upstream belongs to
+    upstream_pools: HashMap<String, String>,
+    /// Strategy (and its state) used to pick an upstream from each pool for each request
+    balancers: HashMap<String, Arc<dyn Balancer>>,
+    /// Keeps clients' requests on the same upstream
+    session_affinity: SessionAffinity,
+    /// Number of requests in flight to each upstream
+    active_connections: Arc<ConnectionCounter>,
+    /// Number of connections open from each client IP
+    client_connections: Arc<ConnectionCounter>,
+    /// Idle keep-alive connections to upstreams, ready to be reused
+    connection_pool: Arc<ConnectionPool>,
+    /// Performs TLS handshakes with https:// upstreams
+    upstream_tls: TlsConnector,
+    /// Upstreams that have been drained or disabled through the admin API. Upstreams that are not
+    /// in this map are enabled. Requests and tunnels to an upstream watch it to find out when they
+    /// should be cut off.
+    upstream_status: Arc<watch::Sender<HashMap<String, UpstreamStatus>>>,
+    /// Counters exported by the admin API's /metrics endpoint
+    metrics: Arc<Metrics>,
+    /// Tells connections when balancebeam is shutting down, and counts the ones still open
+    shutdown: Arc<Shutdown>,
+    /// The config this state was built from
+    config: Config,
+}
+
+/// The current ProxyState. A new ProxyState is swapped in whenever the config file is reloaded;
+/// connections that are already being handled keep using the state they started with.
+type SharedState = watch::Receiver<Arc<ProxyState>>;
+
+/// Held while a config change (a reload, or a change made through the admin API) is applied, so
+/// that changes are made one at a time, each starting from the state the one before left behind
+type ConfigLock = Arc<tokio::sync::Mutex<()>>;
+
+impl ProxyState {
+    /// Builds the state for a config. When reloading, previous is the state being replaced:
+    /// counters carry over, and upstreams that were already known keep their health status. Fails
+    /// if the CA bundle for upstream TLS can't be loaded, or the rate limit store can't be set up.
+    async fn from_config(
+        config: &Config,
+        previous: Option<&ProxyState>,
+    ) -> Result<ProxyState, Error> {
+        let upstream_tls = tls::connector(&config.upstream_tls)
+            .map_err(|err| Error::other(format!("Could not set up TLS for upstreams: {}", err)))?;
+        let upstream_addresses = config.upstream_addresses();
+        let upstream_status = match previous {
+            Some(previous) => {
+                // Forget about upstreams that are no longer configured
+                previous
+                    .upstream_status
+                    .send_if_modified(|upstream_status| {
+                        let len = upstream_status.len();
+                        upstream_status.retain(|addr, _| upstream_addresses.contains(addr));
+                        upstream_status.len() != len
+                    });
+                previous.upstream_status.clone()
+            }
+            None => Default::default(),
+        };
+        let live_upstream_addresses = match previous {
+            Some(previous) => {
+                let previously_live = previous.live_upstream_addresses.read().await;
+                let upstream_status = upstream_status.borrow();
+                upstream_addresses
+                    .iter()
+                    .filter(|addr| {
+                        (previously_live.contains(addr)
+                            || !previous.upstream_addresses.contains(addr))
+                            && !upstream_status.contains_key(*addr)
+                    })
+                    .cloned()
+                    .collect()
+            }
+            None => upstream_addresses.clone(),
+        };
+        // Keep idle connections around across reloads, unless the pool settings have changed
+        let connection_pool = match previous {
+            Some(previous) if previous.config.connection_pool == config.connection_pool => {
+                previous
+                    .connection_pool
+                    .retain_upstreams(&upstream_addresses);
+                previous.connection_pool.clone()
+            }
+            _ => Arc::new(ConnectionPool::new(
+                config.connection_pool.max_idle_per_upstream,
+                Duration::from_secs(config.connection_pool.idle_timeout),
+            )),
+        };
+        // Clients' request counts carry over for rules that haven't changed
+        let rate_limits = Arc::new(RateLimits::new(
+            &config.rate_limit,
+            previous.map(|previous| &*previous.rate_limits),
+        )?);
+        let health_checks = config
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let check = config.health_check.with_overrides(&upstream.health_check);
+                (
+                    upstream.address.clone(),
+                    HealthCheck::new(&check, config.mode),
+                )
+            })
+            .collect();
+        let health = match previous {
+            Some(previous) => {
+                previous.health.retain_upstreams(&upstream_addresses);
+                previous.health.clone()
+            }
+            None => Default::default(),
+        };
+        // Ejected upstreams stay ejected across reloads
+        let circuit_breaker = match previous {
+            Some(previous) => {
+                previous
+                    .circuit_breaker
+                    .retain_upstreams(&upstream_addresses);
+                previous
+                    .circuit_breaker
+                    .set_config(config.circuit_breaker.clone());
+                previous.circuit_breaker.clone()
+            }
+            None => Arc::new(CircuitBreaker::new(config.circuit_breaker.clone())),
+        };
+        let weights: HashMap<String, usize> = config
+            .upstreams
+            .iter()
+            .map(|upstream| (upstream.address.clone(), upstream.weight))
+            .collect();
+        let upstream_pools: HashMap<String, String> = config
+            .upstreams
+            .iter()
+            .map(|upstream| (upstream.address.clone(), upstream.pool.clone()))
+            .collect();
+        let balancers = upstream_pools
+            .values()
+            .map(|pool| {
+                let balancer = balancer::new_balancer(config.strategy, weights.clone());
+                (pool.clone(), balancer)
+            })
+            .collect();
+        Ok(ProxyState {
+            active_health_check_interval: config.health_check.interval,
+            health_checks,
+            health,
+            circuit_breaker,
+            retry_policy: RetryPolicy::new(&config.retry),
+            timeouts: Timeouts::new(&config.timeouts),
+            header_rewriter: HeaderRewriter::new(&config.headers),
+            max_request_body_size: config.max_request_body_size,
+            live_upstream_addresses: Arc::new(RwLock::new(live_upstream_addresses)),
+            upstream_addresses,
+            rate_limits,
+            router: Router::new(&config.routes, &config.upstreams),
+            upstream_pools,
+            balancers,
+            session_affinity: SessionAffinity::new(&config.session_affinity),
+            active_connections: previous.map_or_else(Default::default, |previous| {
+                previous.active_connections.clone()
+            }),
+            client_connections: previous.map_or_else(Default::default, |previous| {
+                previous.client_connections.clone()
+            }),
+            connection_pool,
+            upstream_tls,
+            upstream_status,
+            metrics: previous.map_or_else(Default::default, |previous| previous.metrics.clone()),
+            shutdown: previous.map_or_else(Default::default, |previous| previous.shutdown.clone()),
+            config: config.clone(),
+        })
+    }
+
+    /// Returns how the admin API has asked us to treat the given upstream
+    fn upstream_status(&self, upstream: &str) -> UpstreamStatus {
+        self.upstream_status
+            .borrow()
+            .get(upstream)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Runs the given future, unless the admin API disables the upstream first, in which case
+    /// returns None
+    async fn unless_disabled<F: Future>(&self, upstream: &str, future: F) -> Option<F::Output> {
+        let mut upstream_status = self.upstream_status.subscribe();
+        let disabled = upstream_status.wait_for(|upstream_status| {
+            upstream_status.get(upstream) == Some(&UpstreamStatus::Disabled)
+        });
+        tokio::select! {
+            output = future => Some(output),
+            // The sender lives as long as self, so this only finishes once the upstream is disabled
+            Ok(_) = disabled => None,
+        }
+    }
+
+    /// Returns the live upstreams in a pool, other than the given ones
+    async fn live_upstreams_in_pool(&self, pool: &str, exclude: &[String]) -> Vec<String> {
+        self.live_upstream_addresses
+            .read()
+            .await
+            .iter()
+            .filter(|addr| {
+                !exclude.contains(addr)
+                    && self
+                        .upstream_pools
+                        .get(*addr)
+                        .is_some_and(|upstream_pool| upstream_pool == pool)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Counts a failed request against an upstream, ejecting it if it has failed too often
+    fn record_upstream_failure(&self, upstream: &str) {
+        if self.circuit_breaker.record_failure(upstream) {
+            self.metrics.record_ejection(upstream);
+        }
+    }
+
+    /// Changes how the given upstream is treated. Drained and disabled upstreams are taken out of
+    /// the rotation right away (and their idle connections closed), and requests and tunnels that
+    /// are still open to disabled upstreams are cut off; re-enabled upstreams are put back right
+    /// away and left to the health checks to remove again if they are down.
+    async fn set_upstream_status(&self, upstream: &str, status: UpstreamStatus) {
+        let mut live_upstream_addresses = self.live_upstream_addresses.write().await;
+        live_upstream_addresses.retain(|addr| addr != upstream);
+        if status == UpstreamStatus::Enabled {
+            self.upstream_status
+                .send_if_modified(|upstream_status| upstream_status.remove(upstream).is_some());
+            live_upstream_addresses.push(upstream.to_string());
+        } else {
+            self.upstream_status.send_modify(|upstream_status| {
+                upstream_status.insert(upstream.to_string(), status);
+            });
+            self.connection_pool.remove(upstream);
+        }
+    }
+}
+
+/// Builds a Config from the command-line flags, for when no --config file is given
+fn config_from_options(options: &CmdOptions) -> Config {
+    Config {
+        listeners: vec![options.bind.clone()],
+        tls: if options.tls_cert.is_empty() {
+            None
+        } else {
+            Some(TlsConfig {
+                certificates: options
+                    .tls_cert
+                    .iter()
+                    .zip(&options.tls_key)
+                    .map(|(cert, key)| CertificateConfig {
+                        cert: cert.clone(),
+                        key: key.clone(),
+                    })
+                    .collect(),
+            })
+        },
+        admin_listener: options.admin_bind.clone(),
+        mode: options.mode,
+        upstreams: options
+            .upstream
+            .iter()
+            .enumerate()
+            .map(|(idx, address)| UpstreamConfig {
+                address: address.clone(),
+                weight: options.weight.get(idx).copied().unwrap_or(1),
+                health_check: Default::default(),
+                pool: config::DEFAULT_POOL.to_string(),
+            })
+            .collect(),
+        upstream_tls: UpstreamTlsConfig {
+            ca_bundle: options.upstream_ca_bundle.clone(),
+            insecure_skip_verify: options.upstream_insecure_skip_verify,
+        },
+        strategy: options.strategy,
+        session_affinity: Default::default(),
+        health_check: HealthCheckConfig {
+            interval: options.active_health_check_interval,
+            path: options.active_health_check_path.clone(),
+            ..Default::default()
+        },
+        circuit_breaker: Default::default(),
+        routes: Vec::new(),
+        retry: Default::default(),
+        timeouts: Default::default(),
+        headers: Default::default(),
+        rate_limit: RateLimitConfig {
+            max_requests_per_minute: options.max_requests_per_minute,
+            max_requests_per_second: options.max_requests_per_second,
+            algorithm: options.rate_limit_algorithm,
+            burst: options.rate_limit_burst,
+            rules: Vec::new(),
+            store: Default::default(),
+            max_connections_per_ip: options.max_connections_per_ip,
+        },
+        max_request_body_size: options.max_request_body_size,
+        connection_pool: ConnectionPoolConfig {
+            max_idle_per_upstream: options.max_idle_connections,
+            idle_timeout: options.idle_connection_timeout,
+        },
+    }
 }
 
 #[tokio::main]
@@ -59,280 +476,1489 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
+    let mut config = match &options.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Could not load {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            if options.upstream.is_empty() {
+                log::error!(
+                    "At least one upstream server must be specified using the --upstream option."
+                );
+                std::process::exit(1);
+            }
+            if options.weight.len() > options.upstream.len() {
+                log::error!("More --weight values were given than there are upstream servers.");
+                std::process::exit(1);
+            }
+            if options.tls_cert.len() != options.tls_key.len() {
+                log::error!("Each --tls-cert must have a matching --tls-key.");
+                std::process::exit(1);
+            }
+            let config = config_from_options(&options);
+            if let Err(err) = config.validate() {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+            config
+        }
+    };
+    if config.listeners.is_empty() {
+        config.listeners.push(options.bind.clone());
     }
 
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
+    let initial_state = match ProxyState::from_config(&config, None).await {
+        Ok(state) => state,
         Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+            log::error!("{}", err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
-
-    let state = ProxyState {
-        live_upstream_addresses: Arc::new(RwLock::new(options.upstream.clone())),
-        upstream_addresses: options.upstream,
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        rate_limiting_counter: Arc::new(Mutex::new(HashMap::new())),
+    let (state_sender, state) = watch::channel(Arc::new(initial_state));
+    let config_lock = ConfigLock::default();
+    let (mut sigterm, mut sigint) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!("Could not install shutdown signal handlers: {}", err);
+            std::process::exit(1);
+        }
     };
 
+    // Start listening for connections
+    let mut listeners = Listeners::default();
+    if let Err(err) = listeners.update(&config, &state).await {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+
+    // Start the admin API
+    if let Some(admin_listener) = &config.admin_listener {
+        match TcpListener::bind(admin_listener).await {
+            Ok(listener) => {
+                log::info!("Serving the admin API on {}", admin_listener);
+                tokio::spawn(admin::serve(
+                    listener,
+                    state_sender.clone(),
+                    config_lock.clone(),
+                ));
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", admin_listener, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
     // Start active health check
     let state_temp = state.clone();
     tokio::spawn(async move {
-        active_health_check(&state_temp).await;
+        active_health_check(state_temp).await;
     });
 
-    // Start cleaning up rate limiting counter every minute
+    // Start forgetting about clients that haven't been rate limited in a while
     let state_temp = state.clone();
     tokio::spawn(async move {
-        rate_limiting_counter_clearer(&state_temp, 60).await;
+        rate_limiter_evicter(state_temp, RATE_LIMITER_EVICTION_INTERVAL).await;
     });
 
-    // Handle incoming connections
-    loop {
-        if let Ok((stream, _)) = listener.accept().await {
+    // Start closing idle upstream connections that have timed out
+    let state_temp = state.clone();
+    tokio::spawn(async move {
+        idle_connection_evicter(state_temp, IDLE_CONNECTION_EVICTION_INTERVAL).await;
+    });
+
+    // Reload the config file whenever it changes or we get SIGHUP
+    if let Some(path) = options.config {
+        tokio::spawn(watch_config(
+            path,
+            config,
+            state_sender,
+            config_lock,
+            listeners,
+        ));
+    }
+
+    // Run until we are asked to shut down, then give the connections that are still open some
+    // time to finish. The exit status says whether they all did.
+    let received = shutdown_signal(&mut sigterm, &mut sigint).await;
+    let state = state.borrow().clone();
+    log::info!(
+        "Received {}; shutting down once {} open connection(s) have finished",
+        received,
+        state.shutdown.connections()
+    );
+    state.shutdown.start();
+    let drained = tokio::select! {
+        drained = state.shutdown.drained(state.timeouts.shutdown) => drained,
+        received = shutdown_signal(&mut sigterm, &mut sigint) => {
+            log::warn!("Received {} again; not waiting for connections to finish", received);
+            false
+        }
+    };
+    if drained {
+        log::info!("All connections have finished; exiting");
+        std::process::exit(0);
+    }
+    log::warn!(
+        "Exiting with {} connection(s) still open",
+        state.shutdown.connections()
+    );
+    std::process::exit(1);
+}
+
+/// Waits for SIGTERM or SIGINT, returning the name of the signal received
+async fn shutdown_signal(sigterm: &mut Signal, sigint: &mut Signal) -> &'static str {
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
+
+/// Accept loops for each address we are listening on, keyed by address
+#[derive(Default)]
+struct Listeners {
+    accept_tasks: HashMap<String, JoinHandle<()>>,
+    /// Performs TLS handshakes with new clients when TLS is enabled. This is shared with the accept
+    /// loops so that reloaded certificates are used for new connections right away.
+    tls_acceptor: Arc<parking_lot::RwLock<Option<TlsAcceptor>>>,
+}
+
+impl Listeners {
+    /// Starts listening on any addresses that we aren't listening on yet, and stops listening on
+    /// ones that are no longer wanted, and (re)loads the TLS certificates. Connections that were
+    /// already accepted are unaffected. If the certificates can't be loaded, nothing is changed.
+    async fn update(&mut self, config: &Config, state: &SharedState) -> Result<(), Error> {
+        let tls_acceptor = match &config.tls {
+            Some(tls) => {
+                let acceptor = tls::acceptor(tls, config.mode).map_err(|err| {
+                    Error::other(format!("Could not load TLS certificates: {}", err))
+                })?;
+                log::info!("Loaded {} TLS certificate(s)", tls.certificates.len());
+                Some(acceptor)
+            }
+            None => None,
+        };
+        *self.tls_acceptor.write() = tls_acceptor;
+
+        let addresses = &config.listeners;
+        self.accept_tasks.retain(|address, task| {
+            let keep = addresses.contains(address);
+            if !keep {
+                log::info!("No longer listening for requests on {}", address);
+                task.abort();
+            }
+            keep
+        });
+        for address in addresses {
+            if self.accept_tasks.contains_key(address) {
+                continue;
+            }
+            let listener = TcpListener::bind(address).await.map_err(|err| {
+                Error::new(
+                    err.kind(),
+                    format!("Could not bind to {}: {}", address, err),
+                )
+            })?;
+            log::info!("Listening for requests on {}", address);
             let state = state.clone();
+            self.accept_tasks.insert(
+                address.clone(),
+                tokio::spawn(accept_connections(
+                    listener,
+                    self.tls_acceptor.clone(),
+                    state,
+                )),
+            );
+        }
+        Ok(())
+    }
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    tls_acceptor: Arc<parking_lot::RwLock<Option<TlsAcceptor>>>,
+    state: SharedState,
+) {
+    let shutdown = state.borrow().shutdown.clone();
+    // Handle incoming connections, until we start shutting down
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.started() => {
+                if let Ok(address) = listener.local_addr() {
+                    log::info!("No longer listening for requests on {}", address);
+                }
+                return;
+            }
+        };
+        if let Ok((stream, client_addr)) = accepted {
+            // Each connection sticks with the state that was current when it was accepted
+            let state = state.borrow().clone();
+            let client_ip = client_addr.ip().to_string();
+            let max_connections = state.config.rate_limit.max_connections_per_ip;
+            let client_connection_guard = if max_connections > 0 {
+                match state
+                    .client_connections
+                    .try_track(&client_ip, max_connections)
+                {
+                    Some(guard) => Some(guard),
+                    None => {
+                        log::info!(
+                            "{} already has {} connections open; refusing another",
+                            client_ip,
+                            max_connections
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            let shutdown_guard = shutdown.track();
+            let tls_acceptor = tls_acceptor.read().clone();
             // new tokio task
             tokio::spawn(async move {
-                handle_connection(stream, &state).await;
+                let _client_connection_guard = client_connection_guard;
+                let _shutdown_guard = shutdown_guard;
+                match tls_acceptor {
+                    Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                            handle_connection(stream, client_addr.ip(), "https", http2, &state)
+                                .await
+                        }
+                        Err(err) => {
+                            log::info!("TLS handshake with {} failed: {}", client_addr.ip(), err)
+                        }
+                    },
+                    None => {
+                        handle_connection(stream, client_addr.ip(), "http", false, &state).await
+                    }
+                }
             });
         }
     }
 }
 
-async fn rate_limiting_counter_clearer(state: &ProxyState, clear_interval: u64) {
+/// Reloads the config file whenever its modification time changes or the process receives SIGHUP,
+/// and swaps in a new ProxyState built from it. If the new config is invalid, the old one stays in
+/// effect. Upstreams added or removed through the admin API are overwritten by a reload, since the
+/// file is the source of truth.
+async fn watch_config(
+    path: PathBuf,
+    mut config: Config,
+    state_sender: watch::Sender<Arc<ProxyState>>,
+    config_lock: ConfigLock,
+    mut listeners: Listeners,
+) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(err) => {
+            log::error!("Could not install SIGHUP handler: {}", err);
+            return;
+        }
+    };
+    let modified_time = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified_time(&path);
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => log::info!("Received SIGHUP, reloading {}", path.display()),
+            _ = sleep(Duration::from_secs(CONFIG_POLL_INTERVAL)) => {
+                let modified = modified_time(&path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                log::info!("{} changed, reloading", path.display());
+            }
+        }
+
+        let mut new_config = match Config::load(&path) {
+            Ok(new_config) => new_config,
+            Err(err) => {
+                log::error!("Not reloading {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        if new_config.listeners.is_empty() {
+            new_config.listeners = config.listeners.clone();
+        }
+        let _config_lock = config_lock.lock().await;
+        let previous = state_sender.borrow().clone();
+        let new_state = match ProxyState::from_config(&new_config, Some(&previous)).await {
+            Ok(new_state) => new_state,
+            Err(err) => {
+                log::error!("Not reloading {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        if let Err(err) = listeners
+            .update(&new_config, &state_sender.subscribe())
+            .await
+        {
+            log::error!("{}", err);
+        }
+        state_sender.send_replace(Arc::new(new_state));
+        config = new_config;
+        log::info!("Reloaded {}", path.display());
+    }
+}
+
+async fn rate_limiter_evicter(state: SharedState, evict_interval: u64) {
+    loop {
+        sleep(Duration::from_secs(evict_interval)).await;
+        let state = state.borrow().clone();
+        state.rate_limits.evict_idle();
+    }
+}
+
+async fn idle_connection_evicter(state: SharedState, evict_interval: u64) {
     loop {
-        sleep(Duration::from_secs(clear_interval)).await;
-        // Clean up counter every minute
-        let mut rate_limiting_counter = state.rate_limiting_counter.clone().lock_owned().await;
-        rate_limiting_counter.clear();
+        sleep(Duration::from_secs(evict_interval)).await;
+        let state = state.borrow().clone();
+        state.connection_pool.evict_expired();
     }
 }
 
-async fn active_health_check(state: &ProxyState) {
+/// Checks the health of every upstream on an interval. Each round probes all of the upstreams at
+/// once (each bounded by its own timeout), without holding any locks while it waits, and then
+/// replaces the live upstreams in one go. A failing or misbehaving upstream only affects its own
+/// result, never the rounds that follow.
+async fn active_health_check(state: SharedState) {
     loop {
-        sleep(Duration::from_secs(
-            state.active_health_check_interval.try_into().unwrap(),
-        ))
-        .await;
-
-        let mut live_upstream_addresses = state.live_upstream_addresses.write().await;
-        live_upstream_addresses.clear();
-        // send a request to each upstream
-        // If a failed upstream returns HTTP 200, put it back in the rotation of upstream servers.
-        // If an online upstream returns a non-200 status code, mark that server as failed.
+        let interval = state.borrow().active_health_check_interval;
+        sleep(Duration::from_secs(interval.try_into().unwrap())).await;
+
+        // Always check the latest state, in case the config was reloaded while we were sleeping
+        let state = state.borrow().clone();
+        let mut probes = JoinSet::new();
         for upstream_ip in &state.upstream_addresses {
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state.active_health_check_path)
-                .header("Host", upstream_ip)
-                .body(Vec::new())
-                .unwrap();
-            // Open a connection to a destination server
-            match TcpStream::connect(upstream_ip).await {
-                Ok(mut conn) => {
-                    // Write to stream and read from stream
-                    if let Err(error) = request::write_to_stream(&request, &mut conn).await {
-                        log::error!(
-                            "Failed to send request to upstream {}: {}",
-                            upstream_ip,
-                            error
-                        );
-                        return;
-                    }
-                    let response =
-                        match response::read_from_stream(&mut conn, &request.method()).await {
-                            Ok(response) => response,
-                            Err(error) => {
-                                log::error!("Error reading response from server: {:?}", error);
-                                return;
-                            }
-                        };
-                    // Handle the statusCode of response
-                    match response.status().as_u16() {
-                        200 => {
-                            live_upstream_addresses.push(upstream_ip.clone());
-                        }
-                        status @ _ => {
-                            log::error!(
-                                "upstream server {} is not working: {}",
-                                upstream_ip,
-                                status
-                            );
-                            return;
-                        }
-                    }
+            let state = state.clone();
+            let upstream_ip = upstream_ip.clone();
+            probes.spawn(async move {
+                let check = &state.health_checks[&upstream_ip];
+                let result = check.probe(&upstream_ip, &state.upstream_tls).await;
+                (upstream_ip, result)
+            });
+        }
+        let mut passed = HashMap::new();
+        while let Some(result) = probes.join_next().await {
+            match result {
+                Ok((upstream_ip, Ok(()))) => {
+                    passed.insert(upstream_ip, true);
                 }
-                Err(err) => {
-                    log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                    return;
+                Ok((upstream_ip, Err(reason))) => {
+                    log::error!("upstream server {} is not working: {}", upstream_ip, reason);
+                    passed.insert(upstream_ip, false);
                 }
+                // Upstreams whose probe panicked are left out, and so count as failed below
+                Err(err) => log::error!("Health check failed to run: {}", err),
+            }
+        }
+
+        // Put the upstreams that are healthy (after enough checks in a row have passed or failed
+        // to change their mind) in the rotation, in the order they are configured in
+        let mut live_upstream_addresses = Vec::new();
+        for upstream_ip in &state.upstream_addresses {
+            let passed = passed.get(upstream_ip).copied().unwrap_or(false);
+            state.metrics.record_health_check(upstream_ip, passed);
+            let check = &state.health_checks[upstream_ip];
+            // Upstreams that were drained or disabled through the admin API stay out of the
+            // rotation even when they are healthy
+            if state.health.record(upstream_ip, passed, check)
+                && state.upstream_status(upstream_ip) == UpstreamStatus::Enabled
+            {
+                live_upstream_addresses.push(upstream_ip.clone());
             }
         }
+        log::debug!(
+            "{} of {} upstreams are live after health checks",
+            live_upstream_addresses.len(),
+            state.upstream_addresses.len()
+        );
+        *state.live_upstream_addresses.write().await = live_upstream_addresses;
     }
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
+/// Gets a connection to a live upstream in a pool, picked by the configured balancer, reusing an idle
+/// connection from the pool if there is one. Upstreams ejected by the circuit breaker are skipped,
+/// unless every live upstream has been ejected. If a new connection fails, that counts against the
+/// upstream in the circuit breaker and another upstream is tried. Upstreams in exclude (those that
+/// have already failed the request) are never picked. A request pinned to an upstream by session
+/// affinity goes to that upstream if it is one of the candidates. Returns the stream along with the address of
+/// the upstream it is connected to, or an error of kind TimedOut if the last upstream tried took
+/// too long to connect to.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    client_ip: IpAddr,
+    pool: &str,
+    exclude: &[String],
+    affinity: Option<&affinity::Key>,
+) -> Result<(HttpStream<UpstreamStream>, String), std::io::Error> {
+    // Upstreams that have already been tried for this request
+    let mut tried = exclude.to_vec();
+    let mut timed_out = false;
     loop {
-        let live_upstream_addresses = state.live_upstream_addresses.read().await;
-        let upstream_idx = rng.gen_range(0..live_upstream_addresses.len());
-        let upstream_ip = &live_upstream_addresses.get(upstream_idx).unwrap().clone();
-        drop(live_upstream_addresses); // release read lock
+        let live_upstream_addresses = state.live_upstreams_in_pool(pool, &tried).await;
+        if live_upstream_addresses.is_empty() {
+            log::error!("All upstreams in pool {} are dead", pool);
+            if timed_out {
+                return Err(Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Timed out connecting to upstreams",
+                ));
+            }
+            return Err(Error::other("All upstreams are dead"));
+        }
+        let available: Vec<String> = live_upstream_addresses
+            .iter()
+            .filter(|addr| state.circuit_breaker.is_available(addr))
+            .cloned()
+            .collect();
+        // If everything has been ejected, it is better to keep trying the upstreams than to fail
+        // every request
+        let all_ejected = available.is_empty();
+        let candidates = if all_ejected {
+            &live_upstream_addresses
+        } else {
+            &available
+        };
+        let pinned = affinity.and_then(|key| affinity::choose(key, candidates));
+        if pinned.is_none() && matches!(affinity, Some(affinity::Key::Cookie(Some(_)))) {
+            log::info!("Upstream pinned by the affinity cookie is unavailable; balancing as usual");
+        }
+        let upstream_idx = pinned.unwrap_or_else(|| {
+            state.balancers[pool].choose(candidates, client_ip, &state.active_connections)
+        });
+        let upstream_ip = candidates[upstream_idx].clone();
+        tried.push(upstream_ip.clone());
+        // Someone else may have claimed a half-open upstream's trial request in the meantime
+        if !all_ejected && !state.circuit_breaker.acquire(&upstream_ip) {
+            continue;
+        }
 
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => return Ok(stream),
+        if let Some(mut stream) = state.connection_pool.take(&upstream_ip) {
+            log::debug!("Reusing idle connection to upstream {}", upstream_ip);
+            stream.set_read_timeout(state.timeouts.body_read);
+            return Ok((stream, upstream_ip));
+        }
+        let connect = upstream::connect(&upstream_ip, &state.upstream_tls);
+        let result = timeouts::run(state.timeouts.connect, connect)
+            .await
+            .unwrap_or_else(|| {
+                Err(Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out connecting",
+                ))
+            });
+        match result {
+            Ok(stream) => {
+                let mut stream = HttpStream::new(stream);
+                stream.set_read_timeout(state.timeouts.body_read);
+                return Ok((stream, upstream_ip));
+            }
             Err(err) => {
-                // handle dead upstream_addresses
                 log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                let mut live_upstream_addresses = state.live_upstream_addresses.write().await;
-                live_upstream_addresses.swap_remove(upstream_idx); // remove the dead upstream
-
-                // All upstreams are dead, return Err
-                if live_upstream_addresses.len() == 0 {
-                    log::error!("All upstreams are dead");
-                    return Err(Error::new(ErrorKind::Other, "All upstreams are dead"));
-                }
+                timed_out = err.kind() == std::io::ErrorKind::TimedOut;
+                state.record_upstream_failure(&upstream_ip);
             }
         }
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Logs a response that is about to be sent to a client and counts it in the metrics
+fn log_response(state: &ProxyState, client_ip: &str, response: &http::Response<Vec<u8>>) {
     log::info!(
         "{} <- {}",
         client_ip,
-        response::format_response_line(&response)
+        response::format_response_line(response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    state.metrics.record_response(response.status());
+}
+
+async fn send_response<S>(
+    state: &ProxyState,
+    client_conn: &mut HttpStream<S>,
+    client_ip: &str,
+    response: &http::Response<Vec<u8>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    log_response(state, client_ip, response);
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
 }
 
-async fn check_rate(state: &ProxyState, client_conn: &mut TcpStream) -> Result<(), std::io::Error> {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    let mut rate_limiting_counter = state.rate_limiting_counter.clone().lock_owned().await;
-    let cnt = rate_limiting_counter.entry(client_ip).or_insert(0);
-    *cnt += 1;
-
-    if *cnt > state.max_requests_per_minute {
-        let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-        // send_response(&mut client_conn, &response).await;
-        if let Err(error) = response::write_to_stream(&response, client_conn).await {
-            log::warn!("Failed to send response to client: {}", error);
-        }
-        return Err(Error::new(ErrorKind::Other, "Rate limiting"));
+/// Checks a request against the rate limits that apply to it. Rejected requests are answered with
+/// 429 Too Many Requests here. Allowed requests get the decision stored in their extensions, so that
+/// the rate limit headers can be added to the upstream's response.
+async fn check_rate<S>(
+    state: &ProxyState,
+    request: &mut http::Request<Vec<u8>>,
+    client_conn: &mut HttpStream<S>,
+    client_ip: &str,
+) -> Result<(), std::io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(decision) = state.rate_limits.check(request, client_ip).await else {
+        return Ok(());
+    };
+    if !decision.allowed {
+        let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+        decision.add_headers(response.headers_mut());
+        state.metrics.record_rate_limited();
+        send_response(state, client_conn, client_ip, &response).await;
+        return Err(Error::other("Rate limiting"));
     }
+    request.extensions_mut().insert(decision);
     Ok(())
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Reads requests from a client connection (plaintext or TLS, as given by scheme) and forwards each
+/// of them to an upstream. The connection speaks HTTP/2 if the client negotiated it with ALPN
+/// (http2 is true), sends the HTTP/2 preface straight away, or upgrades to h2c; otherwise it speaks
+/// HTTP/1.x. In tcp mode, the connection is passed to proxy_tcp instead.
+async fn handle_connection<S>(
+    client_conn: S,
+    client_addr: IpAddr,
+    scheme: &'static str,
+    http2: bool,
+    state: &Arc<ProxyState>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_ip = client_addr.to_string();
     log::info!("Connection received from {}", client_ip);
+    let _client_connection_guard = state.metrics.track_client_connection();
+    let mut client_conn = HttpStream::new(client_conn);
+    if state.config.mode == Mode::Tcp {
+        proxy_tcp(&mut client_conn, client_addr, state).await;
+        return;
+    }
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+    let upgraded = if http2 {
+        None
+    } else {
+        let preface = async {
+            tokio::select! {
+                preface = http2::has_preface(&mut client_conn) => Some(preface),
+                _ = state.shutdown.started() => None,
+            }
+        };
+        match timeouts::run(state.timeouts.idle, preface).await {
+            Some(Some(Ok(true))) => None,
+            Some(Some(Ok(false))) => {
+                // Only cleartext connections can be upgraded to HTTP/2 (RFC 7540 section 3.2)
+                let allow_h2c = scheme == "http";
+                let upgraded =
+                    serve_http1(&mut client_conn, client_addr, scheme, allow_h2c, state).await;
+                if upgraded.is_none() {
+                    return;
+                }
+                upgraded
+            }
+            None => {
+                log::debug!("Client connection has been idle for too long; closing it");
+                return;
+            }
+            Some(None) => {
+                log::debug!("Shutting down; closing idle client connection");
+                return;
+            }
+            Some(Some(Err(io_err))) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+        }
+    };
+    log::debug!("Serving HTTP/2 to {}", client_ip);
+    let proxy = |conn| {
+        let state = state.clone();
+        async move {
+            let mut conn = HttpStream::new(conn);
+            serve_http1(&mut conn, client_addr, scheme, false, &state).await;
         }
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+    let shutdown = state.shutdown.started();
+    let serve = http2::serve(client_conn, upgraded, state.timeouts.idle, shutdown, proxy);
+    if let Err(error) = serve.await {
+        log::info!("Error serving HTTP/2 to {}: {}", client_ip, error);
+    }
+}
+
+/// Connects a client to an upstream picked by the balancer and copies bytes between them, without
+/// parsing them, until both sides have closed their end or the connection has been idle for too
+/// long
+async fn proxy_tcp<S>(client_conn: &mut HttpStream<S>, client_addr: IpAddr, state: &ProxyState)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_ip = client_addr.to_string();
+    let (mut upstream_conn, upstream_ip) =
+        match connect_to_upstream(state, client_addr, config::DEFAULT_POOL, &[], None).await {
+            Ok(upstream) => upstream,
+            Err(error) => {
+                log::error!("Could not connect {} to an upstream: {}", client_ip, error);
+                return;
+            }
+        };
+    state.circuit_breaker.record_success(&upstream_ip);
+    let _connection_guard = state.active_connections.track(&upstream_ip);
+    log::info!("{} <-> {}: connected", client_ip, upstream_ip);
+    let tunnel = tunnel::run(client_conn, &mut upstream_conn, state.timeouts.idle);
+    match state.unless_disabled(&upstream_ip, tunnel).await {
+        Some(Ok(transferred)) => log::info!(
+            "{} <-> {}: closed after {} bytes up and {} bytes down",
+            client_ip,
+            upstream_ip,
+            transferred.client_to_upstream,
+            transferred.upstream_to_client
+        ),
+        Some(Err(error)) => log::info!("{} <-> {}: closed: {}", client_ip, upstream_ip, error),
+        None => log::info!(
+            "{} <-> {}: closed; upstream disabled",
+            client_ip,
+            upstream_ip
+        ),
+    }
+}
+
+/// Reads HTTP/1.x requests from a client connection and forwards each of them to an upstream,
+/// until the connection should be closed. If allow_h2c is true and the client asks to upgrade to
+/// h2c, it is sent 101 Switching Protocols, and the HEADERS frame that carries the request on as
+/// an HTTP/2 stream is returned.
+async fn serve_http1<S>(
+    client_conn: &mut HttpStream<S>,
+    client_addr: IpAddr,
+    scheme: &'static str,
+    allow_h2c: bool,
+    state: &ProxyState,
+) -> Option<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_ip = client_addr.to_string();
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Wait for the client to start sending its next request, unless it has already pipelined
+        // it behind the previous one
+        if client_conn.buffered().is_empty() {
+            client_conn.set_read_timeout(None);
+            let next_request = async {
+                tokio::select! {
+                    filled = client_conn.fill() => Some(filled),
+                    _ = state.shutdown.started() => None,
+                }
+            };
+            match timeouts::run(state.timeouts.idle, next_request).await {
+                None => {
+                    log::debug!("Client connection has been idle for too long; closing it");
+                    return None;
+                }
+                Some(None) => {
+                    log::debug!("Shutting down; closing idle client connection");
+                    return None;
+                }
+                Some(Some(Ok(0))) => {
+                    log::debug!("Client finished sending requests. Shutting down connection");
+                    return None;
+                }
+                Some(Some(Ok(_))) => {}
+                Some(Some(Err(io_err))) => {
+                    log::info!("Error reading request from client stream: {}", io_err);
+                    return None;
+                }
+            }
+        }
+        client_conn.set_read_timeout(state.timeouts.body_read);
+
+        // Read a request's headers from the client. The body is streamed to the upstream later.
+        let read_headers = request::read_headers(client_conn);
+        let Some(result) = timeouts::run(state.timeouts.header_read, read_headers).await else {
+            log::info!("Client {} took too long to send request headers", client_ip);
+            let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+            send_response(state, client_conn, &client_ip, &response).await;
+            return None;
+        };
+        let mut request = match result {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
-                return;
+                return None;
+            }
+            Err(request::Error::ConnectionError(io_err))
+                if io_err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                log::info!("Client {} stopped sending request headers", client_ip);
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                send_response(state, client_conn, &client_ip, &response).await;
+                return None;
             }
             // Handle I/O error in reading from the client
             Err(request::Error::ConnectionError(io_err)) => {
                 log::info!("Error reading request from client stream: {}", io_err);
-                return;
+                return None;
             }
             Err(error) => {
+                // We can't tell where the next request would start, so close the connection
                 log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                send_response(state, client_conn, &client_ip, &response).await;
+                return None;
+            }
+        };
+        let request_body = match request::body_length(&request) {
+            Ok(request_body) => request_body,
+            Err(error) => {
+                log::debug!("Error parsing request: {:?}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                send_response(state, client_conn, &client_ip, &response).await;
+                return None;
+            }
+        };
+        if let (Some(max_size), BodyLength::Fixed(content_length)) =
+            (state.max_request_body_size, request_body)
+        {
+            if content_length > max_size {
+                // Close the connection rather than reading the body just to throw it away
+                log::debug!("Request body of {} bytes is too large", content_length);
+                let response = response::make_http_error(http::StatusCode::PAYLOAD_TOO_LARGE);
+                send_response(state, client_conn, &client_ip, &response).await;
+                return None;
+            }
+        }
+        // HTTP/1.1 requests have to say which host they are for (RFC 7230 section 5.4)
+        if request.version() >= http::Version::HTTP_11
+            && !request.headers().contains_key(http::header::HOST)
+        {
+            log::debug!("Request without a Host header");
+            let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+            send_response(state, client_conn, &client_ip, &response).await;
+            return None;
+        }
+        if hop_by_hop::has_unsupported_expectation(&request) {
+            log::debug!("Request with an unsupported Expect header");
+            let response = response::make_http_error(http::StatusCode::EXPECTATION_FAILED);
+            send_response(state, client_conn, &client_ip, &response).await;
+            return None;
+        }
+        if allow_h2c && request_body == BodyLength::Empty {
+            if let Some(frame) = http2::h2c_upgrade(&request) {
+                let response = http::Response::builder()
+                    .status(http::StatusCode::SWITCHING_PROTOCOLS)
+                    .header(http::header::CONNECTION, "upgrade")
+                    .header(http::header::UPGRADE, "h2c")
+                    .version(http::Version::HTTP_11)
+                    .body(Vec::new())
+                    .unwrap();
+                send_response(state, client_conn, &client_ip, &response).await;
+                return Some(frame);
+            }
+        }
+        let mut client = ClientConnection::new(&request);
+        if state.shutdown.is_started() {
+            // Clients are told that this is their last request on the connection
+            client.keep_alive = false;
+        }
+        // rate limiting
+        if let Err(_error) = check_rate(state, &mut request, client_conn, &client_ip).await {
+            log::error!("{} rate limiting", &client_ip);
+            if !skip_body(client_conn, client, request_body).await {
+                return None;
+            }
+            continue;
+        }
+
+        let Some(pool) = state.router.route(&mut request) else {
+            log::info!(
+                "No route for {} from {}",
+                request::format_request_line(&request),
+                client_ip
+            );
+            let response = response::make_http_error(http::StatusCode::NOT_FOUND);
+            send_response(state, client_conn, &client_ip, &response).await;
+            if !skip_body(client_conn, client, request_body).await {
+                return None;
+            }
+            continue;
+        };
+        let Ok((outcome, upstream_conn, upstream_ip)) = forward_request(
+            state,
+            request,
+            request_body,
+            client_conn,
+            client,
+            &client_ip,
+            client_addr,
+            scheme,
+            pool,
+        )
+        .await
+        else {
+            return None;
+        };
+        match outcome {
+            Outcome::KeepAlive | Outcome::ClientClosing => {
+                log::debug!("Forwarded response to client");
+                // Upstreams that were drained or disabled don't get any more requests, so there is
+                // no point keeping their connections around
+                if state.upstream_status(&upstream_ip) == UpstreamStatus::Enabled {
+                    state.connection_pool.put(&upstream_ip, upstream_conn);
+                }
+                if outcome == Outcome::ClientClosing {
+                    log::debug!("Client asked for its connection to be closed; closing");
+                    return None;
+                }
+            }
+            Outcome::UpstreamClosing => {
+                log::debug!("Forwarded response to client; upstream is closing its connection")
+            }
+            Outcome::Close => {
+                log::debug!("Response was delimited by closing the connection; closing");
+                return None;
+            }
+            Outcome::Tunnel => {
+                log::debug!("Tunnel closed; closing");
+                return None;
+            }
+        }
+    }
+}
+
+/// Skips over the body of a request that was answered without forwarding it, so that the next
+/// request on the connection can be read. Returns false if the connection should be closed
+/// instead: the client doesn't want to send more requests, is waiting for a 100 Continue before
+/// sending the body, or the body couldn't be read.
+async fn skip_body<S>(
+    client_conn: &mut HttpStream<S>,
+    client: ClientConnection,
+    request_body: BodyLength,
+) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !client.keep_alive || (client.expects_continue && request_body != BodyLength::Empty) {
+        return false;
+    }
+    let mut discard = tokio::io::sink();
+    body::forward(client_conn, &mut discard, request_body, None)
+        .await
+        .is_ok()
+}
+
+/// What can be done with the connections once a response has been forwarded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    /// Both the client and upstream connections can be used for further requests
+    KeepAlive,
+    /// The client connection can be used for further requests, but the upstream won't accept any
+    /// more on its connection
+    UpstreamClosing,
+    /// The upstream connection can be used for further requests, but the client connection has to
+    /// be closed: the client asked for that, or its response had to be delimited by closing it
+    ClientClosing,
+    /// The response ended with the upstream closing the connection, so the only way to tell the
+    /// client where the response ends is to close the client connection too
+    Close,
+    /// The upstream switched protocols (or accepted a CONNECT), so the connections have been
+    /// joined into a tunnel and can't be used for HTTP any more
+    Tunnel,
+}
+
+/// Why a request couldn't be sent to an upstream
+enum SendError {
+    /// The upstream failed: the request couldn't be written to it, or no valid response came back
+    /// (in time). Contains the status of the error response to send the client (502 Bad Gateway or
+    /// 504 Gateway Timeout) if the request isn't retried on another upstream.
+    Upstream(http::StatusCode),
+    /// The client failed to send the request body. Contains the status of the error response to
+    /// send it, if any.
+    Client(Option<http::StatusCode>),
+}
+
+/// Sends a request to an upstream picked by the balancer, and then streams the upstream's response
+/// back to the client. If the upstream fails the request and the retry policy allows it, the
+/// request is sent again to a different upstream. Request bodies are streamed from the client
+/// unless the request may be retried, in which case they are small enough to keep in memory.
+/// Everything has to be done by the request timeout, if there is one. The configured header rules
+/// are applied to the request for each upstream it is sent to, and to the response.
+///
+/// Returns what can be done with the connections afterwards, along with the upstream connection
+/// and address, or Err(()) if something went wrong (in which case an error response has been sent
+/// if possible and the client connection should be closed).
+#[allow(clippy::too_many_arguments)]
+async fn forward_request<S>(
+    state: &ProxyState,
+    mut request: http::Request<Vec<u8>>,
+    request_body: BodyLength,
+    client_conn: &mut HttpStream<S>,
+    client: ClientConnection,
+    client_ip: &str,
+    client_addr: IpAddr,
+    scheme: &str,
+    pool: &str,
+) -> Result<(Outcome, HttpStream<UpstreamStream>, String), ()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let host = headers::request_host(&request);
+    let method = request.method().to_string();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .to_string();
+    let request_id = state.header_rewriter.request_id(&request);
+    let variables = Variables {
+        client_ip,
+        scheme,
+        host: &host,
+        method: &method,
+        path: &path,
+        request_id: &request_id,
+        upstream: "",
+        pool,
+    };
+    let upgrade = hop_by_hop::upgrade(request.version(), request.headers());
+    hop_by_hop::remove_headers(request.headers_mut());
+    if let Some(protocols) = upgrade {
+        // Upgrades are passed on, so that the upstream can agree to them
+        hop_by_hop::set_upgrade(request.headers_mut(), protocols);
+    }
+    if client.expects_continue {
+        // The client is told to go ahead right away; upstreams aren't asked
+        request.headers_mut().remove(http::header::EXPECT);
+    }
+    state
+        .header_rewriter
+        .add_proxy_headers(&mut request, &variables);
+    // Upstreams are always spoken to in HTTP/1.1, whatever the client speaks
+    *request.version_mut() = http::Version::HTTP_11;
+    let deadline = state.timeouts.request_deadline();
+    let retryable = state.retry_policy.is_retryable(&request, request_body);
+    if client.expects_continue && request_body != BodyLength::Empty {
+        let response = http::Response::builder()
+            .status(http::StatusCode::CONTINUE)
+            .version(http::Version::HTTP_11)
+            .body(Vec::new())
+            .unwrap();
+        if let Err(error) = response::write_to_stream(&response, client_conn).await {
+            log::info!("Failed to send 100 Continue to client: {}", error);
+            return Err(());
+        }
+    }
+    if retryable && request_body != BodyLength::Empty {
+        let read_body = body::read_to_end(client_conn, request_body, state.max_request_body_size);
+        match timeouts::run_until(deadline, read_body).await {
+            Some(Ok(body)) => *request.body_mut() = body,
+            Some(Err(body::Error::ReadError(error)))
+                if error.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                log::info!("Client stopped sending the request body: {}", error);
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                send_response(state, client_conn, client_ip, &response).await;
+                return Err(());
+            }
+            Some(Err(error)) => {
+                log::info!("Error reading request body from client: {}", error);
+                return Err(());
+            }
+            None => {
+                log::info!("Client took too long to send the request body");
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                send_response(state, client_conn, client_ip, &response).await;
+                return Err(());
+            }
+        }
+    }
+
+    let affinity = state.session_affinity.key(&request);
+    if let Some(affinity) = &affinity {
+        // Kept with the request, so that the response can pin the client if need be
+        request.extensions_mut().insert(affinity.clone());
+    }
+    // The headers before the rules are applied, since they are applied again for each upstream
+    let proxied_headers = request.headers().clone();
+    // Upstreams the request has been sent to so far
+    let mut tried: Vec<String> = Vec::new();
+    loop {
+        // Each request goes to whichever upstream the balancer picks for it, over an idle pooled
+        // connection if there is one
+        let connect = connect_to_upstream(state, client_addr, pool, &tried, affinity.as_ref());
+        let (mut upstream_conn, upstream_ip) = match timeouts::run_until(deadline, connect).await {
+            Some(Ok(upstream)) => upstream,
+            Some(Err(error)) => {
+                let status = if error.kind() == std::io::ErrorKind::TimedOut {
+                    http::StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    http::StatusCode::BAD_GATEWAY
+                };
+                let response = response::make_http_error(status);
+                send_response(state, client_conn, client_ip, &response).await;
+                return Err(());
+            }
+            None => {
+                log::warn!("Timed out connecting to an upstream before the request deadline");
+                let response = response::make_http_error(http::StatusCode::GATEWAY_TIMEOUT);
+                send_response(state, client_conn, client_ip, &response).await;
+                return Err(());
             }
         };
+        // Count this request against the upstream until its response has been forwarded
+        let _request_guard = state.active_connections.track(&upstream_ip);
         log::info!(
             "{} -> {}: {}",
             client_ip,
             upstream_ip,
             request::format_request_line(&request)
         );
-
-        // rate limiting
-        if state.max_requests_per_minute > 0 {
-            if let Err(_error) = check_rate(&state, &mut client_conn).await {
-                log::error!("{} rate limiting", &client_ip);
-                continue;
+        tried.push(upstream_ip.clone());
+        let variables = Variables {
+            upstream: &upstream_ip,
+            ..variables
+        };
+        *request.headers_mut() = proxied_headers.clone();
+        state
+            .header_rewriter
+            .rewrite_request(&mut request, &variables);
+        // HTTP/1.0 clients may leave out the Host header, which HTTP/1.1 requires
+        if !request.headers().contains_key(http::header::HOST) {
+            let host = upstream::authority(&upstream_ip);
+            if let Ok(host) = http::HeaderValue::from_str(host) {
+                request.headers_mut().insert(http::header::HOST, host);
             }
         }
+        // Chunked bodies are re-encoded as they are forwarded, so any Content-Length sent
+        // alongside Transfer-Encoding (which it overrides) would be wrong
+        body::set_length_headers(request.headers_mut(), request_body);
+
+        let send = async {
+            let send = send_request(
+                state,
+                &request,
+                request_body,
+                client_conn,
+                &mut upstream_conn,
+                &upstream_ip,
+            );
+            state
+                .unless_disabled(&upstream_ip, send)
+                .await
+                .unwrap_or_else(|| {
+                    log::warn!(
+                        "Upstream {} was disabled while handling a request",
+                        upstream_ip
+                    );
+                    // Not the upstream's fault, so it isn't counted against it
+                    Err(SendError::Upstream(http::StatusCode::BAD_GATEWAY))
+                })
+        };
+        let result = timeouts::run_until(deadline, send)
+            .await
+            .unwrap_or_else(|| {
+                log::warn!(
+                    "Upstream {} did not respond before the request deadline",
+                    upstream_ip
+                );
+                state.record_upstream_failure(&upstream_ip);
+                // There is no time left to retry
+                Err(SendError::Upstream(http::StatusCode::GATEWAY_TIMEOUT))
+            });
+        let timed_out = deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline);
+        let (reason, failure) = match result {
+            Ok((response, response_body)) => {
+                if timed_out
+                    || !(state.retry_policy.retries_status(response.status())
+                        && can_retry(state, pool, retryable, &tried).await)
+                {
+                    let forward = forward_response(
+                        state,
+                        &request,
+                        client,
+                        response,
+                        response_body,
+                        &variables,
+                        client_conn,
+                        &mut upstream_conn,
+                    );
+                    let forward = state.unless_disabled(&upstream_ip, forward);
+                    let Some(outcome) = timeouts::run_until(deadline, forward).await else {
+                        log::warn!(
+                            "Response from {} did not finish before the request deadline",
+                            upstream_ip
+                        );
+                        return Err(());
+                    };
+                    let Some(outcome) = outcome else {
+                        log::warn!(
+                            "Upstream {} was disabled while forwarding its response; closing the \
+                             client connection",
+                            upstream_ip
+                        );
+                        return Err(());
+                    };
+                    let outcome = outcome?;
+                    if outcome == Outcome::Tunnel {
+                        // The tunnel stays open for as long as both sides want it (and keep using
+                        // it), so the request deadline doesn't apply
+                        log::info!("{} <-> {}: tunnel open", client_ip, upstream_ip);
+                        let tunnel =
+                            tunnel::run(client_conn, &mut upstream_conn, state.timeouts.idle);
+                        match state.unless_disabled(&upstream_ip, tunnel).await {
+                            Some(Ok(transferred)) => log::info!(
+                                "{} <-> {}: tunnel closed after {} bytes up and {} bytes down",
+                                client_ip,
+                                upstream_ip,
+                                transferred.client_to_upstream,
+                                transferred.upstream_to_client
+                            ),
+                            Some(Err(error)) => log::info!(
+                                "{} <-> {}: tunnel closed: {}",
+                                client_ip,
+                                upstream_ip,
+                                error
+                            ),
+                            None => {
+                                log::info!(
+                                    "{} <-> {}: tunnel closed; upstream disabled",
+                                    client_ip,
+                                    upstream_ip
+                                );
+                                return Err(());
+                            }
+                        }
+                    }
+                    return Ok((outcome, upstream_conn, upstream_ip));
+                }
+                ("status", format!("status {}", response.status().as_u16()))
+            }
+            Err(SendError::Upstream(status))
+                if !timed_out && can_retry(state, pool, retryable, &tried).await =>
+            {
+                (
+                    "error",
+                    if status == http::StatusCode::GATEWAY_TIMEOUT {
+                        "a timeout".to_string()
+                    } else {
+                        "an error".to_string()
+                    },
+                )
+            }
+            Err(SendError::Upstream(status)) => {
+                let response = response::make_http_error(status);
+                send_response(state, client_conn, client_ip, &response).await;
+                return Err(());
+            }
+            Err(SendError::Client(status)) => {
+                if let Some(status) = status {
+                    let response = response::make_http_error(status);
+                    send_response(state, client_conn, client_ip, &response).await;
+                }
+                return Err(());
+            }
+        };
+        let retry = tried.len();
+        let backoff = state.retry_policy.backoff(retry);
+        log::warn!(
+            "Retrying {} on another upstream in {}ms after {} from {} (retry {} of {})",
+            request::format_request_line(&request),
+            backoff.as_millis(),
+            failure,
+            upstream_ip,
+            retry,
+            state.retry_policy.max_retries
+        );
+        state.metrics.record_retry(&upstream_ip, reason);
+        timeouts::run_until(deadline, tokio::time::sleep(backoff)).await;
+    }
+}
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+/// Returns whether a request that has been sent to the given upstreams can be retried on another
+/// live upstream in its pool
+async fn can_retry(state: &ProxyState, pool: &str, retryable: bool, tried: &[String]) -> bool {
+    retryable
+        && tried.len() <= state.retry_policy.max_retries
+        && !state.live_upstreams_in_pool(pool, tried).await.is_empty()
+}
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+/// Sends a request to the upstream, either from memory or streaming its body from the client, and
+/// reads the upstream's response headers. Failures are counted against the upstream in the circuit
+/// breaker.
+async fn send_request<S>(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    request_body: BodyLength,
+    client_conn: &mut HttpStream<S>,
+    upstream_conn: &mut HttpStream<UpstreamStream>,
+    upstream_ip: &str,
+) -> Result<(http::Response<Vec<u8>>, BodyLength), SendError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Forward the request to the server
+    let forwarded_at = Instant::now();
+    let written = if request.body().is_empty() {
+        request::write_headers(request, upstream_conn).await
+    } else {
+        // The body has already been read, so that it can be sent again on a retry
+        request::write_to_stream(request, upstream_conn).await
+    };
+    if let Err(error) = written {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_ip,
+            error
+        );
+        state.record_upstream_failure(upstream_ip);
+        return Err(SendError::Upstream(http::StatusCode::BAD_GATEWAY));
+    }
+    let streamed_body = if request.body().is_empty() {
+        request_body
+    } else {
+        BodyLength::Empty
+    };
+    match body::forward(
+        client_conn,
+        upstream_conn,
+        streamed_body,
+        state.max_request_body_size,
+    )
+    .await
+    {
+        Ok(_) => {}
+        Err(body::Error::ReadError(error)) if error.kind() == std::io::ErrorKind::TimedOut => {
+            log::info!("Client stopped sending the request body: {}", error);
+            return Err(SendError::Client(Some(http::StatusCode::REQUEST_TIMEOUT)));
+        }
+        Err(body::Error::ReadError(error)) => {
+            log::info!("Error reading request body from client: {}", error);
+            return Err(SendError::Client(None));
+        }
+        Err(body::Error::Truncated(bytes_copied)) => {
+            log::info!(
+                "Client hung up after sending {} bytes of the request body",
+                bytes_copied
+            );
+            return Err(SendError::Client(None));
+        }
+        Err(body::Error::TooLarge) => {
+            return Err(SendError::Client(Some(http::StatusCode::PAYLOAD_TOO_LARGE)));
+        }
+        Err(body::Error::InvalidChunk) => {
+            log::debug!("Client sent a malformed chunked request body");
+            return Err(SendError::Client(Some(http::StatusCode::BAD_REQUEST)));
+        }
+        Err(body::Error::WriteError(error)) => {
             log::error!(
-                "Failed to send request to upstream {}: {}",
+                "Failed to send request body to upstream {}: {}",
                 upstream_ip,
                 error
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+            state.record_upstream_failure(upstream_ip);
+            return Err(SendError::Upstream(http::StatusCode::BAD_GATEWAY));
         }
-        log::debug!("Forwarded request to server");
+    }
+    log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
+    // Read the server's response headers. balancebeam answers Expect: 100-continue itself, so
+    // interim responses from the upstream are dropped.
+    let response = loop {
+        let read_headers = response::read_headers(upstream_conn);
+        let response = match timeouts::run(state.timeouts.header_read, read_headers).await {
+            Some(Ok(response)) => response,
+            Some(Err(response::Error::ConnectionError(error)))
+                if error.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                log::error!(
+                    "Upstream {} stopped sending its response: {}",
+                    upstream_ip,
+                    error
+                );
+                state.record_upstream_failure(upstream_ip);
+                return Err(SendError::Upstream(http::StatusCode::GATEWAY_TIMEOUT));
+            }
+            Some(Err(error)) => {
                 log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+                state.record_upstream_failure(upstream_ip);
+                return Err(SendError::Upstream(http::StatusCode::BAD_GATEWAY));
+            }
+            None => {
+                log::error!("Upstream {} took too long to respond", upstream_ip);
+                state.record_upstream_failure(upstream_ip);
+                return Err(SendError::Upstream(http::StatusCode::GATEWAY_TIMEOUT));
             }
         };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        if response.status().is_informational()
+            && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+        {
+            log::debug!(
+                "Dropping interim {} response from {}",
+                response.status(),
+                upstream_ip
+            );
+            continue;
+        }
+        break response;
+    };
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS
+        && !request.headers().contains_key(http::header::UPGRADE)
+    {
+        log::error!(
+            "Upstream {} switched protocols without being asked to",
+            upstream_ip
+        );
+        state.record_upstream_failure(upstream_ip);
+        return Err(SendError::Upstream(http::StatusCode::BAD_GATEWAY));
+    }
+    let response_body = match response::body_length(&response, request.method()) {
+        Ok(response_body) => response_body,
+        Err(error) => {
+            log::error!("Error reading response from server: {:?}", error);
+            state.record_upstream_failure(upstream_ip);
+            return Err(SendError::Upstream(http::StatusCode::BAD_GATEWAY));
+        }
+    };
+    state
+        .metrics
+        .record_upstream_response(upstream_ip, response.status(), forwarded_at.elapsed());
+    if response.status().is_server_error() {
+        state.record_upstream_failure(upstream_ip);
+    } else {
+        state.circuit_breaker.record_success(upstream_ip);
+    }
+    Ok((response, response_body))
+}
+
+/// Streams an upstream's response back to the client. The body is never held in memory as a
+/// whole. The upstream's hop-by-hop headers are replaced with balancebeam's own (except for the
+/// ones that switch protocols), and the header rules applied.
+///
+/// Returns what can be done with the connections afterwards, or Err(()) if something went wrong (in
+/// which case both connections should be closed).
+#[allow(clippy::too_many_arguments)]
+async fn forward_response<S>(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    client: ClientConnection,
+    mut response: http::Response<Vec<u8>>,
+    response_body: BodyLength,
+    variables: &Variables<'_>,
+    client_conn: &mut HttpStream<S>,
+    upstream_conn: &mut HttpStream<UpstreamStream>,
+) -> Result<Outcome, ()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let upstream_ip = variables.upstream;
+    let upstream_keep_alive = hop_by_hop::keep_alive(response.version(), response.headers())
+        && response_body != BodyLength::UntilClose;
+    // Once the upstream agrees to switch protocols or to a CONNECT, the connections become a
+    // tunnel
+    let tunnel = response.status() == http::StatusCode::SWITCHING_PROTOCOLS
+        || (request.method() == http::Method::CONNECT && response.status().is_success());
+    let switching_to = hop_by_hop::upgrade(response.version(), response.headers());
+    hop_by_hop::remove_headers(response.headers_mut());
+    if let Some(decision) = request.extensions().get::<rate_limit::Decision>() {
+        decision.add_headers(response.headers_mut());
+    }
+    if let Some(affinity) = request.extensions().get::<affinity::Key>() {
+        if let Some(cookie) = state.session_affinity.set_cookie(affinity, upstream_ip) {
+            response
+                .headers_mut()
+                .append(http::header::SET_COOKIE, cookie);
+        }
+    }
+    state
+        .header_rewriter
+        .rewrite_response(&mut response, variables);
+    // Chunked bodies are re-encoded as they are forwarded, so any Content-Length sent alongside
+    // Transfer-Encoding (which it overrides) would be wrong
+    body::set_length_headers(response.headers_mut(), response_body);
+
+    // HTTP/1.0 clients don't understand chunked encoding, so chunked bodies are decoded for them
+    // and delimited by closing the connection instead
+    let decode_chunks =
+        response_body == BodyLength::Chunked && client.version < http::Version::HTTP_11;
+    if decode_chunks {
+        response
+            .headers_mut()
+            .remove(http::header::TRANSFER_ENCODING);
+    }
+    // Shutdown may have started while the request was being handled
+    let client_keep_alive = client.keep_alive
+        && !decode_chunks
+        && response_body != BodyLength::UntilClose
+        && !state.shutdown.is_started();
+    if tunnel {
+        if let Some(protocols) = switching_to {
+            hop_by_hop::set_upgrade(response.headers_mut(), protocols);
+        }
+    } else if !client_keep_alive {
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    } else if client.version < http::Version::HTTP_11 {
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("keep-alive"),
+        );
+    }
+    // Responses go out in the version balancebeam speaks, whatever the upstream speaks
+    *response.version_mut() = http::Version::HTTP_11;
+
+    // Forward the response to the client, streaming the body as it arrives. Once the headers have
+    // been sent we can no longer send an error response, so if anything goes wrong after that, all
+    // we can do is close the connection.
+    log_response(state, variables.client_ip, &response);
+    if let Err(error) = response::write_headers(&response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+        return Err(());
+    }
+    let forwarded = if decode_chunks {
+        body::forward_decoded(upstream_conn, client_conn, None).await
+    } else {
+        body::forward(upstream_conn, client_conn, response_body, None).await
+    };
+    if let Err(error) = forwarded {
+        log::warn!(
+            "Failed to forward response body from {}: {}",
+            upstream_ip,
+            error
+        );
+        // The upstream resetting the connection partway through counts against it; the client
+        // going away doesn't
+        if matches!(error, body::Error::ReadError(_) | body::Error::Truncated(_)) {
+            state.record_upstream_failure(upstream_ip);
+        }
+        return Err(());
+    }
+    if tunnel {
+        return Ok(Outcome::Tunnel);
     }
+    Ok(match (client_keep_alive, upstream_keep_alive) {
+        (true, true) => Outcome::KeepAlive,
+        (true, false) => Outcome::UpstreamClosing,
+        (false, true) => Outcome::ClientClosing,
+        (false, false) => Outcome::Close,
+    })
 }
@@ -0,0 +1,354 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the buckets in the upstream latency histogram
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters and histograms describing what balancebeam has been doing. These are shared by all
+/// connection tasks and rendered in the Prometheus text format by the admin API's /metrics
+/// endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    /// Responses sent to clients, keyed by status code
+    responses: Mutex<BTreeMap<u16, u64>>,
+    /// Requests forwarded to upstreams, keyed by (upstream, status code of the upstream's response)
+    upstream_requests: Mutex<BTreeMap<(String, u16), u64>>,
+    /// Time from forwarding each request to an upstream until its response headers arrived
+    upstream_latency: Mutex<BTreeMap<String, Histogram>>,
+    /// Results of active health checks, keyed by (upstream, whether the check passed)
+    health_checks: Mutex<BTreeMap<(String, bool), u64>>,
+    /// Requests retried on another upstream, keyed by (upstream that failed them, reason)
+    retries: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// Number of times each upstream has been ejected by the circuit breaker
+    ejections: Mutex<BTreeMap<String, u64>>,
+    /// Number of requests rejected with 429 Too Many Requests
+    rate_limited: AtomicU64,
+    /// Number of client connections currently open
+    client_connections: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Number of observations that fell into each bucket (not cumulative)
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Per-upstream gauges that are read from the proxy state when the metrics are rendered, rather
+/// than being tracked by Metrics itself
+pub struct UpstreamGauges {
+    pub address: String,
+    pub live: bool,
+    pub active_connections: usize,
+    pub idle_connections: usize,
+}
+
+/// Decrements the open client connection gauge when dropped.
+pub struct ClientConnectionGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ClientConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .client_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Records a response sent to a client
+    pub fn record_response(&self, status: http::StatusCode) {
+        *self.responses.lock().entry(status.as_u16()).or_insert(0) += 1;
+    }
+
+    /// Records a response received from an upstream, along with how long it took for the response
+    /// headers to arrive
+    pub fn record_upstream_response(
+        &self,
+        upstream: &str,
+        status: http::StatusCode,
+        latency: Duration,
+    ) {
+        *self
+            .upstream_requests
+            .lock()
+            .entry((upstream.to_string(), status.as_u16()))
+            .or_insert(0) += 1;
+        self.upstream_latency
+            .lock()
+            .entry(upstream.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Records the result of an active health check
+    pub fn record_health_check(&self, upstream: &str, healthy: bool) {
+        *self
+            .health_checks
+            .lock()
+            .entry((upstream.to_string(), healthy))
+            .or_insert(0) += 1;
+    }
+
+    /// Records a request being retried after the given upstream failed it. reason is "error" if
+    /// the request couldn't be sent or the response couldn't be read, or "status" if the response
+    /// had a status that is retried.
+    pub fn record_retry(&self, upstream: &str, reason: &'static str) {
+        *self
+            .retries
+            .lock()
+            .entry((upstream.to_string(), reason))
+            .or_insert(0) += 1;
+    }
+
+    /// Records an upstream being ejected by the circuit breaker
+    pub fn record_ejection(&self, upstream: &str) {
+        *self
+            .ejections
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Records a request that was rejected by the rate limiter
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client connection as open until the returned guard is dropped
+    pub fn track_client_connection(&self) -> ClientConnectionGuard<'_> {
+        self.client_connections.fetch_add(1, Ordering::Relaxed);
+        ClientConnectionGuard { metrics: self }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self, upstreams: &[UpstreamGauges]) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "balancebeam_responses_total",
+            "counter",
+            "Responses sent to clients",
+        );
+        for (status, count) in self.responses.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_responses_total{{status=\"{}\"}} {}",
+                status, count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_upstream_requests_total",
+            "counter",
+            "Requests forwarded to each upstream, by the status code of the upstream's response",
+        );
+        for ((upstream, status), count) in self.upstream_requests.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_upstream_requests_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                escape(upstream),
+                status,
+                count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_upstream_latency_seconds",
+            "histogram",
+            "Time until each upstream's response headers arrived",
+        );
+        for (upstream, histogram) in self.upstream_latency.lock().iter() {
+            let upstream = escape(upstream);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "balancebeam_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                    upstream, bound, cumulative
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "balancebeam_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+                upstream, histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "balancebeam_upstream_latency_seconds_sum{{upstream=\"{}\"}} {}",
+                upstream, histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "balancebeam_upstream_latency_seconds_count{{upstream=\"{}\"}} {}",
+                upstream, histogram.count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_client_connections",
+            "gauge",
+            "Client connections currently open",
+        );
+        writeln!(
+            out,
+            "balancebeam_client_connections {}",
+            self.client_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        header(
+            &mut out,
+            "balancebeam_upstream_connections",
+            "gauge",
+            "Connections to each upstream that a request is currently being forwarded over",
+        );
+        for upstream in upstreams {
+            writeln!(
+                out,
+                "balancebeam_upstream_connections{{upstream=\"{}\"}} {}",
+                escape(&upstream.address),
+                upstream.active_connections
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_upstream_idle_connections",
+            "gauge",
+            "Idle keep-alive connections to each upstream waiting in the pool",
+        );
+        for upstream in upstreams {
+            writeln!(
+                out,
+                "balancebeam_upstream_idle_connections{{upstream=\"{}\"}} {}",
+                escape(&upstream.address),
+                upstream.idle_connections
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_upstream_live",
+            "gauge",
+            "Whether each upstream is currently in the rotation (1) or not (0)",
+        );
+        for upstream in upstreams {
+            writeln!(
+                out,
+                "balancebeam_upstream_live{{upstream=\"{}\"}} {}",
+                escape(&upstream.address),
+                upstream.live as u8
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_health_checks_total",
+            "counter",
+            "Active health checks performed against each upstream, by result",
+        );
+        for ((upstream, healthy), count) in self.health_checks.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_health_checks_total{{upstream=\"{}\",result=\"{}\"}} {}",
+                escape(upstream),
+                if *healthy { "success" } else { "failure" },
+                count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_upstream_retries_total",
+            "counter",
+            "Requests retried on another upstream after each upstream failed them, by reason",
+        );
+        for ((upstream, reason), count) in self.retries.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_upstream_retries_total{{upstream=\"{}\",reason=\"{}\"}} {}",
+                escape(upstream),
+                reason,
+                count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_upstream_ejections_total",
+            "counter",
+            "Times each upstream has been ejected by the circuit breaker",
+        );
+        for (upstream, count) in self.ejections.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_upstream_ejections_total{{upstream=\"{}\"}} {}",
+                escape(upstream),
+                count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "balancebeam_rate_limited_total",
+            "counter",
+            "Requests rejected with 429 Too Many Requests",
+        );
+        writeln!(
+            out,
+            "balancebeam_rate_limited_total {}",
+            self.rate_limited.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+/// Escapes a label value for the Prometheus text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,107 @@
+use crate::stream::HttpStream;
+use crate::upstream::UpstreamStream;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A connection to an upstream that finished its last response and is waiting to be reused
+struct IdleConnection {
+    conn: HttpStream<UpstreamStream>,
+    idle_since: Instant,
+}
+
+/// Keep-alive connections to upstreams that aren't currently being used, so that requests don't
+/// each have to open a new connection. Connections are handed out one request at a time: a
+/// connection is taken out of the pool while a request is forwarded over it and put back once the
+/// response has been fully forwarded.
+pub struct ConnectionPool {
+    /// Idle connections to each upstream, with the most recently used last
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+    /// Maximum number of idle connections to keep per upstream (0 disables pooling)
+    max_idle_per_upstream: usize,
+    /// How long a connection may sit idle before it is closed
+    idle_timeout: Duration,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle_per_upstream: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle_per_upstream,
+            idle_timeout,
+        }
+    }
+
+    /// Takes the most recently used idle connection to the given upstream, if there is one that is
+    /// still usable. Connections that have timed out, or that the upstream has closed in the
+    /// meantime, are dropped along the way.
+    pub fn take(&self, upstream: &str) -> Option<HttpStream<UpstreamStream>> {
+        let mut idle = self.idle.lock();
+        let connections = idle.get_mut(upstream)?;
+        while let Some(idle_conn) = connections.pop() {
+            if idle_conn.idle_since.elapsed() < self.idle_timeout && is_reusable(&idle_conn.conn) {
+                return Some(idle_conn.conn);
+            }
+        }
+        None
+    }
+
+    /// Returns a connection to the pool once the response on it has been fully read. If the pool
+    /// already holds as many idle connections to the upstream as it may, the oldest one is closed.
+    pub fn put(&self, upstream: &str, conn: HttpStream<UpstreamStream>) {
+        if self.max_idle_per_upstream == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        let connections = idle.entry(upstream.to_string()).or_default();
+        if connections.len() >= self.max_idle_per_upstream {
+            connections.remove(0);
+        }
+        connections.push(IdleConnection {
+            conn,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Closes all idle connections to the given upstream
+    pub fn remove(&self, upstream: &str) {
+        self.idle.lock().remove(upstream);
+    }
+
+    /// Closes idle connections to upstreams that aren't in the given list
+    pub fn retain_upstreams(&self, upstreams: &[String]) {
+        self.idle
+            .lock()
+            .retain(|upstream, _| upstreams.contains(upstream));
+    }
+
+    /// Closes idle connections that have timed out
+    pub fn evict_expired(&self) {
+        let mut idle = self.idle.lock();
+        for connections in idle.values_mut() {
+            connections.retain(|idle_conn| idle_conn.idle_since.elapsed() < self.idle_timeout);
+        }
+        idle.retain(|_, connections| !connections.is_empty());
+    }
+
+    /// Returns the number of idle connections to the given upstream
+    pub fn idle_count(&self, upstream: &str) -> usize {
+        self.idle.lock().get(upstream).map_or(0, Vec::len)
+    }
+}
+
+/// Checks that an idle connection hasn't been closed by the upstream. An idle connection should have
+/// nothing to read; if reading doesn't block, the upstream has either hung up or sent something we
+/// didn't ask for, and either way the connection can't be used for another request. (For TLS
+/// connections this reads underneath the encryption, which is fine since a connection that had
+/// anything to read is dropped anyway.)
+fn is_reusable(conn: &HttpStream<UpstreamStream>) -> bool {
+    if !conn.buffered().is_empty() {
+        return false;
+    }
+    let mut byte = [0_u8; 1];
+    matches!(
+        conn.get_ref().tcp_stream().try_read(&mut byte),
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock
+    )
+}
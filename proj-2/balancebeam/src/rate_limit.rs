@@ -0,0 +1,379 @@
+use crate::config::{RateLimitConfig, RateLimitRule};
+use crate::rate_limit_store::{self, Store, WindowCounts};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The algorithm used to decide whether a client has sent too many requests.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// Each client has a bucket of tokens that refills at a steady rate. Every request takes a
+    /// token, so clients can burst up to the size of the bucket and then have to slow down to the
+    /// refill rate.
+    TokenBucket,
+    /// Count requests over a window that slides forward continuously, estimated by weighting the
+    /// count for the previous fixed window by how much of it still overlaps the sliding one. Unlike
+    /// a fixed window, this doesn't let a client send twice the limit around a window boundary.
+    #[default]
+    SlidingWindow,
+}
+
+/// What requests are counted together by a rate limit rule.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Key {
+    /// Each client IP address gets its own limit
+    #[default]
+    ClientIp,
+    /// Each client IP address gets its own limit, with the address taken from X-Forwarded-For as
+    /// set by trusted proxies in front of balancebeam (falling back to the IP address the request
+    /// came from if the header is missing or has too few entries)
+    ForwardedFor,
+    /// Each value of a header (e.g. an API key) gets its own limit
+    Header,
+    /// All requests share one limit
+    Global,
+}
+
+/// How many requests a client may send, and how that is enforced
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub algorithm: Algorithm,
+    /// Number of requests allowed per period
+    pub requests: usize,
+    pub period: Duration,
+    /// Size of the token bucket, i.e. how many requests can be sent at once after a quiet spell.
+    /// Only used by the token bucket algorithm; the sliding window always allows the full limit.
+    pub burst: usize,
+}
+
+/// The result of checking a request against a rate limit, which is reported to the client in the
+/// X-RateLimit-* (and, for rejected requests, Retry-After) response headers
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The most requests the client could have sent at this point
+    pub limit: usize,
+    /// How many more requests the client can send right now
+    pub remaining: usize,
+    /// How long until the client's allowance is back to the full limit
+    pub reset: Duration,
+    /// How long a rejected client has to wait before its next request would be allowed
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    /// Adds the rate limit headers to a response
+    pub fn add_headers(&self, headers: &mut http::HeaderMap) {
+        headers.insert("x-ratelimit-limit", self.limit.into());
+        headers.insert("x-ratelimit-remaining", self.remaining.into());
+        headers.insert("x-ratelimit-reset", whole_seconds(self.reset).into());
+        if let Some(retry_after) = self.retry_after {
+            headers.insert("retry-after", whole_seconds(retry_after).max(1).into());
+        }
+    }
+}
+
+/// Rounds up to a whole number of seconds, since that's all the headers can express
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + (duration.subsec_nanos() > 0) as u64
+}
+
+/// The state of a client's token bucket
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Applies a Limit to each client separately. Clients are identified by a key (e.g. their IP
+/// address).
+pub struct RateLimiter {
+    limit: Limit,
+    /// Token buckets, which are only ever kept in memory
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// Request counts for the sliding window algorithm, which may be shared with other limiters
+    /// and other instances of balancebeam
+    store: Arc<dyn Store>,
+    /// Prepended to keys in the store, so that different limiters' counts are kept apart
+    namespace: String,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit, store: Arc<dyn Store>, namespace: String) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+            store,
+            namespace,
+        }
+    }
+
+    /// Checks whether the client with the given key may send another request, counting the request
+    /// if so. Rejected requests aren't counted, so a client that keeps retrying too early isn't
+    /// locked out for longer. Also returns the window an allowed request was counted in, if any,
+    /// which is needed to refund it.
+    pub async fn check(&self, key: &str) -> (Decision, Option<u64>) {
+        match self.limit.algorithm {
+            Algorithm::TokenBucket => (self.check_bucket(key), None),
+            Algorithm::SlidingWindow => {
+                let (decision, window) = self.check_window(key).await;
+                (decision, Some(window).filter(|_| decision.allowed))
+            }
+        }
+    }
+
+    fn check_bucket(&self, key: &str) -> Decision {
+        let now = Instant::now();
+        let capacity = self.limit.burst as f64;
+        let mut buckets = self.buckets.lock();
+        let Bucket { tokens, updated } = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let rate = self.limit.requests as f64 / self.limit.period.as_secs_f64();
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(capacity);
+        *updated = now;
+        let allowed = *tokens >= 1.0;
+        let retry_after = if allowed {
+            *tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - *tokens) / rate))
+        };
+        Decision {
+            allowed,
+            limit: self.limit.burst,
+            remaining: *tokens as usize,
+            reset: Duration::from_secs_f64((capacity - *tokens) / rate),
+            retry_after,
+        }
+    }
+
+    /// Counts the request first and takes it back if that puts the client over the limit, since
+    /// with a shared store that is the only way to check and count atomically
+    async fn check_window(&self, key: &str) -> (Decision, u64) {
+        let period = self.limit.period;
+        let elapsed = rate_limit_store::unix_time() / period.as_secs_f64();
+        let window = elapsed as u64;
+        // How far we are into the current fixed window, from 0 to 1
+        let progress = elapsed.fract();
+        let key = format!("{}{}", self.namespace, key);
+        let counts = self.store.add(&key, window, period, 1).await;
+
+        let limit = self.limit.requests as f64;
+        let estimate = |counts: WindowCounts| {
+            counts.previous as f64 * (1.0 - progress) + counts.current as f64
+        };
+        let allowed = estimate(counts) <= limit;
+        let (counts, retry_after) = if allowed {
+            (counts, None)
+        } else {
+            let counts = self.store.add(&key, window, period, -1).await;
+            let (current, previous) = (counts.current as f64, counts.previous as f64);
+            // Find when the previous window's weight will have dropped enough to let one more
+            // request in, possibly not until the window after this one
+            let room = limit - 1.0;
+            let wait = if current <= room && previous > 0.0 {
+                (1.0 - (room - current) / previous) - progress
+            } else {
+                (1.0 - progress) + (1.0 - room / current)
+            };
+            (counts, Some(period.mul_f64(wait.max(0.0))))
+        };
+        // The count drops to zero once both windows with requests in them are out of the picture
+        let reset = if counts.current > 0 {
+            2.0 - progress
+        } else if counts.previous > 0 {
+            1.0 - progress
+        } else {
+            0.0
+        };
+        let decision = Decision {
+            allowed,
+            limit: self.limit.requests,
+            remaining: (limit - estimate(counts)).max(0.0) as usize,
+            reset: period.mul_f64(reset),
+            retry_after,
+        };
+        (decision, window)
+    }
+
+    /// Gives back a request that was allowed by check (and counted in the given window, if any),
+    /// for when a request that was within this limit was rejected by another one
+    pub async fn refund(&self, key: &str, window: Option<u64>) {
+        match window {
+            Some(window) => {
+                let key = format!("{}{}", self.namespace, key);
+                self.store.add(&key, window, self.limit.period, -1).await;
+            }
+            None => {
+                if let Some(bucket) = self.buckets.lock().get_mut(key) {
+                    bucket.tokens = (bucket.tokens + 1.0).min(self.limit.burst as f64);
+                }
+            }
+        }
+    }
+
+    /// Forgets about token buckets that are full again, so that clients that have gone away don't
+    /// take up memory forever. Window counts are evicted by the store.
+    pub fn evict_idle(&self) {
+        let now = Instant::now();
+        let rate = self.limit.requests as f64 / self.limit.period.as_secs_f64();
+        self.buckets.lock().retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate
+                < self.limit.burst as f64
+        });
+    }
+}
+
+/// A rate limit rule along with the counts it has been keeping
+struct Rule {
+    config: RateLimitRule,
+    header: Option<http::HeaderName>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Rule {
+    /// Returns the key a request is counted under, or None if the rule doesn't apply to it
+    fn key(&self, request: &http::Request<Vec<u8>>, client_ip: &str) -> Option<String> {
+        if let Some(prefix) = &self.config.path_prefix {
+            if !request.uri().path().starts_with(prefix.as_str()) {
+                return None;
+            }
+        }
+        match self.config.key {
+            Key::ClientIp => Some(client_ip.to_string()),
+            Key::ForwardedFor => Some(
+                forwarded_for(request, self.config.trusted_hops.unwrap_or(1))
+                    .unwrap_or_else(|| client_ip.to_string()),
+            ),
+            Key::Header => request
+                .headers()
+                .get(self.header.as_ref()?)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned()),
+            Key::Global => Some(String::new()),
+        }
+    }
+}
+
+/// Returns the address that the outermost of trusted_hops trusted proxies says the request came
+/// from. Each proxy appends the address it got the request from to X-Forwarded-For, so entries
+/// further from the end than that could have been sent by the client itself.
+fn forwarded_for(request: &http::Request<Vec<u8>>, trusted_hops: usize) -> Option<String> {
+    let entries: Vec<String> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|entry| entry.trim().to_string())
+        .collect();
+    let idx = entries.len().checked_sub(trusted_hops)?;
+    Some(entries[idx].clone()).filter(|entry| !entry.is_empty())
+}
+
+/// All of the rate limit rules. A request is let through only if every rule that applies to it
+/// lets it through.
+pub struct RateLimits {
+    rules: Vec<Rule>,
+    store: Arc<dyn Store>,
+}
+
+impl RateLimits {
+    /// Sets up the rules in a config. Rules that are unchanged from previous keep their counts,
+    /// and the store is carried over from previous as is. Fails if the store can't be set up.
+    pub fn new(config: &RateLimitConfig, previous: Option<&RateLimits>) -> io::Result<RateLimits> {
+        let store = match previous {
+            Some(previous) => previous.store.clone(),
+            None => rate_limit_store::new_store(&config.store)?,
+        };
+        let rules = config
+            .all_rules()
+            .into_iter()
+            .filter_map(|rule_config| {
+                let limit = rule_config.limit()?;
+                let limiter = previous
+                    .and_then(|previous| {
+                        previous
+                            .rules
+                            .iter()
+                            .find(|rule| rule.config == rule_config)
+                    })
+                    .map_or_else(
+                        || {
+                            Arc::new(RateLimiter::new(
+                                limit,
+                                store.clone(),
+                                namespace(&rule_config, &limit),
+                            ))
+                        },
+                        |rule| rule.limiter.clone(),
+                    );
+                Some(Rule {
+                    header: rule_config
+                        .header
+                        .as_ref()
+                        .and_then(|header| http::HeaderName::from_bytes(header.as_bytes()).ok()),
+                    config: rule_config,
+                    limiter,
+                })
+            })
+            .collect();
+        Ok(RateLimits { rules, store })
+    }
+
+    /// Checks a request against every rule that applies to it, counting it if it is allowed.
+    /// Returns the decision of the rule that rejected it, or else the decision of the rule that
+    /// leaves the client the fewest remaining requests, or None if no rules apply.
+    pub async fn check(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        client_ip: &str,
+    ) -> Option<Decision> {
+        let mut counted: Vec<(&Rule, String, Option<u64>)> = Vec::new();
+        let mut tightest: Option<Decision> = None;
+        for rule in &self.rules {
+            let Some(key) = rule.key(request, client_ip) else {
+                continue;
+            };
+            let (decision, window) = rule.limiter.check(&key).await;
+            if !decision.allowed {
+                // Don't count the request against the rules that did allow it
+                for (rule, key, window) in counted {
+                    rule.limiter.refund(&key, window).await;
+                }
+                return Some(decision);
+            }
+            if tightest.is_none_or(|tightest| decision.remaining < tightest.remaining) {
+                tightest = Some(decision);
+            }
+            counted.push((rule, key, window));
+        }
+        tightest
+    }
+
+    /// Forgets about clients that are back to their full allowance
+    pub fn evict_idle(&self) {
+        for rule in &self.rules {
+            rule.limiter.evict_idle();
+        }
+        self.store.evict_expired();
+    }
+}
+
+/// Returns the prefix for a rule's keys in the store. This is made up of everything that decides
+/// which requests are counted together, so that other instances sharing the store count the same
+/// requests under the same keys even if their rules are in a different order.
+fn namespace(rule: &RateLimitRule, limit: &Limit) -> String {
+    format!(
+        "{:?}:{}:{}:{}:{}:",
+        rule.key,
+        rule.header.as_deref().unwrap_or(""),
+        rule.trusted_hops.unwrap_or(1),
+        rule.path_prefix.as_deref().unwrap_or(""),
+        limit.period.as_secs()
+    )
+}
@@ -0,0 +1,498 @@
+use crate::config::RateLimitStoreConfig;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+/// Largest gossip message to send, small enough to not be fragmented on most networks. A single
+/// count that doesn't fit is sent on its own anyway.
+const MAX_GOSSIP_MESSAGE: usize = 1400;
+
+/// How many requests with a key were counted in a fixed window and in the window before it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WindowCounts {
+    pub current: usize,
+    pub previous: usize,
+}
+
+/// Keeps the per-window request counts that the sliding window rate limit algorithm works from.
+/// Windows are numbered from the Unix epoch, so that every instance of balancebeam sharing a store
+/// agrees on them.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Adds delta (which is negative when giving back a request) to the count for a key in the
+    /// given window, which is period long. Returns the counts for that window and the one before it
+    /// afterwards, including requests counted by other instances.
+    async fn add(&self, key: &str, window: u64, period: Duration, delta: i64) -> WindowCounts;
+
+    /// Forgets about keys that have no requests in the current or previous window
+    fn evict_expired(&self) {}
+}
+
+/// Creates the store for a config. Fails if the store needs a socket that can't be bound, or peers
+/// that can't be resolved.
+pub fn new_store(config: &RateLimitStoreConfig) -> io::Result<Arc<dyn Store>> {
+    Ok(match config {
+        RateLimitStoreConfig::Local => Arc::new(MemoryStore::default()),
+        RateLimitStoreConfig::Redis {
+            address,
+            password,
+            timeout_ms,
+        } => Arc::new(RedisStore::new(
+            address.clone(),
+            password.clone(),
+            Duration::from_millis(*timeout_ms),
+        )),
+        RateLimitStoreConfig::Gossip {
+            bind,
+            peers,
+            interval_ms,
+        } => Arc::new(GossipStore::new(
+            bind,
+            peers,
+            Duration::from_millis(*interval_ms),
+        )?),
+    })
+}
+
+/// Returns the current time as seconds since the Unix epoch
+pub fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn apply_delta(count: usize, delta: i64) -> usize {
+    if delta < 0 {
+        count.saturating_sub(delta.unsigned_abs() as usize)
+    } else {
+        count.saturating_add(delta as usize)
+    }
+}
+
+/// The counts for a key in the latest window it was counted in
+#[derive(Clone, Copy, Debug)]
+struct Counts {
+    window: u64,
+    current: usize,
+    previous: usize,
+    period: Duration,
+}
+
+impl Counts {
+    /// Returns the counts as seen from the given window
+    fn at(&self, window: u64) -> WindowCounts {
+        let (current, previous) = if window == self.window {
+            (self.current, self.previous)
+        } else if window == self.window + 1 {
+            (0, self.current)
+        } else if window + 1 == self.window {
+            (self.previous, 0)
+        } else {
+            (0, 0)
+        };
+        WindowCounts { current, previous }
+    }
+
+    /// Returns whether both windows with requests in them are over
+    fn expired(&self, now: f64) -> bool {
+        (self.window + 2) as f64 * self.period.as_secs_f64() <= now
+    }
+}
+
+/// Keeps counts in memory, for use by this instance only
+#[derive(Default)]
+pub struct MemoryStore {
+    counts: Mutex<HashMap<String, Counts>>,
+}
+
+impl MemoryStore {
+    fn add_now(&self, key: &str, window: u64, period: Duration, delta: i64) -> WindowCounts {
+        let mut counts = self.counts.lock();
+        let entry = counts.entry(key.to_string()).or_insert(Counts {
+            window,
+            current: 0,
+            previous: 0,
+            period,
+        });
+        if window > entry.window {
+            let moved = entry.at(window);
+            entry.window = window;
+            entry.current = moved.current;
+            entry.previous = moved.previous;
+        }
+        if window == entry.window {
+            entry.current = apply_delta(entry.current, delta);
+        } else if window + 1 == entry.window {
+            entry.previous = apply_delta(entry.previous, delta);
+        }
+        entry.at(window)
+    }
+
+    fn get(&self, key: &str) -> Option<Counts> {
+        self.counts.lock().get(key).copied()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn add(&self, key: &str, window: u64, period: Duration, delta: i64) -> WindowCounts {
+        self.add_now(key, window, period, delta)
+    }
+
+    fn evict_expired(&self) {
+        let now = unix_time();
+        self.counts.lock().retain(|_, counts| !counts.expired(now));
+    }
+}
+
+/// Keeps counts in a Redis server, as one key per window that expires once the window no longer
+/// matters. If the server can't be reached in time, requests are counted in memory instead, so
+/// that an outage of the store neither takes down the proxy nor turns off rate limiting.
+pub struct RedisStore {
+    address: String,
+    password: Option<String>,
+    timeout: Duration,
+    /// Connections to the server that aren't in use
+    idle_connections: Mutex<Vec<BufStream<TcpStream>>>,
+    fallback: MemoryStore,
+    /// Whether the last request to the server failed, so that an outage is only logged once
+    unreachable: AtomicBool,
+}
+
+/// A reply from a Redis server
+enum Reply {
+    Status,
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl RedisStore {
+    pub fn new(address: String, password: Option<String>, timeout: Duration) -> RedisStore {
+        RedisStore {
+            address,
+            password,
+            timeout,
+            idle_connections: Mutex::new(Vec::new()),
+            fallback: MemoryStore::default(),
+            unreachable: AtomicBool::new(false),
+        }
+    }
+
+    async fn connect(&self) -> io::Result<BufStream<TcpStream>> {
+        let mut conn = BufStream::new(TcpStream::connect(&self.address).await?);
+        if let Some(password) = &self.password {
+            write_command(&mut conn, &["AUTH", password]).await?;
+            conn.flush().await?;
+            read_reply(&mut conn).await?;
+        }
+        Ok(conn)
+    }
+
+    async fn try_add(
+        &self,
+        key: &str,
+        window: u64,
+        period: Duration,
+        delta: i64,
+    ) -> io::Result<WindowCounts> {
+        let idle_connection = self.idle_connections.lock().pop();
+        let mut conn = match idle_connection {
+            Some(conn) => conn,
+            None => self.connect().await?,
+        };
+        let current_key = format!("balancebeam:{}:{}", key, window);
+        let previous_key = format!("balancebeam:{}:{}", key, window.wrapping_sub(1));
+        // The count for a window is needed until the end of the window after it
+        let ttl = (period.as_millis() * 2).to_string();
+        write_command(&mut conn, &["INCRBY", &current_key, &delta.to_string()]).await?;
+        write_command(&mut conn, &["PEXPIRE", &current_key, &ttl]).await?;
+        write_command(&mut conn, &["GET", &previous_key]).await?;
+        conn.flush().await?;
+        let Reply::Integer(current) = read_reply(&mut conn).await? else {
+            return Err(protocol_error("INCRBY did not return an integer"));
+        };
+        read_reply(&mut conn).await?;
+        let previous = match read_reply(&mut conn).await? {
+            Reply::Bulk(Some(value)) => String::from_utf8_lossy(&value)
+                .parse::<i64>()
+                .map_err(|_| protocol_error("count is not an integer"))?,
+            Reply::Bulk(None) => 0,
+            _ => return Err(protocol_error("GET did not return a string")),
+        };
+        self.idle_connections.lock().push(conn);
+        Ok(WindowCounts {
+            current: current.max(0) as usize,
+            previous: previous.max(0) as usize,
+        })
+    }
+}
+
+#[async_trait]
+impl Store for RedisStore {
+    async fn add(&self, key: &str, window: u64, period: Duration, delta: i64) -> WindowCounts {
+        let result = tokio::time::timeout(self.timeout, self.try_add(key, window, period, delta))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+        match result {
+            Ok(counts) => {
+                if self.unreachable.swap(false, Ordering::Relaxed) {
+                    log::info!("Rate limit store {} is reachable again", self.address);
+                }
+                counts
+            }
+            Err(err) => {
+                if !self.unreachable.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "Rate limit store {} is unreachable, counting requests locally: {}",
+                        self.address,
+                        err
+                    );
+                }
+                self.fallback.add_now(key, window, period, delta)
+            }
+        }
+    }
+
+    fn evict_expired(&self) {
+        self.fallback.evict_expired();
+    }
+}
+
+/// Writes a command in the Redis protocol (an array of bulk strings)
+async fn write_command(conn: &mut BufStream<TcpStream>, args: &[&str]) -> io::Result<()> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    conn.write_all(command.as_bytes()).await
+}
+
+/// Reads a single (non-array) reply in the Redis protocol
+async fn read_reply(conn: &mut BufStream<TcpStream>) -> io::Result<Reply> {
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    let line = line.trim_end();
+    let (kind, value) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Reply::Status),
+        "-" => Err(io::Error::other(format!("server error: {}", value))),
+        ":" => value
+            .parse()
+            .map(Reply::Integer)
+            .map_err(|_| protocol_error("invalid integer reply")),
+        "$" => {
+            let len: i64 = value
+                .parse()
+                .map_err(|_| protocol_error("invalid bulk string length"))?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut data = vec![0; len as usize + 2];
+            conn.read_exact(&mut data).await?;
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => Err(protocol_error("unexpected reply")),
+    }
+}
+
+/// The counts for a key sent from one instance to its peers
+#[derive(Serialize, Deserialize)]
+struct GossipEntry {
+    key: String,
+    window: u64,
+    current: usize,
+    previous: usize,
+    period_ms: u64,
+}
+
+/// State shared between a GossipStore and its background tasks
+struct GossipState {
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+    local: MemoryStore,
+    /// Keys whose local counts have changed since they were last sent to the peers
+    changed: Mutex<HashSet<String>>,
+    /// The latest counts received from each peer
+    peer_counts: Mutex<HashMap<SocketAddr, HashMap<String, Counts>>>,
+}
+
+/// Counts requests locally, and sends the counts that have changed to every peer on an interval.
+/// The counts for a key are the local ones plus the latest ones heard from each peer. Each message
+/// carries whole counts rather than increments, so a lost message is made up for by the next one.
+pub struct GossipStore {
+    state: Arc<GossipState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl GossipStore {
+    pub fn new(bind: &str, peers: &[String], interval: Duration) -> io::Result<GossipStore> {
+        let socket = std::net::UdpSocket::bind(bind).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Could not bind gossip socket to {}: {}", bind, err),
+            )
+        })?;
+        socket.set_nonblocking(true)?;
+        let mut peer_addresses = Vec::new();
+        for peer in peers {
+            let addresses = peer.to_socket_addrs().map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("Could not resolve gossip peer {}: {}", peer, err),
+                )
+            })?;
+            peer_addresses.extend(addresses);
+        }
+        let state = Arc::new(GossipState {
+            socket: UdpSocket::from_std(socket)?,
+            peers: peer_addresses,
+            local: MemoryStore::default(),
+            changed: Mutex::new(HashSet::new()),
+            peer_counts: Mutex::new(HashMap::new()),
+        });
+        log::info!(
+            "Sharing rate limit counts with {} peer(s) from {}",
+            state.peers.len(),
+            bind
+        );
+        let tasks = vec![
+            tokio::spawn(send_gossip(state.clone(), interval)),
+            tokio::spawn(receive_gossip(state.clone())),
+        ];
+        Ok(GossipStore { state, tasks })
+    }
+}
+
+impl Drop for GossipStore {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Store for GossipStore {
+    async fn add(&self, key: &str, window: u64, period: Duration, delta: i64) -> WindowCounts {
+        let mut counts = self.state.local.add_now(key, window, period, delta);
+        self.state.changed.lock().insert(key.to_string());
+        for peer_counts in self.state.peer_counts.lock().values() {
+            if let Some(peer) = peer_counts.get(key) {
+                let peer = peer.at(window);
+                counts.current += peer.current;
+                counts.previous += peer.previous;
+            }
+        }
+        counts
+    }
+
+    fn evict_expired(&self) {
+        self.state.local.evict_expired();
+        let now = unix_time();
+        let mut peer_counts = self.state.peer_counts.lock();
+        for counts in peer_counts.values_mut() {
+            counts.retain(|_, counts| !counts.expired(now));
+        }
+        peer_counts.retain(|_, counts| !counts.is_empty());
+    }
+}
+
+/// Sends the counts that have changed to every peer, once per interval
+async fn send_gossip(state: Arc<GossipState>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let changed = std::mem::take(&mut *state.changed.lock());
+        // Build messages out of JSON arrays of entries, each as big as they can be
+        let mut messages: Vec<Vec<u8>> = Vec::new();
+        let mut message = Vec::new();
+        for key in changed {
+            let Some(counts) = state.local.get(&key) else {
+                continue;
+            };
+            let entry = serde_json::to_vec(&GossipEntry {
+                key,
+                window: counts.window,
+                current: counts.current,
+                previous: counts.previous,
+                period_ms: counts.period.as_millis() as u64,
+            })
+            .unwrap();
+            if !message.is_empty() && message.len() + entry.len() + 2 > MAX_GOSSIP_MESSAGE {
+                message.push(b']');
+                messages.push(std::mem::take(&mut message));
+            }
+            message.push(if message.is_empty() { b'[' } else { b',' });
+            message.extend(entry);
+        }
+        if !message.is_empty() {
+            message.push(b']');
+            messages.push(message);
+        }
+        for message in &messages {
+            for peer in &state.peers {
+                if let Err(err) = state.socket.send_to(message, peer).await {
+                    log::debug!("Failed to send rate limit counts to {}: {}", peer, err);
+                }
+            }
+        }
+    }
+}
+
+/// Records the counts sent by peers
+async fn receive_gossip(state: Arc<GossipState>) {
+    let mut buf = vec![0; 65536];
+    loop {
+        let (len, from) = match state.socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                log::debug!("Failed to receive rate limit counts: {}", err);
+                continue;
+            }
+        };
+        if !state.peers.contains(&from) {
+            log::debug!("Ignoring rate limit counts from unknown peer {}", from);
+            continue;
+        }
+        let entries: Vec<GossipEntry> = match serde_json::from_slice(&buf[..len]) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::debug!("Invalid rate limit counts from {}: {}", from, err);
+                continue;
+            }
+        };
+        let mut peer_counts = state.peer_counts.lock();
+        let peer_counts = peer_counts.entry(from).or_default();
+        for entry in entries {
+            let counts = Counts {
+                window: entry.window,
+                current: entry.current,
+                previous: entry.previous,
+                period: Duration::from_millis(entry.period_ms),
+            };
+            // Messages can arrive out of order, so don't let older counts replace newer ones
+            match peer_counts.get(&entry.key) {
+                Some(existing) if existing.window > counts.window => {}
+                _ => {
+                    peer_counts.insert(entry.key, counts);
+                }
+            }
+        }
+    }
+}
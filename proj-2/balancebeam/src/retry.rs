@@ -0,0 +1,69 @@
+use crate::body::BodyLength;
+use crate::config::{RetryConfig, StatusRange};
+use std::time::Duration;
+
+/// Decides which requests are retried on another upstream, built from the (validated) [retry]
+/// config
+pub struct RetryPolicy {
+    /// Maximum number of times a request is retried
+    pub max_retries: usize,
+    backoff: Duration,
+    retry_on: Vec<StatusRange>,
+    safe_headers: Vec<http::HeaderName>,
+    max_body_size: usize,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: config.max_retries,
+            backoff: Duration::from_millis(config.backoff_ms),
+            retry_on: config.retry_on.clone(),
+            safe_headers: config
+                .safe_headers
+                .iter()
+                .map(|name| {
+                    http::HeaderName::from_bytes(name.as_bytes())
+                        .expect("Retry safe header should have been validated")
+                })
+                .collect(),
+            max_body_size: config.max_body_size,
+        }
+    }
+
+    /// Returns whether a request may be retried: it has to be safe to send twice, and its body (if
+    /// any) small enough to keep in memory until the response arrives
+    pub fn is_retryable(&self, request: &http::Request<Vec<u8>>, body: BodyLength) -> bool {
+        if self.max_retries == 0 {
+            return false;
+        }
+        let safe = matches!(
+            *request.method(),
+            http::Method::GET
+                | http::Method::HEAD
+                | http::Method::PUT
+                | http::Method::DELETE
+                | http::Method::OPTIONS
+                | http::Method::TRACE
+        ) || self
+            .safe_headers
+            .iter()
+            .any(|name| request.headers().contains_key(name));
+        safe && match body {
+            BodyLength::Empty => true,
+            BodyLength::Fixed(length) => length <= self.max_body_size,
+            BodyLength::UntilClose | BodyLength::Chunked => false,
+        }
+    }
+
+    /// Returns whether an upstream response with this status should be retried
+    pub fn retries_status(&self, status: http::StatusCode) -> bool {
+        self.retry_on.iter().any(|range| range.contains(status))
+    }
+
+    /// Returns how long to wait before the given retry of a request (1 for the first retry)
+    pub fn backoff(&self, retry: usize) -> Duration {
+        self.backoff
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+    }
+}
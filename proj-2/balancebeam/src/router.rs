@@ -0,0 +1,170 @@
+use crate::config::{RouteConfig, UpstreamConfig, DEFAULT_POOL};
+use crate::headers;
+use regex::Regex;
+
+/// A route, built from its (validated) config
+struct Route {
+    pool: String,
+    /// Lowercase host, or the domain after "*." for a wildcard
+    host: Option<String>,
+    wildcard: bool,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    methods: Vec<http::Method>,
+    headers: Vec<(http::HeaderName, String)>,
+    strip_prefix: bool,
+    rewrite_path: Option<String>,
+}
+
+impl Route {
+    fn new(config: &RouteConfig) -> Route {
+        let host = config.host.as_ref().map(|host| host.to_ascii_lowercase());
+        let wildcard = host.as_ref().is_some_and(|host| host.starts_with("*."));
+        Route {
+            pool: config.pool.clone(),
+            host: host.map(|host| host.trim_start_matches("*.").to_string()),
+            wildcard,
+            path_prefix: config.path_prefix.clone(),
+            path_regex: config.path_regex.as_ref().map(|path_regex| {
+                Regex::new(path_regex).expect("Route path_regex should have been validated")
+            }),
+            methods: config
+                .methods
+                .iter()
+                .map(|method| {
+                    http::Method::from_bytes(method.as_bytes())
+                        .expect("Route method should have been validated")
+                })
+                .collect(),
+            headers: config
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        http::HeaderName::from_bytes(name.as_bytes())
+                            .expect("Route header should have been validated"),
+                        value.clone(),
+                    )
+                })
+                .collect(),
+            strip_prefix: config.strip_prefix,
+            rewrite_path: config.rewrite_path.clone(),
+        }
+    }
+
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        if let Some(host) = &self.host {
+            let Some(request_host) = headers::request_hostname(request) else {
+                return false;
+            };
+            let matched = if self.wildcard {
+                request_host
+                    .strip_suffix(host.as_str())
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+            } else {
+                request_host == *host
+            };
+            if !matched {
+                return false;
+            }
+        }
+        let path = request.uri().path();
+        if self
+            .path_prefix
+            .as_ref()
+            .is_some_and(|prefix| !path.starts_with(prefix.as_str()))
+        {
+            return false;
+        }
+        if self
+            .path_regex
+            .as_ref()
+            .is_some_and(|path_regex| !path_regex.is_match(path))
+        {
+            return false;
+        }
+        if !self.methods.is_empty() && !self.methods.contains(request.method()) {
+            return false;
+        }
+        self.headers.iter().all(|(name, value)| {
+            request
+                .headers()
+                .get_all(name)
+                .iter()
+                .any(|actual| actual.as_bytes() == value.as_bytes())
+        })
+    }
+
+    /// Returns the path a matching request should be forwarded with, if the route changes it
+    fn rewritten_path(&self, path: &str) -> Option<String> {
+        let mut new_path = path.to_string();
+        if self.strip_prefix {
+            let prefix = self.path_prefix.as_ref()?;
+            new_path = new_path[prefix.len()..].to_string();
+        }
+        if let (Some(path_regex), Some(rewrite_path)) = (&self.path_regex, &self.rewrite_path) {
+            new_path = path_regex
+                .replace(&new_path, rewrite_path.as_str())
+                .into_owned();
+        }
+        if !new_path.starts_with('/') {
+            new_path.insert(0, '/');
+        }
+        (new_path != path).then_some(new_path)
+    }
+}
+
+/// Replaces the path of a request, keeping its query string (and its scheme and authority, if the
+/// request target is in absolute form)
+fn set_path(request: &mut http::Request<Vec<u8>>, path: &str) {
+    let mut parts = request.uri().clone().into_parts();
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    match path_and_query.parse() {
+        Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+        Err(_) => {
+            log::warn!("Not rewriting path to invalid {}", path_and_query);
+            return;
+        }
+    }
+    if let Ok(uri) = http::Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+}
+
+/// Decides which pool of upstreams each request goes to
+pub struct Router {
+    routes: Vec<Route>,
+    /// Whether any upstreams are in the default pool
+    has_default_pool: bool,
+}
+
+impl Router {
+    pub fn new(routes: &[RouteConfig], upstreams: &[UpstreamConfig]) -> Router {
+        Router {
+            routes: routes.iter().map(Route::new).collect(),
+            has_default_pool: upstreams
+                .iter()
+                .any(|upstream| upstream.pool == DEFAULT_POOL),
+        }
+    }
+
+    /// Returns the pool of the first route that matches a request, rewriting the request's path
+    /// if the route says to, or the default pool if no route matches. Returns None if no route
+    /// matches and there is no default pool.
+    pub fn route(&self, request: &mut http::Request<Vec<u8>>) -> Option<&str> {
+        match self.routes.iter().find(|route| route.matches(request)) {
+            Some(route) => {
+                if let Some(path) = route.rewritten_path(request.uri().path()) {
+                    log::debug!("Rewriting path {} to {}", request.uri().path(), path);
+                    set_path(request, &path);
+                }
+                Some(&route.pool)
+            }
+            None if self.has_default_pool => Some(DEFAULT_POOL),
+            None => None,
+        }
+    }
+}
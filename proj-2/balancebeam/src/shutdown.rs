@@ -0,0 +1,78 @@
+use crate::timeouts;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Coordinates a graceful shutdown. Once it has started, the listeners stop accepting connections,
+/// keep-alive connections are closed once their current request is done, and main waits for the
+/// connections that are still being handled to finish before exiting.
+pub struct Shutdown {
+    /// Whether the shutdown has started
+    started: watch::Sender<bool>,
+    /// Number of client connections being handled
+    connections: watch::Sender<usize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            started: watch::Sender::new(false),
+            connections: watch::Sender::new(0),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn start(&self) {
+        self.started.send_replace(true);
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Waits until the shutdown has started
+    pub async fn started(&self) {
+        let mut started = self.started.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let _ = started.wait_for(|started| *started).await;
+    }
+
+    /// Returns the number of client connections being handled
+    pub fn connections(&self) -> usize {
+        *self.connections.borrow()
+    }
+
+    /// Records a new client connection. The connection is counted until the returned guard is
+    /// dropped.
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections
+            .send_modify(|connections| *connections += 1);
+        ConnectionGuard {
+            shutdown: self.clone(),
+        }
+    }
+
+    /// Waits for every client connection to finish, for at most the given time (or forever, if
+    /// None). Returns whether they all did.
+    pub async fn drained(&self, timeout: Option<Duration>) -> bool {
+        let mut connections = self.connections.subscribe();
+        let drained = async move {
+            let _ = connections.wait_for(|connections| *connections == 0).await;
+        };
+        timeouts::run(timeout, drained).await.is_some()
+    }
+}
+
+/// Stops counting a client connection when dropped
+pub struct ConnectionGuard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.shutdown
+            .connections
+            .send_modify(|connections| *connections -= 1);
+    }
+}
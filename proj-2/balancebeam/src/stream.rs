@@ -0,0 +1,138 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Size of each read from the underlying stream
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Wraps a connection so that bytes read past the end of one message (e.g. the start of a body, or
+/// the next pipelined request) aren't lost. The request and response parsers read into the buffer
+/// until they have a full set of headers and consume only those bytes; anything left over is
+/// returned first by subsequent reads.
+///
+/// Reads can be given a timeout, so that a peer that stops sending partway through a message
+/// can't hold the connection forever.
+pub struct HttpStream<S> {
+    inner: S,
+    /// Bytes that have been read from inner but not consumed yet
+    buffer: Vec<u8>,
+    /// How long a read may wait for data before failing with TimedOut
+    read_timeout: Option<Duration>,
+    /// When the read that is currently waiting for data times out
+    read_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> HttpStream<S> {
+    pub fn new(inner: S) -> HttpStream<S> {
+        HttpStream {
+            inner,
+            buffer: Vec::new(),
+            read_timeout: None,
+            read_deadline: None,
+        }
+    }
+
+    /// Makes reads that wait longer than this for data fail with TimedOut (or never, if None)
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+        self.read_deadline = None;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the bytes that have been read but not consumed yet
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Marks the first n buffered bytes as consumed
+    pub fn consume(&mut self, n: usize) {
+        self.buffer.drain(..n);
+    }
+
+    /// Puts bytes back in front of the buffered ones, so that they are read next
+    pub fn unread(&mut self, bytes: &[u8]) {
+        self.buffer.splice(..0, bytes.iter().copied());
+    }
+}
+
+impl<S: AsyncRead + Unpin> HttpStream<S> {
+    /// Reads more bytes from the underlying stream and appends them to the buffer. Returns the
+    /// number of bytes read, which is 0 if the other side has closed the connection.
+    pub async fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0_u8; READ_CHUNK_SIZE];
+        let bytes_read = std::future::poll_fn(|cx| {
+            let mut buf = ReadBuf::new(&mut chunk);
+            ready!(self.poll_read_inner(cx, &mut buf))?;
+            Poll::Ready(Ok::<_, io::Error>(buf.filled().len()))
+        })
+        .await?;
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    /// Reads from the underlying stream, failing with TimedOut if no data arrives in time
+    fn poll_read_inner(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Poll::Ready(result) = Pin::new(&mut self.inner).poll_read(cx, buf) {
+            self.read_deadline = None;
+            return Poll::Ready(result);
+        }
+        let Some(timeout) = self.read_timeout else {
+            return Poll::Pending;
+        };
+        let deadline = self
+            .read_deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        ready!(deadline.as_mut().poll(cx));
+        self.read_deadline = None;
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no data received for {}ms", timeout.as_millis()),
+        )))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HttpStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.buffer.is_empty() {
+            return self.poll_read_inner(cx, buf);
+        }
+        // Hand out buffered bytes before reading anything new
+        let n = std::cmp::min(buf.remaining(), self.buffer.len());
+        buf.put_slice(&self.buffer[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HttpStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,51 @@
+use crate::config::TimeoutConfig;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The timeouts from the [timeouts] config, with None for no limit
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub header_read: Option<Duration>,
+    pub body_read: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub request: Option<Duration>,
+    pub shutdown: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new(config: &TimeoutConfig) -> Timeouts {
+        let millis = |ms| (ms > 0).then(|| Duration::from_millis(ms));
+        Timeouts {
+            connect: millis(config.connect_ms),
+            header_read: millis(config.header_read_ms),
+            body_read: millis(config.body_read_ms),
+            idle: millis(config.idle_ms),
+            request: millis(config.request_ms),
+            shutdown: millis(config.shutdown_ms),
+        }
+    }
+
+    /// Returns when a request whose headers have just been received has to be done by
+    pub fn request_deadline(&self) -> Option<Instant> {
+        self.request.map(|timeout| Instant::now() + timeout)
+    }
+}
+
+/// Runs a future for at most the given time (or to completion, if None). Returns None if it
+/// didn't finish in time.
+pub async fn run<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Runs a future until the given deadline (or to completion, if None). Returns None if it didn't
+/// finish in time.
+pub async fn run_until<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
@@ -0,0 +1,214 @@
+use crate::config::{CertificateConfig, Mode, TlsConfig, UpstreamTlsConfig};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Debug)]
+pub enum Error {
+    /// A certificate or key file could not be read
+    Io(PathBuf, std::io::Error),
+    /// A certificate file doesn't contain any PEM certificates
+    NoCertificates(PathBuf),
+    /// A key file doesn't contain a PEM private key
+    NoPrivateKey(PathBuf),
+    /// A CA bundle contains a certificate that can't be used as a trust anchor
+    InvalidCertificate(PathBuf, rustls::Error),
+    /// A private key is of a type that can't be used for TLS
+    InvalidKey(PathBuf, rustls::Error),
+    /// The TLS server config could not be built
+    Rustls(rustls::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            Error::NoCertificates(path) => {
+                write!(f, "no PEM certificates found in {}", path.display())
+            }
+            Error::NoPrivateKey(path) => {
+                write!(f, "no PEM private key found in {}", path.display())
+            }
+            Error::InvalidCertificate(path, err) => {
+                write!(f, "unusable certificate in {}: {}", path.display(), err)
+            }
+            Error::InvalidKey(path, err) => {
+                write!(f, "unusable private key in {}: {}", path.display(), err)
+            }
+            Error::Rustls(err) => write!(f, "could not set up TLS: {}", err),
+        }
+    }
+}
+
+/// Loads the certificates and keys named in the config from disk and builds an acceptor that
+/// performs the server side of TLS handshakes with them. This is called again whenever the config
+/// is reloaded, which is how renewed certificates get picked up. HTTP/2 and HTTP/1.1 are offered
+/// over ALPN in http mode; in tcp mode the bytes inside TLS aren't ours to interpret, so no protocol
+/// is offered.
+pub fn acceptor(config: &TlsConfig, mode: Mode) -> Result<TlsAcceptor, Error> {
+    let certificates = config
+        .certificates
+        .iter()
+        .map(load_certificate)
+        .collect::<Result<Vec<_>, _>>()?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(Error::Rustls)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(CertificateResolver { certificates }));
+    if mode == Mode::Http {
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Builds the connector used for TLS handshakes with https:// upstreams. Upstream certificates are
+/// checked against the configured CA bundle, or the Mozilla root certificates if there isn't one,
+/// unless verification has been turned off.
+pub fn connector(config: &UpstreamTlsConfig) -> Result<TlsConnector, Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(Error::Rustls)?;
+    let mut client_config = if config.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        match &config.ca_bundle {
+            Some(path) => {
+                for cert in load_certs(path)? {
+                    roots
+                        .add(cert)
+                        .map_err(|err| Error::InvalidCertificate(path.clone(), err))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// Reads all of the certificates in a PEM file, which must contain at least one
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = rustls_pemfile::certs(&mut read_file(path)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::Io(path.to_path_buf(), err))?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+/// Reads a PEM certificate chain and the matching private key
+fn load_certificate(config: &CertificateConfig) -> Result<Arc<CertifiedKey>, Error> {
+    let cert_chain = load_certs(&config.cert)?;
+    let key = rustls_pemfile::private_key(&mut read_file(&config.key)?.as_slice())
+        .map_err(|err| Error::Io(config.key.clone(), err))?
+        .ok_or_else(|| Error::NoPrivateKey(config.key.clone()))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|err| Error::InvalidKey(config.key.clone(), err))?;
+    Ok(Arc::new(CertifiedKey::new(cert_chain, signing_key)))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    std::fs::read(path).map_err(|err| Error::Io(path.to_path_buf(), err))
+}
+
+/// Picks which certificate to present based on the server name the client asked for (SNI). The
+/// first certificate that is valid for the name is used; if the client didn't send a name, or none
+/// of the certificates are valid for it, the first certificate is used.
+#[derive(Debug)]
+struct CertificateResolver {
+    certificates: Vec<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello
+            .server_name()
+            .and_then(|name| ServerName::try_from(name).ok());
+        server_name
+            .and_then(|server_name| {
+                self.certificates.iter().find(|certificate| {
+                    certificate
+                        .end_entity_cert()
+                        .is_ok_and(|cert| is_valid_for(cert, &server_name))
+                })
+            })
+            .or_else(|| self.certificates.first())
+            .cloned()
+    }
+}
+
+/// Returns whether a certificate covers the given server name (including through wildcards)
+fn is_valid_for(cert: &CertificateDer, server_name: &ServerName) -> bool {
+    webpki::EndEntityCert::try_from(cert)
+        .and_then(|cert| cert.verify_is_valid_for_subject_name(server_name))
+        .is_ok()
+}
+
+/// Accepts any certificate an upstream presents, for testing against upstreams with self-signed
+/// certificates. Handshake signatures are still checked, so the upstream does need to have the
+/// private key for whatever certificate it sends.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,83 @@
+use crate::stream::HttpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the buffer used for each direction of a tunnel
+const TUNNEL_BUFFER_SIZE: usize = 16384;
+
+/// How many bytes went each way through a tunnel
+#[derive(Debug, Default)]
+pub struct Transferred {
+    pub client_to_upstream: u64,
+    pub upstream_to_client: u64,
+}
+
+/// Copies bytes in both directions between a client and an upstream whose connection has switched
+/// to another protocol (after a 101 Switching Protocols, or a successful CONNECT), until both
+/// sides have closed their end. Bytes already buffered on either side are passed on first. When
+/// one side closes its end, the other side's end is shut down for writing, so half-closed
+/// connections work.
+///
+/// Fails with TimedOut if nothing is sent either way for idle_timeout (if given).
+pub async fn run<C, U>(
+    client: &mut HttpStream<C>,
+    upstream: &mut HttpStream<U>,
+    idle_timeout: Option<Duration>,
+) -> Result<Transferred, std::io::Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    // The tunnel has its own idle timeout, covering both directions at once
+    client.set_read_timeout(None);
+    upstream.set_read_timeout(None);
+    let mut transferred = Transferred::default();
+    let mut client_buffer = vec![0_u8; TUNNEL_BUFFER_SIZE];
+    let mut upstream_buffer = vec![0_u8; TUNNEL_BUFFER_SIZE];
+    let mut client_open = true;
+    let mut upstream_open = true;
+    while client_open || upstream_open {
+        let idle = async {
+            match idle_timeout {
+                Some(idle_timeout) => tokio::time::sleep(idle_timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        // Reads are cancel-safe, so whichever side doesn't win the race loses nothing
+        tokio::select! {
+            result = client.read(&mut client_buffer), if client_open => {
+                let bytes_read = result?;
+                if bytes_read == 0 {
+                    client_open = false;
+                    // The other side may already be gone, which makes no difference here
+                    let _ = upstream.shutdown().await;
+                } else {
+                    upstream.write_all(&client_buffer[..bytes_read]).await?;
+                    upstream.flush().await?;
+                    transferred.client_to_upstream += bytes_read as u64;
+                }
+            }
+            result = upstream.read(&mut upstream_buffer), if upstream_open => {
+                let bytes_read = result?;
+                if bytes_read == 0 {
+                    upstream_open = false;
+                    let _ = client.shutdown().await;
+                } else {
+                    client.write_all(&upstream_buffer[..bytes_read]).await?;
+                    client.flush().await?;
+                    transferred.upstream_to_client += bytes_read as u64;
+                }
+            }
+            _ = idle => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "nothing sent either way for {}ms",
+                        idle_timeout.unwrap_or_default().as_millis()
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(transferred)
+}
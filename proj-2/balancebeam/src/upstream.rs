@@ -0,0 +1,98 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+/// A connection to an upstream. Upstreams whose address starts with https:// are connected to
+/// over TLS; all others (with or without an http:// prefix) over plain TCP.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl UpstreamStream {
+    /// Returns the TCP connection underneath any encryption
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            UpstreamStream::Plain(stream) => stream,
+            UpstreamStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+}
+
+/// Returns the host and port of an upstream address, without the scheme, e.g. for use in a Host
+/// header
+pub fn authority(address: &str) -> &str {
+    address
+        .strip_prefix("https://")
+        .or_else(|| address.strip_prefix("http://"))
+        .unwrap_or(address)
+        .trim_end_matches('/')
+}
+
+/// Opens a connection to an upstream, performing a TLS handshake if its address starts with
+/// https:// (in which case the port defaults to 443)
+pub async fn connect(address: &str, tls_connector: &TlsConnector) -> io::Result<UpstreamStream> {
+    if !address.starts_with("https://") {
+        return Ok(UpstreamStream::Plain(
+            TcpStream::connect(authority(address)).await?,
+        ));
+    }
+    let authority: http::uri::Authority = authority(address)
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // IPv6 hosts are written in brackets, but neither connect nor rustls expect them
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let stream = TcpStream::connect((host, authority.port_u16().unwrap_or(443))).await?;
+    let stream = tls_connector.connect(server_name, stream).await?;
+    Ok(UpstreamStream::Tls(Box::new(stream)))
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -271,7 +271,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
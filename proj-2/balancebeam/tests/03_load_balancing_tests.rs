@@ -0,0 +1,162 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+
+async fn setup_with_args(
+    n_upstreams: usize,
+    extra_args: &[&str],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address())
+        .collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_addresses, extra_args).await;
+    (balancebeam, upstreams)
+}
+
+/// Sends n_requests requests to balancebeam, each on a new connection, and makes sure they all
+/// succeed
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+}
+
+/// Stops all the upstreams and returns the number of requests each one received, in the order the
+/// upstreams were passed to balancebeam
+async fn stop_upstreams(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
+fn assert_evenly_distributed(request_counters: &[usize]) {
+    let avg_req_count =
+        request_counters.iter().sum::<usize>() as f64 / request_counters.len() as f64;
+    for &upstream_req_count in request_counters {
+        assert!(
+            (upstream_req_count as f64 - avg_req_count).abs() <= 0.4 * avg_req_count,
+            "Upstream request count {} differs too much from the average {}",
+            upstream_req_count,
+            avg_req_count
+        );
+    }
+}
+
+/// Round robin should hand out sequential connections to each upstream in turn
+#[tokio::test]
+async fn test_round_robin() {
+    let (balancebeam, upstreams) = setup_with_args(3, &["--strategy", "round-robin"]).await;
+    send_requests(&balancebeam, 30).await;
+    assert_eq!(stop_upstreams(upstreams).await, vec![10, 10, 10]);
+    log::info!("All done :)");
+}
+
+/// Weighted round robin should send traffic in proportion to the configured weights
+#[tokio::test]
+async fn test_weighted_round_robin() {
+    let (balancebeam, upstreams) = setup_with_args(
+        2,
+        &[
+            "--strategy",
+            "weighted-round-robin",
+            "--weight",
+            "3",
+            "--weight",
+            "1",
+        ],
+    )
+    .await;
+    send_requests(&balancebeam, 40).await;
+    assert_eq!(stop_upstreams(upstreams).await, vec![30, 10]);
+    log::info!("All done :)");
+}
+
+/// Least connections and power of two choices should both spread sequential connections across
+/// the upstreams
+#[tokio::test]
+async fn test_connection_aware_strategies_distribute_load() {
+    for strategy in ["least-connections", "power-of-two-choices"] {
+        log::info!("Testing {}", strategy);
+        let (balancebeam, upstreams) = setup_with_args(3, &["--strategy", strategy]).await;
+        send_requests(&balancebeam, 60).await;
+        assert_evenly_distributed(&stop_upstreams(upstreams).await);
+    }
+    log::info!("All done :)");
+}
+
+/// Consistent hashing on the client IP should send every connection from the same client to the
+/// same upstream
+#[tokio::test]
+async fn test_consistent_hash() {
+    let (balancebeam, upstreams) = setup_with_args(3, &["--strategy", "consistent-hash"]).await;
+    send_requests(&balancebeam, 20).await;
+    let mut request_counters = stop_upstreams(upstreams).await;
+    request_counters.sort_unstable();
+    assert_eq!(request_counters, vec![0, 0, 20]);
+    log::info!("All done :)");
+}
+
+/// When the upstream a client hashes to goes down, the client should be moved to another upstream
+#[tokio::test]
+async fn test_consistent_hash_failover() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--strategy",
+            "consistent-hash",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    send_requests(&balancebeam, 5).await;
+
+    // Take the upstream that served the client out of rotation through the admin API, leaving it
+    // running so that no port has to be bound again
+    let served_idx = upstreams
+        .iter()
+        .position(|upstream| upstream.requests_received() == 5)
+        .expect("Requests were spread across upstreams");
+    let status = reqwest::Client::new()
+        .post(format!(
+            "http://{}/upstreams/{}/disable",
+            admin_address, upstreams[served_idx].address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .status();
+    assert_eq!(status, reqwest::StatusCode::OK);
+
+    send_requests(&balancebeam, 5).await;
+    let [first, second] = upstreams;
+    let mut request_counters = vec![Box::new(first).stop().await, Box::new(second).stop().await];
+    assert_eq!(request_counters.remove(served_idx), 5);
+    assert_eq!(request_counters, vec![5]);
+    log::info!("All done :)");
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+fn toml_config(upstream: &str) -> String {
+    format!(
+        "strategy = \"round-robin\"\n\n[[upstreams]]\naddress = \"{}\"\n\n\
+        [health_check]\ninterval = 60\n",
+        upstream
+    )
+}
+
+async fn get_ok(balancebeam: &BalanceBeam, path: &str) {
+    let response_text = balancebeam
+        .get(path)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+}
+
+/// Start balancebeam from a TOML config file, then change the file to point at a different upstream
+/// and make sure new requests go there
+#[tokio::test]
+async fn test_toml_config_reloads_on_change() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let config = ConfigFile::new("toml", &toml_config(&first.address));
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+
+    for i in 0..3 {
+        get_ok(&balancebeam, &format!("/before-reload-{}", i)).await;
+    }
+
+    log::info!("Pointing the config file at the second upstream");
+    config.write(&toml_config(&second.address));
+    sleep(Duration::from_secs(3)).await;
+
+    for i in 0..3 {
+        get_ok(&balancebeam, &format!("/after-reload-{}", i)).await;
+    }
+
+    assert_eq!(Box::new(first).stop().await, 3);
+    assert_eq!(Box::new(second).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// YAML config files should work the same way, and reloading should be triggered by SIGHUP
+#[tokio::test]
+async fn test_yaml_config_reloads_on_sighup() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let yaml_config = |upstream: &str| format!("upstreams:\n  - address: \"{}\"\n", upstream);
+    let config = ConfigFile::new("yaml", &yaml_config(&first.address));
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+
+    get_ok(&balancebeam, "/before-reload").await;
+
+    log::info!("Pointing the config file at the second upstream and sending SIGHUP");
+    config.write(&yaml_config(&second.address));
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+
+    get_ok(&balancebeam, "/after-reload").await;
+
+    assert_eq!(Box::new(first).stop().await, 1);
+    assert_eq!(Box::new(second).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A connection that was open before a reload should keep working (and keep using the upstream it
+/// was already using), while new connections use the new config
+#[tokio::test]
+async fn test_reload_keeps_existing_connections() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let config = ConfigFile::new("toml", &toml_config(&first.address));
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+
+    // reqwest keeps the connection open between requests made with the same client
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/keep-alive", balancebeam.address);
+    client.get(&url).send().await.unwrap().text().await.unwrap();
+
+    config.write(&toml_config(&second.address));
+    sleep(Duration::from_secs(3)).await;
+
+    let response_text = client
+        .get(&url)
+        .send()
+        .await
+        .expect("Connection opened before the reload was dropped")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /keep-alive HTTP/1.1"));
+    get_ok(&balancebeam, "/new-connection").await;
+    drop(client);
+
+    assert_eq!(Box::new(first).stop().await, 2);
+    assert_eq!(Box::new(second).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// An invalid config file should be ignored, leaving the previous config in effect
+#[tokio::test]
+async fn test_invalid_config_is_ignored() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config = ConfigFile::new("toml", &toml_config(&upstream.address));
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+
+    config.write("upstreams = \"not a list\"\n");
+    sleep(Duration::from_secs(3)).await;
+
+    get_ok(&balancebeam, "/still-working").await;
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
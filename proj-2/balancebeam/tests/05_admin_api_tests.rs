@@ -0,0 +1,483 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts balancebeam with the admin API enabled, and returns it along with the admin address
+async fn setup(upstreams: &[&str]) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        upstreams,
+        &["--strategy", "round-robin", "--admin-bind", &admin_address],
+    )
+    .await;
+    (balancebeam, admin_address)
+}
+
+/// Sends a request to the admin API and returns the status code and parsed JSON body
+async fn admin_request(
+    method: reqwest::Method,
+    admin_address: &str,
+    path: &str,
+    body: Option<&str>,
+) -> (u16, Value) {
+    let client = reqwest::Client::new();
+    let mut request = client.request(method, format!("http://{}{}", admin_address, path));
+    if let Some(body) = body {
+        request = request.body(body.to_string());
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    let status = response.status().as_u16();
+    let text = response.text().await.unwrap();
+    let json = serde_json::from_str(&text)
+        .unwrap_or_else(|_| panic!("Admin API returned invalid JSON: {}", text));
+    (status, json)
+}
+
+fn upstream_entry<'a>(listing: &'a Value, address: &str) -> &'a Value {
+    listing["upstreams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|upstream| upstream["address"] == address)
+        .unwrap_or_else(|| panic!("{} missing from listing {}", address, listing))
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Starts an upstream that answers each request with "first-half", sending the second half of the
+/// body a second after the first
+async fn slow_body_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read_u8().await {
+                        Ok(byte) => request.push(byte),
+                        Err(_) => return,
+                    }
+                }
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nfirst")
+                    .await;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let _ = stream.write_all(b"-half").await;
+            });
+        }
+    });
+    address
+}
+
+/// Starts a request for a slow response, changes the upstream's status with the given admin action
+/// once the first half of the body has arrived, and returns the body the client ends up with
+async fn body_after_action(action: &str) -> String {
+    let upstream = slow_body_upstream().await;
+    let (balancebeam, admin_address) = setup(&[&upstream]).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    while !response.ends_with(b"first") {
+        response.push(stream.read_u8().await.unwrap());
+    }
+
+    let (status, _) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        &format!("/upstreams/{}/{}", upstream, action),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("The client connection should have been closed")
+        .unwrap();
+    let response = String::from_utf8(response).unwrap();
+    response.split_once("\r\n\r\n").unwrap().1.to_string()
+}
+
+/// The listing should include every upstream along with its health and admin status
+#[tokio::test]
+async fn test_list_upstreams() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (_balancebeam, admin_address) = setup(&[&first.address, &second.address]).await;
+
+    let (status, listing) =
+        admin_request(reqwest::Method::GET, &admin_address, "/upstreams", None).await;
+    assert_eq!(status, 200);
+    for upstream in [&first.address, &second.address] {
+        let entry = upstream_entry(&listing, upstream);
+        assert_eq!(entry["live"], true);
+        assert_eq!(entry["status"], "enabled");
+    }
+    assert_eq!(listing["live_upstreams"].as_array().unwrap().len(), 2);
+
+    let (status, _) =
+        admin_request(reqwest::Method::GET, &admin_address, "/no-such-thing", None).await;
+    assert_eq!(status, 404);
+    log::info!("All done :)");
+}
+
+/// Upstreams added at runtime should be put in the rotation, and removed ones taken out of it
+#[tokio::test]
+async fn test_add_and_remove_upstreams() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&first.address]).await;
+
+    log::info!("Adding the second upstream");
+    let body = format!("{{\"address\": \"{}\"}}", second.address);
+    let (status, listing) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        "/upstreams",
+        Some(&body),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(upstream_entry(&listing, &second.address)["live"], true);
+    let (status, _) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        "/upstreams",
+        Some(&body),
+    )
+    .await;
+    assert_eq!(status, 409, "Adding the same upstream twice should fail");
+
+    send_requests(&balancebeam, 4).await;
+
+    log::info!("Removing the first upstream");
+    let (status, listing) = admin_request(
+        reqwest::Method::DELETE,
+        &admin_address,
+        &format!("/upstreams/{}", first.address),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(listing["upstreams"].as_array().unwrap().len(), 1);
+
+    send_requests(&balancebeam, 4).await;
+
+    assert_eq!(Box::new(first).stop().await, 2);
+    assert_eq!(Box::new(second).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// A drained upstream should get no new requests, even from clients that sent it requests before
+/// it was drained
+#[tokio::test]
+async fn test_drain_upstream() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&first.address, &second.address]).await;
+
+    // With round robin, the first request goes to the first upstream
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/keep-alive", balancebeam.address);
+    client.get(&url).send().await.unwrap().text().await.unwrap();
+
+    let (status, listing) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        &format!("/upstreams/{}/drain", first.address),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    let entry = upstream_entry(&listing, &first.address);
+    assert_eq!(entry["status"], "draining");
+    assert_eq!(entry["live"], false);
+    assert_eq!(entry["active_connections"], 0);
+    assert_eq!(entry["idle_connections"], 0);
+
+    send_requests(&balancebeam, 4).await;
+    client.get(&url).send().await.unwrap().text().await.unwrap();
+    drop(client);
+
+    assert_eq!(Box::new(first).stop().await, 1);
+    assert_eq!(Box::new(second).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Requests from a client that was talking to a disabled upstream should go to another upstream
+#[tokio::test]
+async fn test_disable_upstream() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&first.address, &second.address]).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/keep-alive", balancebeam.address);
+    client.get(&url).send().await.unwrap().text().await.unwrap();
+
+    let (status, _) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        &format!("/upstreams/{}/disable", first.address),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let response_text = client.get(&url).send().await.unwrap().text().await.unwrap();
+    assert!(response_text.contains("GET /keep-alive HTTP/1.1"));
+    send_requests(&balancebeam, 2).await;
+
+    log::info!("Re-enabling the first upstream");
+    let (status, listing) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        &format!("/upstreams/{}/enable", first.address),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(upstream_entry(&listing, &first.address)["live"], true);
+    drop(client);
+
+    assert_eq!(Box::new(first).stop().await, 1);
+    assert_eq!(Box::new(second).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// The /metrics endpoint should report request counts, latencies, upstream health and rate
+/// limiting in the Prometheus text format
+#[tokio::test]
+async fn test_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--max-requests-per-minute",
+            "3",
+        ],
+    )
+    .await;
+
+    for i in 0..5 {
+        let client = reqwest::Client::new();
+        client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let response = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let metrics = response.text().await.unwrap();
+    log::info!("Metrics:\n{}", metrics);
+    for expected_line in [
+        "balancebeam_responses_total{status=\"200\"} 3".to_string(),
+        "balancebeam_responses_total{status=\"429\"} 2".to_string(),
+        "balancebeam_rate_limited_total 2".to_string(),
+        format!(
+            "balancebeam_upstream_requests_total{{upstream=\"{}\",status=\"200\"}} 3",
+            upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_latency_seconds_count{{upstream=\"{}\"}} 3",
+            upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} 3",
+            upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_live{{upstream=\"{}\"}} 1",
+            upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_idle_connections{{upstream=\"{}\"}} 1",
+            upstream.address
+        ),
+    ] {
+        assert!(
+            metrics.lines().any(|line| line == expected_line),
+            "Metrics are missing the line {}",
+            expected_line
+        );
+    }
+    log::info!("All done :)");
+}
+
+/// Responses that are in flight when an upstream is drained should finish, while ones from an
+/// upstream that is disabled should be cut off
+#[tokio::test]
+async fn test_disable_cuts_in_flight_requests() {
+    assert_eq!(body_after_action("drain").await, "first-half");
+    assert_eq!(body_after_action("disable").await, "first");
+    log::info!("All done :)");
+}
+
+/// Upstreams that would make the config invalid should be refused with a 400 that says why, and
+/// leave the config as it was
+#[tokio::test]
+async fn test_add_invalid_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--mode", "tcp", "--admin-bind", &admin_address],
+    )
+    .await;
+
+    // tcp mode only has the default pool
+    let (status, response) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        "/upstreams",
+        Some("{\"address\": \"127.0.0.1:1\", \"pool\": \"api\"}"),
+    )
+    .await;
+    assert_eq!(status, 400);
+    assert!(
+        response["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid config"),
+        "{}",
+        response
+    );
+
+    let (status, listing) =
+        admin_request(reqwest::Method::GET, &admin_address, "/upstreams", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(listing["upstreams"].as_array().unwrap().len(), 1);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Upstreams whose addresses have a scheme should be reachable through the API once their address
+/// is percent-encoded
+#[tokio::test]
+async fn test_https_upstream_address() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, admin_address) = setup(&[&upstream.address]).await;
+    // Nothing listens on port 1, but the upstream only needs to be configured
+    let https_upstream = "https://127.0.0.1:1";
+    let encoded = "https%3A%2F%2F127.0.0.1%3A1";
+
+    let body = format!("{{\"address\": \"{}\"}}", https_upstream);
+    let (status, _) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        "/upstreams",
+        Some(&body),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    for (action, expected_status) in [
+        ("drain", "draining"),
+        ("disable", "disabled"),
+        ("enable", "enabled"),
+    ] {
+        let (status, listing) = admin_request(
+            reqwest::Method::POST,
+            &admin_address,
+            &format!("/upstreams/{}/{}", encoded, action),
+            None,
+        )
+        .await;
+        assert_eq!(status, 200, "{} failed: {}", action, listing);
+        assert_eq!(
+            upstream_entry(&listing, https_upstream)["status"],
+            expected_status
+        );
+    }
+
+    let (status, listing) = admin_request(
+        reqwest::Method::DELETE,
+        &admin_address,
+        &format!("/upstreams/{}", encoded),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(listing["upstreams"].as_array().unwrap().len(), 1);
+
+    let (status, _) = admin_request(
+        reqwest::Method::DELETE,
+        &admin_address,
+        "/upstreams/https%3A%2F%2F127.0.0.1%3",
+        None,
+    )
+    .await;
+    assert_eq!(status, 400, "Malformed escapes should be refused");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Changes made at the same time should all take effect, rather than each starting from the same
+/// config and the last one to finish undoing the others
+#[tokio::test]
+async fn test_concurrent_changes() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, admin_address) = setup(&[&upstream.address]).await;
+
+    let added: Vec<String> = (1..=8).map(|port| format!("127.0.0.1:{}", port)).collect();
+    let mut requests = tokio::task::JoinSet::new();
+    for address in &added {
+        let admin_address = admin_address.clone();
+        let body = format!("{{\"address\": \"{}\"}}", address);
+        requests.spawn(async move {
+            admin_request(
+                reqwest::Method::POST,
+                &admin_address,
+                "/upstreams",
+                Some(&body),
+            )
+            .await
+            .0
+        });
+    }
+    while let Some(status) = requests.join_next().await {
+        assert_eq!(status.unwrap(), 201);
+    }
+
+    let (_, listing) =
+        admin_request(reqwest::Method::GET, &admin_address, "/upstreams", None).await;
+    for address in &added {
+        upstream_entry(&listing, address);
+    }
+    assert_eq!(listing["upstreams"].as_array().unwrap().len(), 9);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn setup(extra_args: &[&str]) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], extra_args).await;
+    (balancebeam, upstream)
+}
+
+/// Bodies bigger than balancebeam would ever hold in memory at once should make it through intact
+/// in both directions
+#[tokio::test]
+async fn test_large_bodies() {
+    let (balancebeam, upstream) = setup(&[]).await;
+
+    let body: Vec<u8> = (0..20_000_000_u32).map(|i| (i % 251) as u8).collect();
+    log::info!("Sending a {} byte POST request", body.len());
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("http://{}/upload", balancebeam.address))
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let response_body = response.bytes().await.unwrap();
+    assert!(response_body.starts_with(b"POST /upload HTTP/1.1"));
+    assert!(
+        response_body.ends_with(&body),
+        "The echoed body doesn't match what was sent"
+    );
+    drop(client);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --max-request-body-size, requests with bigger bodies should be rejected without being
+/// forwarded
+#[tokio::test]
+async fn test_max_request_body_size() {
+    let (balancebeam, upstream) = setup(&["--max-request-body-size", "100"]).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/upload", balancebeam.address);
+    let response = client
+        .post(&url)
+        .body(vec![b'a'; 1000])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 413);
+    let response = client
+        .post(&url)
+        .body(vec![b'a'; 100])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    drop(client);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A client may send its next request before it has read the response to the previous one. Bytes
+/// of the next request that arrive along with the current one shouldn't be lost.
+#[tokio::test]
+async fn test_pipelined_requests() {
+    let (balancebeam, upstream) = setup(&[]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(
+        b"POST /first HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\n\r\nhello\
+          GET /second HTTP/1.1\r\nHost: test\r\n\r\n",
+    )
+    .await
+    .unwrap();
+
+    let mut received = String::new();
+    let mut buffer = [0_u8; 4096];
+    while !received.contains("GET /second HTTP/1.1") {
+        let bytes_read =
+            tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                .await
+                .expect("Timed out waiting for responses")
+                .unwrap();
+        assert_ne!(bytes_read, 0, "Connection closed early: {}", received);
+        received += &String::from_utf8_lossy(&buffer[..bytes_read]);
+    }
+    assert_eq!(received.matches("HTTP/1.1 200 OK").count(), 2);
+    assert!(received.contains("POST /first HTTP/1.1"));
+    assert!(received.contains("\n\nhello"));
+    drop(conn);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use common::{expected_body, init_logging, BalanceBeam, ChunkedServer, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends raw bytes to balancebeam on a new connection and returns everything it sends back until
+/// received contains until
+async fn raw_exchange(balancebeam: &BalanceBeam, request: &[u8], until: &str) -> String {
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(request).await.unwrap();
+    let mut received = String::new();
+    let mut buffer = [0_u8; 4096];
+    while !received.contains(until) {
+        let bytes_read = tokio::time::timeout(Duration::from_secs(5), conn.read(&mut buffer))
+            .await
+            .unwrap_or_else(|_| panic!("Timed out waiting for a response: {:?}", received))
+            .unwrap();
+        if bytes_read == 0 {
+            break;
+        }
+        received += &String::from_utf8_lossy(&buffer[..bytes_read]);
+    }
+    received
+}
+
+/// Chunked responses should reach the client intact, and the connection should stay usable for
+/// further requests afterwards
+#[tokio::test]
+async fn test_chunked_response() {
+    init_logging();
+    let upstream = ChunkedServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let client = reqwest::Client::new();
+    for path in ["/stream-1", "/stream-2", "/stream-3"] {
+        log::info!("Requesting {}", path);
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert_eq!(response_text, expected_body(path));
+    }
+    drop(client);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// The response should still be chunked when it gets to the client, with the chunk extensions
+/// dropped and the trailer fields passed along
+#[tokio::test]
+async fn test_chunked_response_reencoded() {
+    init_logging();
+    let upstream = ChunkedServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let received = raw_exchange(
+        &balancebeam,
+        b"GET /raw HTTP/1.1\r\nHost: test\r\n\r\n",
+        "\r\n0\r\nx-trailer: done\r\n\r\n",
+    )
+    .await;
+    log::info!("Received:\n{}", received);
+    assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(received.contains("transfer-encoding: chunked\r\n"));
+    assert!(
+        !received.contains("index="),
+        "Chunk extensions were forwarded"
+    );
+    assert!(received.contains("10\r\nchunk 0 of /raw\n\r\n"));
+    assert!(received.ends_with("\r\n0\r\nx-trailer: done\r\n\r\n"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Chunked request bodies should be forwarded to the upstream, and requests after them on the same
+/// connection should still be read correctly
+#[tokio::test]
+async fn test_chunked_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let received = raw_exchange(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+          6;name=value\r\nhello \r\n5\r\nworld\r\n0\r\nx-checksum: abc\r\n\r\n\
+          GET /after HTTP/1.1\r\nHost: test\r\n\r\n",
+        "GET /after HTTP/1.1",
+    )
+    .await;
+    log::info!("Received:\n{}", received);
+    assert!(received.contains("POST /upload HTTP/1.1"));
+    assert!(received.contains("transfer-encoding: chunked"));
+    assert!(received.contains("\n\nhello world"));
+    assert!(received.contains("GET /after HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Requests with malformed chunked bodies, or with a Transfer-Encoding we can't find the end of,
+/// should be rejected
+#[tokio::test]
+async fn test_bad_chunked_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let received = raw_exchange(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: gzip\r\n\r\nxyz",
+        "\r\n\r\n",
+    )
+    .await;
+    assert!(received.starts_with("HTTP/1.1 400 Bad Request"));
+
+    let received = raw_exchange(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+          zz\r\nhello\r\n0\r\n\r\n",
+        "\r\n\r\n",
+    )
+    .await;
+    assert!(received.starts_with("HTTP/1.1 400 Bad Request"));
+
+    log::info!("All done :)");
+}
+
+/// --max-request-body-size should apply to the decoded size of chunked bodies
+#[tokio::test]
+async fn test_chunked_request_too_large() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-request-body-size", "8"]).await;
+
+    let received = raw_exchange(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n",
+        "\r\n\r\n",
+    )
+    .await;
+    assert!(received.starts_with("HTTP/1.1 413 Payload Too Large"));
+
+    let received = raw_exchange(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\nhello\r\n0\r\n\r\n",
+        "\n\nhello",
+    )
+    .await;
+    assert!(received.starts_with("HTTP/1.1 200 OK"));
+
+    log::info!("All done :)");
+}
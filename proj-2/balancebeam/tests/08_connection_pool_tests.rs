@@ -0,0 +1,125 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts balancebeam with round robin and the admin API enabled, and returns it along with the
+/// admin address
+async fn setup(upstreams: &[&str], extra_args: &[&str]) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = free_address();
+    let mut args = vec!["--strategy", "round-robin", "--admin-bind", &admin_address];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(upstreams, &args).await;
+    (balancebeam, admin_address)
+}
+
+/// Returns the number of idle pooled connections to the given upstream, according to the admin API
+async fn idle_connections(admin_address: &str, upstream: &str) -> u64 {
+    let text = reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    let listing: Value = serde_json::from_str(&text).unwrap();
+    listing["upstreams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["address"] == upstream)
+        .unwrap_or_else(|| panic!("{} missing from listing {}", upstream, listing))
+        ["idle_connections"]
+        .as_u64()
+        .unwrap()
+}
+
+/// Sends n_requests on a single keep-alive client connection
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Requests sent over one client connection should each be balanced on their own, rather than all
+/// going to the upstream the connection started out with
+#[tokio::test]
+async fn test_requests_spread_across_upstreams() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (balancebeam, _) = setup(&[&first.address, &second.address], &[]).await;
+
+    send_requests(&balancebeam, 10).await;
+
+    assert_eq!(Box::new(first).stop().await, 5);
+    assert_eq!(Box::new(second).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Sequential requests, even from different clients, should reuse one upstream connection
+#[tokio::test]
+async fn test_upstream_connections_reused() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&upstream.address], &[]).await;
+
+    for _ in 0..3 {
+        send_requests(&balancebeam, 3).await;
+    }
+    assert_eq!(idle_connections(&admin_address, &upstream.address).await, 1);
+
+    assert_eq!(Box::new(upstream).stop().await, 9);
+    log::info!("All done :)");
+}
+
+/// Idle connections should be closed once they time out, and not pooled at all if pooling is off
+#[tokio::test]
+async fn test_idle_connection_limits() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) =
+        setup(&[&upstream.address], &["--idle-connection-timeout", "1"]).await;
+    send_requests(&balancebeam, 2).await;
+    assert_eq!(idle_connections(&admin_address, &upstream.address).await, 1);
+    sleep(Duration::from_millis(2500)).await;
+    assert_eq!(idle_connections(&admin_address, &upstream.address).await, 0);
+    send_requests(&balancebeam, 1).await;
+
+    let (balancebeam, admin_address) =
+        setup(&[&upstream.address], &["--max-idle-connections", "0"]).await;
+    send_requests(&balancebeam, 2).await;
+    assert_eq!(idle_connections(&admin_address, &upstream.address).await, 0);
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// If an upstream closes a pooled connection while it is idle, the next request should be sent
+/// over a fresh connection instead
+#[tokio::test]
+async fn test_closed_idle_connection_not_reused() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&upstream.address], &[]).await;
+    send_requests(&balancebeam, 1).await;
+    assert_eq!(idle_connections(&admin_address, &upstream.address).await, 1);
+
+    log::info!("Restarting the upstream, which closes its idle connections");
+    let address = upstream.address.clone();
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let upstream = EchoServer::new_at_address(address).await;
+    sleep(Duration::from_millis(500)).await;
+
+    send_requests(&balancebeam, 2).await;
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server, TestCertificate};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends a GET request over TLS, asking for the given server name, with a client that trusts only
+/// the given certificate. Returns the response body.
+async fn get_tls(
+    balancebeam: &BalanceBeam,
+    certificate: &TestCertificate,
+    server_name: &str,
+    path: &str,
+) -> Result<String, reqwest::Error> {
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+    certificate
+        .client(server_name, &balancebeam.address)
+        .get(format!("https://{}:{}{}", server_name, port, path))
+        .send()
+        .await?
+        .text()
+        .await
+}
+
+/// Requests sent over TLS should be decrypted and forwarded to the upstream as usual, and plaintext
+/// requests should no longer be accepted
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certificate = TestCertificate::new(&["localhost"]);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            certificate.cert_path(),
+            "--tls-key",
+            certificate.key_path(),
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        let path = format!("/secure-{}", i);
+        let response_text = get_tls(&balancebeam, &certificate, "localhost", &path)
+            .await
+            .expect("Error sending request to balancebeam over TLS");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert!(
+        balancebeam.get("/plaintext").await.is_err(),
+        "Plaintext request was accepted on a TLS listener"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Each client should be given the certificate for the server name it asks for
+#[tokio::test]
+async fn test_sni_certificate_selection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let first = TestCertificate::new(&["first.test"]);
+    let second = TestCertificate::new(&["second.test", "*.second.test"]);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            first.cert_path(),
+            "--tls-key",
+            first.key_path(),
+            "--tls-cert",
+            second.cert_path(),
+            "--tls-key",
+            second.key_path(),
+        ],
+    )
+    .await;
+
+    get_tls(&balancebeam, &first, "first.test", "/first")
+        .await
+        .expect("Wrong certificate for first.test");
+    get_tls(&balancebeam, &second, "second.test", "/second")
+        .await
+        .expect("Wrong certificate for second.test");
+    get_tls(&balancebeam, &second, "www.second.test", "/wildcard")
+        .await
+        .expect("Wrong certificate for www.second.test");
+    assert!(
+        get_tls(&balancebeam, &first, "second.test", "/mismatch")
+            .await
+            .is_err(),
+        "The first certificate was presented for second.test"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Changing the certificate in the config file should take effect for new connections without a
+/// restart
+#[tokio::test]
+async fn test_certificate_reloads_on_config_change() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let old_certificate = TestCertificate::new(&["localhost"]);
+    let new_certificate = TestCertificate::new(&["localhost"]);
+    let tls_config = |certificate: &TestCertificate| {
+        format!(
+            "[[upstreams]]\naddress = \"{}\"\n\n\
+            [[tls.certificates]]\ncert = \"{}\"\nkey = \"{}\"\n",
+            upstream.address,
+            certificate.cert_path(),
+            certificate.key_path()
+        )
+    };
+    let config = ConfigFile::new("toml", &tls_config(&old_certificate));
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+
+    get_tls(
+        &balancebeam,
+        &old_certificate,
+        "localhost",
+        "/before-reload",
+    )
+    .await
+    .expect("Error sending request to balancebeam over TLS");
+
+    log::info!("Pointing the config file at the new certificate");
+    config.write(&tls_config(&new_certificate));
+    sleep(Duration::from_secs(3)).await;
+
+    get_tls(&balancebeam, &new_certificate, "localhost", "/after-reload")
+        .await
+        .expect("New certificate was not loaded");
+    assert!(
+        get_tls(
+            &balancebeam,
+            &old_certificate,
+            "localhost",
+            "/old-certificate"
+        )
+        .await
+        .is_err(),
+        "Old certificate is still being presented"
+    );
+
+    log::info!("Breaking the config file, which should keep the new certificate in use");
+    config.write(&format!(
+        "[[upstreams]]\naddress = \"{}\"\n\n\
+        [[tls.certificates]]\ncert = \"/nonexistent.crt\"\nkey = \"/nonexistent.key\"\n",
+        upstream.address
+    ));
+    sleep(Duration::from_secs(3)).await;
+    get_tls(
+        &balancebeam,
+        &new_certificate,
+        "localhost",
+        "/after-bad-reload",
+    )
+    .await
+    .expect("Bad certificate paths were loaded");
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
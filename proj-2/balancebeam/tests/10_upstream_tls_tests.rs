@@ -0,0 +1,109 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TestCertificate};
+
+/// Starts an HTTPS upstream: a balancebeam terminating TLS with the given certificate, in front of
+/// an echo server. Returns both, along with the https:// address of the upstream.
+async fn https_upstream(certificate: &TestCertificate) -> (EchoServer, BalanceBeam, String) {
+    let echo_server = EchoServer::new().await;
+    let tls_server = BalanceBeam::new_with_args(
+        &[&echo_server.address],
+        &[
+            "--tls-cert",
+            certificate.cert_path(),
+            "--tls-key",
+            certificate.key_path(),
+        ],
+    )
+    .await;
+    let port = tls_server.address.rsplit(':').next().unwrap();
+    let address = format!("https://localhost:{}", port);
+    (echo_server, tls_server, address)
+}
+
+/// Sends a request and returns its status code and response body
+async fn get(balancebeam: &BalanceBeam, client: &reqwest::Client, path: &str) -> (u16, String) {
+    let response = client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Requests should be forwarded over TLS to upstreams with https:// addresses whose certificates
+/// are signed by the CA bundle, and the encrypted connections should be reused
+#[tokio::test]
+async fn test_https_upstream_with_ca_bundle() {
+    init_logging();
+    let certificate = TestCertificate::new(&["localhost"]);
+    let (echo_server, _tls_server, address) = https_upstream(&certificate).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &["--upstream-ca-bundle", certificate.cert_path()],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let path = format!("/over-tls-{}", i);
+        let (status, response_text) = get(&balancebeam, &client, &path).await;
+        assert_eq!(status, 200);
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    drop(client);
+    // A plaintext upstream alongside it should keep working too
+    let plain_server = EchoServer::new().await;
+    let mixed = BalanceBeam::new_with_args(
+        &[&address, &plain_server.address],
+        &[
+            "--upstream-ca-bundle",
+            certificate.cert_path(),
+            "--strategy",
+            "round-robin",
+        ],
+    )
+    .await;
+    for _ in 0..4 {
+        mixed.get("/mixed").await.expect("Error sending request");
+    }
+
+    assert_eq!(Box::new(echo_server).stop().await, 5);
+    assert_eq!(Box::new(plain_server).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Upstreams whose certificates can't be verified should be treated as unreachable, unless
+/// verification is turned off
+#[tokio::test]
+async fn test_https_upstream_verification() {
+    init_logging();
+    let certificate = TestCertificate::new(&["localhost"]);
+    let other_certificate = TestCertificate::new(&["localhost"]);
+    let (echo_server, _tls_server, address) = https_upstream(&certificate).await;
+
+    log::info!("Trusting only the Mozilla roots");
+    let balancebeam = BalanceBeam::new_with_args(&[&address], &[] as &[&str]).await;
+    let (status, _) = get(&balancebeam, &reqwest::Client::new(), "/untrusted").await;
+    assert_eq!(status, 502);
+
+    log::info!("Trusting a different certificate");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &["--upstream-ca-bundle", other_certificate.cert_path()],
+    )
+    .await;
+    let (status, _) = get(&balancebeam, &reqwest::Client::new(), "/wrong-ca").await;
+    assert_eq!(status, 502);
+
+    log::info!("Turning off verification");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&address], &["--upstream-insecure-skip-verify"]).await;
+    let (status, response_text) = get(&balancebeam, &reqwest::Client::new(), "/insecure").await;
+    assert_eq!(status, 200);
+    assert!(response_text.contains("GET /insecure HTTP/1.1"));
+
+    assert_eq!(Box::new(echo_server).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,272 @@
+mod common;
+
+use common::{init_logging, with_config, BalanceBeam, ConfigFile, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends a request and returns the response
+async fn send_request(
+    balancebeam: &BalanceBeam,
+    client: &reqwest::Client,
+    path: &str,
+) -> reqwest::Response {
+    client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+fn header<'a>(response: &'a reqwest::Response, name: &str) -> &'a str {
+    response
+        .headers()
+        .get(name)
+        .unwrap_or_else(|| panic!("Response is missing {}", name))
+        .to_str()
+        .unwrap()
+}
+
+/// Sends n requests as quickly as possible and returns how many of them were let through
+async fn count_allowed(balancebeam: &BalanceBeam, client: &reqwest::Client, n: usize) -> usize {
+    let mut allowed = 0;
+    for i in 0..n {
+        if send_request(balancebeam, client, &format!("/count-{}", i))
+            .await
+            .status()
+            .is_success()
+        {
+            allowed += 1;
+        }
+    }
+    allowed
+}
+
+/// The token bucket should let a client burst up to the bucket size, then only let requests through
+/// as fast as the bucket refills
+#[tokio::test]
+async fn test_token_bucket() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-second",
+            "2",
+            "--rate-limit-algorithm",
+            "token-bucket",
+            "--rate-limit-burst",
+            "4",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    for i in 0..4 {
+        let response = send_request(&balancebeam, &client, &format!("/burst-{}", i)).await;
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(header(&response, "x-ratelimit-limit"), "4");
+        assert_eq!(
+            header(&response, "x-ratelimit-remaining"),
+            (3 - i).to_string()
+        );
+    }
+    let response = send_request(&balancebeam, &client, "/over-limit").await;
+    assert_eq!(response.status().as_u16(), 429);
+    assert_eq!(header(&response, "retry-after"), "1");
+    assert_eq!(header(&response, "x-ratelimit-remaining"), "0");
+    assert_eq!(header(&response, "x-ratelimit-reset"), "2");
+
+    log::info!("Waiting for the bucket to refill by 2 tokens");
+    sleep(Duration::from_millis(1100)).await;
+    assert_eq!(count_allowed(&balancebeam, &client, 4).await, 2);
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Once a client has used up its allowance, the sliding window should only let it send more
+/// requests as the old ones slide out of the window, rather than handing out a whole new
+/// allowance at once as a fixed window would
+#[tokio::test]
+async fn test_sliding_window() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-requests-per-second", "10"])
+            .await;
+    let client = reqwest::Client::new();
+
+    assert_eq!(count_allowed(&balancebeam, &client, 10).await, 10);
+    let response = send_request(&balancebeam, &client, "/over-limit").await;
+    assert_eq!(response.status().as_u16(), 429);
+    assert_eq!(header(&response, "x-ratelimit-limit"), "10");
+    assert_eq!(header(&response, "x-ratelimit-remaining"), "0");
+    let retry_after: u64 = header(&response, "retry-after").parse().unwrap();
+    assert!((1..=2).contains(&retry_after));
+
+    // Whichever point of the fixed window we're at, at least half of the earlier requests are
+    // still within the sliding window half a second later
+    sleep(Duration::from_millis(500)).await;
+    let allowed = count_allowed(&balancebeam, &client, 10).await;
+    log::info!("{} requests allowed half a second later", allowed);
+    assert!(allowed <= 6, "{} requests were allowed", allowed);
+
+    sleep(Duration::from_millis(2100)).await;
+    assert_eq!(count_allowed(&balancebeam, &client, 10).await, 10);
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 20 + allowed);
+    log::info!("All done :)");
+}
+
+/// The per-minute limit should be enforced over a sliding minute
+#[tokio::test]
+async fn test_per_minute_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-requests-per-minute", "3"]).await;
+    let client = reqwest::Client::new();
+
+    for remaining in ["2", "1", "0"] {
+        let response = send_request(&balancebeam, &client, "/allowed").await;
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(header(&response, "x-ratelimit-limit"), "3");
+        assert_eq!(header(&response, "x-ratelimit-remaining"), remaining);
+    }
+    let response = send_request(&balancebeam, &client, "/over-limit").await;
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = header(&response, "retry-after").parse().unwrap();
+    assert!((1..=80).contains(&retry_after));
+    let reset: u64 = header(&response, "x-ratelimit-reset").parse().unwrap();
+    assert!((60..=120).contains(&reset));
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Starts balancebeam with the given rate limit rules (in TOML) in its config file
+async fn with_rules(upstream: &EchoServer, rules: &str) -> (BalanceBeam, ConfigFile) {
+    with_config(&upstream.address, rules).await
+}
+
+/// Sends a request with the given headers and returns the status code of the response
+async fn status_with_headers(
+    balancebeam: &BalanceBeam,
+    client: &reqwest::Client,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> u16 {
+    let mut request = client.get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Behind a trusted proxy, clients should be told apart by the address the proxy put in
+/// X-Forwarded-For, and entries added by the clients themselves should be ignored
+#[tokio::test]
+async fn test_forwarded_for_rule() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancebeam, _config) = with_rules(
+        &upstream,
+        "[[rate_limit.rules]]\nkey = \"forwarded-for\"\nmax_requests_per_minute = 2\n",
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let from = |address| [("x-forwarded-for", address)];
+
+    for expected_status in [200, 200, 429] {
+        let status = status_with_headers(&balancebeam, &client, "/", &from("10.0.0.1")).await;
+        assert_eq!(status, expected_status);
+    }
+    let status = status_with_headers(&balancebeam, &client, "/", &from("10.0.0.2")).await;
+    assert_eq!(status, 200);
+    let status = status_with_headers(&balancebeam, &client, "/", &from("1.2.3.4, 10.0.0.1")).await;
+    assert_eq!(status, 429, "Spoofed X-Forwarded-For entry was trusted");
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Each API key should get its own limit, all inside a global limit shared by every request
+#[tokio::test]
+async fn test_api_key_rule_within_global_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancebeam, _config) = with_rules(
+        &upstream,
+        "[[rate_limit.rules]]\nkey = \"header\"\nheader = \"x-api-key\"\n\
+        max_requests_per_minute = 2\n\n\
+        [[rate_limit.rules]]\nkey = \"global\"\nmax_requests_per_minute = 5\n",
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let with_key = |key| [("x-api-key", key)];
+
+    for expected_status in [200, 200, 429] {
+        let status = status_with_headers(&balancebeam, &client, "/", &with_key("first")).await;
+        assert_eq!(status, expected_status);
+    }
+    for _ in 0..2 {
+        let status = status_with_headers(&balancebeam, &client, "/", &with_key("second")).await;
+        assert_eq!(status, 200);
+    }
+    log::info!("Requests without a key only count against the global limit");
+    assert_eq!(
+        status_with_headers(&balancebeam, &client, "/", &[]).await,
+        200
+    );
+    assert_eq!(
+        status_with_headers(&balancebeam, &client, "/", &[]).await,
+        429
+    );
+    let status = status_with_headers(&balancebeam, &client, "/", &with_key("third")).await;
+    assert_eq!(status, 429, "Global limit was not applied to a new key");
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Rules with a path prefix should only count requests to paths under it
+#[tokio::test]
+async fn test_path_prefix_rule() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancebeam, _config) = with_rules(
+        &upstream,
+        "[[rate_limit.rules]]\npath_prefix = \"/api/\"\nmax_requests_per_minute = 1\n",
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    assert_eq!(
+        status_with_headers(&balancebeam, &client, "/api/first", &[]).await,
+        200
+    );
+    assert_eq!(
+        status_with_headers(&balancebeam, &client, "/api/second", &[]).await,
+        429
+    );
+    for _ in 0..3 {
+        assert_eq!(
+            status_with_headers(&balancebeam, &client, "/static", &[]).await,
+            200
+        );
+    }
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
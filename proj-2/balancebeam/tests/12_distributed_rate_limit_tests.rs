@@ -0,0 +1,137 @@
+mod common;
+
+use common::{init_logging, with_config, BalanceBeam, ConfigFile, EchoServer, RedisServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts balancebeam with a per-IP limit of max_requests_per_minute and the given rate limit
+/// store config (in TOML)
+async fn with_store(
+    upstream: &EchoServer,
+    max_requests_per_minute: usize,
+    store: &str,
+) -> (BalanceBeam, ConfigFile) {
+    with_config(
+        &upstream.address,
+        &format!(
+            "[rate_limit]\nmax_requests_per_minute = {}\n\n[rate_limit.store]\n{}",
+            max_requests_per_minute, store
+        ),
+    )
+    .await
+}
+
+/// Sends a request and returns the status code of the response
+async fn status(balancebeam: &BalanceBeam, client: &reqwest::Client, path: &str) -> u16 {
+    client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Returns a local address that nothing is listening on
+fn unused_address(udp: bool) -> String {
+    if udp {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().to_string()
+    } else {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+}
+
+/// Instances sharing a Redis store should enforce one limit between them
+#[tokio::test]
+async fn test_redis_store_shared_between_instances() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let redis = RedisServer::new(Some("hunter2")).await;
+    let store = format!(
+        "type = \"redis\"\naddress = \"{}\"\npassword = \"hunter2\"\n",
+        redis.address
+    );
+    let (first, _first_config) = with_store(&upstream, 4, &store).await;
+    let (second, _second_config) = with_store(&upstream, 4, &store).await;
+    let client = reqwest::Client::new();
+
+    for balancebeam in [&first, &second, &first, &second] {
+        assert_eq!(status(balancebeam, &client, "/shared").await, 200);
+    }
+    assert_eq!(status(&first, &client, "/over-limit").await, 429);
+    assert_eq!(status(&second, &client, "/over-limit").await, 429);
+    assert!(
+        redis
+            .keys()
+            .iter()
+            .any(|key| key.starts_with("balancebeam:") && key.contains("127.0.0.1")),
+        "Counts were not stored per client: {:?}",
+        redis.keys()
+    );
+    assert!(
+        redis.keys().iter().all(|key| redis.get(key).unwrap() <= 4),
+        "Rejected requests were counted"
+    );
+
+    drop(client);
+    // Each rejected request is counted and then taken back
+    assert_eq!(Box::new(redis).stop().await, 8);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// If the Redis store can't be reached, requests should still be limited, locally
+#[tokio::test]
+async fn test_redis_store_unreachable() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let store = format!(
+        "type = \"redis\"\naddress = \"{}\"\n",
+        unused_address(false)
+    );
+    let (balancebeam, _config) = with_store(&upstream, 2, &store).await;
+    let client = reqwest::Client::new();
+
+    for expected_status in [200, 200, 429] {
+        assert_eq!(status(&balancebeam, &client, "/").await, expected_status);
+    }
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Instances gossiping with each other should soon find out about requests sent to the others
+#[tokio::test]
+async fn test_gossip_store() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let first_address = unused_address(true);
+    let second_address = unused_address(true);
+    let gossip = |bind: &str, peer: &str| {
+        format!(
+            "type = \"gossip\"\nbind = \"{}\"\npeers = [\"{}\"]\ninterval_ms = 50\n",
+            bind, peer
+        )
+    };
+    let (first, _first_config) =
+        with_store(&upstream, 4, &gossip(&first_address, &second_address)).await;
+    let (second, _second_config) =
+        with_store(&upstream, 4, &gossip(&second_address, &first_address)).await;
+    let client = reqwest::Client::new();
+
+    for _ in 0..3 {
+        assert_eq!(status(&first, &client, "/first").await, 200);
+    }
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(status(&second, &client, "/second").await, 200);
+    assert_eq!(status(&second, &client, "/over-limit").await, 429);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(status(&first, &client, "/over-limit").await, 429);
+
+    drop(client);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::{init_logging, upstreams_in_rotation, with_health_check, HealthServer, Server};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Only responses with an expected status and a body matching the pattern should count as healthy
+#[tokio::test]
+async fn test_expected_status_and_body() {
+    init_logging();
+    let healthy = HealthServer::new().await;
+    let degraded = HealthServer::new().await;
+    let redirecting = HealthServer::new().await;
+    healthy.set_health(202, "status: ok");
+    degraded.set_health(200, "status: degraded");
+    redirecting.set_health(302, "status: ok");
+    let (balancebeam, _config) = with_health_check(
+        &[(&healthy, ""), (&degraded, ""), (&redirecting, "")],
+        "expected_status = [\"2xx\", 304]\nbody = \"status: (ok|fine)\"\n",
+    )
+    .await;
+
+    degraded.wait_for_health_checks(1).await;
+    assert_eq!(
+        upstreams_in_rotation(&balancebeam).await,
+        HashSet::from([healthy.address.clone()])
+    );
+
+    log::info!("Fixing the degraded upstream");
+    degraded.set_health(200, "status: fine");
+    degraded.wait_for_health_checks(2).await;
+    assert_eq!(
+        upstreams_in_rotation(&balancebeam).await,
+        HashSet::from([healthy.address.clone(), degraded.address.clone()])
+    );
+
+    for upstream in [healthy, degraded, redirecting] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Health checks should be sent with the configured method and headers, and upstreams that take
+/// longer than the timeout should fail them. Each upstream can override the settings.
+#[tokio::test]
+async fn test_method_headers_timeout_and_overrides() {
+    init_logging();
+    let fast = HealthServer::new().await;
+    let slow = HealthServer::new().await;
+    let slow_but_expected = HealthServer::new().await;
+    for upstream in [&fast, &slow, &slow_but_expected] {
+        upstream.require_token("secret");
+    }
+    slow.set_delay(Duration::from_millis(800));
+    slow_but_expected.set_delay(Duration::from_millis(800));
+    let (balancebeam, _config) = with_health_check(
+        &[
+            (&fast, ""),
+            (&slow, ""),
+            (&slow_but_expected, "health_check = { timeout_ms = 3000 }"),
+        ],
+        "method = \"OPTIONS\"\ntimeout_ms = 300\n\
+        [health_check.headers]\nx-health-token = \"secret\"\n",
+    )
+    .await;
+
+    slow_but_expected.wait_for_health_checks(1).await;
+    assert_eq!(
+        upstreams_in_rotation(&balancebeam).await,
+        HashSet::from([fast.address.clone(), slow_but_expected.address.clone()])
+    );
+    assert!(fast
+        .health_checks()
+        .iter()
+        .all(|method| method == "OPTIONS"));
+
+    for upstream in [fast, slow, slow_but_expected] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// An upstream should only be taken out of the rotation after `fall` failed checks in a row, and
+/// only put back after `rise` passed checks in a row
+#[tokio::test]
+async fn test_rise_and_fall_thresholds() {
+    init_logging();
+    let stable = HealthServer::new().await;
+    let flaky = HealthServer::new().await;
+    let (balancebeam, _config) =
+        with_health_check(&[(&stable, ""), (&flaky, "")], "rise = 2\nfall = 3\n").await;
+    let both = HashSet::from([stable.address.clone(), flaky.address.clone()]);
+    let only_stable = HashSet::from([stable.address.clone()]);
+
+    log::info!("A single failed check shouldn't take the upstream out of the rotation");
+    let checks = flaky.health_checks().len();
+    flaky.set_health(500, "");
+    flaky.wait_for_health_checks(checks + 1).await;
+    flaky.set_health(200, "");
+    flaky.wait_for_health_checks(checks + 2).await;
+    assert_eq!(upstreams_in_rotation(&balancebeam).await, both);
+
+    log::info!("Failing three checks in a row should");
+    let checks = flaky.health_checks().len();
+    flaky.set_health(500, "");
+    flaky.wait_for_health_checks(checks + 2).await;
+    assert_eq!(upstreams_in_rotation(&balancebeam).await, both);
+    flaky.wait_for_health_checks(checks + 3).await;
+    assert_eq!(upstreams_in_rotation(&balancebeam).await, only_stable);
+
+    log::info!("It should take two passed checks to come back");
+    let checks = flaky.health_checks().len();
+    flaky.set_health(200, "");
+    flaky.wait_for_health_checks(checks + 1).await;
+    assert_eq!(upstreams_in_rotation(&balancebeam).await, only_stable);
+    flaky.wait_for_health_checks(checks + 2).await;
+    assert_eq!(upstreams_in_rotation(&balancebeam).await, both);
+
+    Box::new(stable).stop().await;
+    Box::new(flaky).stop().await;
+    log::info!("All done :)");
+}
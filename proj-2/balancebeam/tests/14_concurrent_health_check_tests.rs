@@ -0,0 +1,97 @@
+mod common;
+
+use common::{init_logging, upstreams_in_rotation, with_health_check, HealthServer, Server};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// An upstream that is slow to answer health checks shouldn't hold up the checks of the others, or
+/// the requests being forwarded while the checks are running
+#[tokio::test]
+async fn test_slow_upstream_does_not_block() {
+    init_logging();
+    let slow = HealthServer::new().await;
+    let first = HealthServer::new().await;
+    let second = HealthServer::new().await;
+    slow.set_delay(Duration::from_millis(1800));
+    let (balancebeam, _config) = with_health_check(
+        &[(&slow, ""), (&first, ""), (&second, "")],
+        "timeout_ms = 1500\n",
+    )
+    .await;
+
+    log::info!("Sending requests while the slow upstream is being checked");
+    let started = Instant::now();
+    let mut slowest = Duration::ZERO;
+    while started.elapsed() < Duration::from_secs(3) {
+        let sent = Instant::now();
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        slowest = slowest.max(sent.elapsed());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(
+        slowest < Duration::from_millis(500),
+        "A request took {:?} while health checks were running",
+        slowest
+    );
+    assert_eq!(
+        upstreams_in_rotation(&balancebeam).await,
+        HashSet::from([first.address.clone(), second.address.clone()])
+    );
+
+    log::info!("The other upstreams should still be checked");
+    let checks = first.health_checks().len();
+    first.set_health(500, "");
+    // The live upstreams are only updated once the whole round is over, slow upstream and all
+    first.wait_for_health_checks(checks + 2).await;
+    assert_eq!(
+        upstreams_in_rotation(&balancebeam).await,
+        HashSet::from([second.address.clone()])
+    );
+
+    for upstream in [slow, first, second] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Upstreams that can't be reached at all shouldn't stop the health checks: other upstreams should
+/// still be taken out of the rotation and put back as their health changes
+#[tokio::test]
+async fn test_checks_continue_after_failures() {
+    init_logging();
+    let dead = HealthServer::new().await;
+    let alive = HealthServer::new().await;
+    let (balancebeam, _config) = with_health_check(&[(&dead, ""), (&alive, "")], "").await;
+
+    log::info!("Killing an upstream");
+    Box::new(dead).stop().await;
+    let checks = alive.health_checks().len();
+    alive.wait_for_health_checks(checks + 2).await;
+    assert_eq!(
+        upstreams_in_rotation(&balancebeam).await,
+        HashSet::from([alive.address.clone()])
+    );
+
+    log::info!("Failing the other upstream too");
+    alive.set_health(503, "");
+    alive.wait_for_health_checks(checks + 3).await;
+    let status = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status();
+    assert_eq!(status.as_u16(), 502);
+
+    log::info!("Bringing it back");
+    alive.set_health(200, "");
+    alive.wait_for_health_checks(checks + 5).await;
+    assert_eq!(
+        upstreams_in_rotation(&balancebeam).await,
+        HashSet::from([alive.address.clone()])
+    );
+
+    Box::new(alive).stop().await;
+    log::info!("All done :)");
+}
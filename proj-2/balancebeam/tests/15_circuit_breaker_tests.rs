@@ -0,0 +1,193 @@
+mod common;
+
+use common::{
+    free_address, init_logging, with_upstreams, BalanceBeam, ConfigFile, HealthServer, Server,
+};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Starts balancebeam with round-robin balancing over the given upstreams, ejecting them after two
+/// failures in a row for 1s (doubling up to 4s). The active health checks are too infrequent to
+/// have any say. Returns balancebeam, its config file and the address of its admin API.
+async fn with_circuit_breaker(upstreams: &[&HealthServer]) -> (BalanceBeam, ConfigFile, String) {
+    let admin_address = free_address();
+    let upstreams: Vec<(&str, &str)> = upstreams
+        .iter()
+        .map(|upstream| (upstream.address.as_str(), ""))
+        .collect();
+    let (balancebeam, config) = with_upstreams(
+        &format!(
+            "strategy = \"round-robin\"\nadmin_listener = \"{}\"\n",
+            admin_address
+        ),
+        &upstreams,
+        "[circuit_breaker]\nconsecutive_failures = 2\nbase_ejection_time = 1\n\
+         max_ejection_time = 4\n",
+    )
+    .await;
+    (balancebeam, config, admin_address)
+}
+
+/// Sends n requests, ignoring their responses. The same client is used throughout, so that the
+/// requests go out quickly enough for the ejection timings to hold.
+async fn send_requests(client: &reqwest::Client, balancebeam: &BalanceBeam, n: usize) {
+    for _ in 0..n {
+        client
+            .get(format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+    }
+}
+
+/// Returns the circuit state of an upstream, as listed by the admin API
+async fn circuit_state(admin_address: &str, upstream: &str) -> String {
+    let listing: Value = serde_json::from_str(&admin_get(admin_address, "/upstreams").await)
+        .expect("Admin API returned invalid JSON");
+    listing["upstreams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|info| info["address"] == upstream)
+        .expect("Upstream missing from the admin API listing")["circuit"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+async fn admin_get(admin_address: &str, path: &str) -> String {
+    reqwest::get(format!("http://{}{}", admin_address, path))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap()
+}
+
+/// An upstream that keeps answering with 5xx errors should be ejected, and restored once a trial
+/// request after the ejection succeeds
+#[tokio::test]
+async fn test_eject_and_restore() {
+    init_logging();
+    let healthy = HealthServer::new().await;
+    let failing = HealthServer::new().await;
+    failing.set_status(500);
+    let (balancebeam, _config, admin_address) = with_circuit_breaker(&[&healthy, &failing]).await;
+    let client = reqwest::Client::new();
+
+    send_requests(&client, &balancebeam, 8).await;
+    assert_eq!(
+        failing.requests(),
+        2,
+        "The failing upstream should get no more requests once it has failed twice"
+    );
+    assert_eq!(
+        circuit_state(&admin_address, &failing.address).await,
+        "open",
+        "The admin API should report the failing upstream as ejected"
+    );
+    let metrics = admin_get(&admin_address, "/metrics").await;
+    assert!(
+        metrics.contains(&format!(
+            "balancebeam_upstream_ejections_total{{upstream=\"{}\"}} 1",
+            failing.address
+        )),
+        "Metrics should count the ejection: {}",
+        metrics
+    );
+
+    log::info!("Fixing the failing upstream");
+    failing.set_status(200);
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    send_requests(&client, &balancebeam, 8).await;
+    assert!(
+        failing.requests() >= 5,
+        "The upstream should be back in the rotation after passing its trial request (got {} \
+         requests)",
+        failing.requests()
+    );
+    assert_eq!(
+        circuit_state(&admin_address, &failing.address).await,
+        "closed"
+    );
+
+    for upstream in [healthy, failing] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// An upstream that fails its trial request should be ejected again, for twice as long
+#[tokio::test]
+async fn test_failed_trial_doubles_ejection() {
+    init_logging();
+    let healthy = HealthServer::new().await;
+    let failing = HealthServer::new().await;
+    failing.set_status(503);
+    let (balancebeam, _config, admin_address) = with_circuit_breaker(&[&healthy, &failing]).await;
+    let client = reqwest::Client::new();
+
+    send_requests(&client, &balancebeam, 6).await;
+    assert_eq!(failing.requests(), 2);
+
+    // The first ejection lasts 1s, after which a single trial request is let through
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    send_requests(&client, &balancebeam, 6).await;
+    assert_eq!(
+        failing.requests(),
+        3,
+        "Only one trial request should be sent after the ejection"
+    );
+
+    // The trial failed, so the second ejection lasts 2s
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    send_requests(&client, &balancebeam, 6).await;
+    assert_eq!(
+        failing.requests(),
+        3,
+        "The upstream should still be ejected after failing its trial request"
+    );
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    send_requests(&client, &balancebeam, 6).await;
+    assert_eq!(failing.requests(), 4);
+
+    let metrics = admin_get(&admin_address, "/metrics").await;
+    assert!(
+        metrics.contains(&format!(
+            "balancebeam_upstream_ejections_total{{upstream=\"{}\"}} 3",
+            failing.address
+        )),
+        "{}",
+        metrics
+    );
+
+    for upstream in [healthy, failing] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// If every upstream has been ejected, requests should still be sent to them rather than failing
+#[tokio::test]
+async fn test_all_ejected() {
+    init_logging();
+    let failing = HealthServer::new().await;
+    failing.set_status(500);
+    let (balancebeam, _config, _admin_address) = with_circuit_breaker(&[&failing]).await;
+    let client = reqwest::Client::new();
+
+    send_requests(&client, &balancebeam, 4).await;
+    assert_eq!(failing.requests(), 4);
+    failing.set_status(200);
+    let response_text = balancebeam
+        .get("/")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, format!("hello from {}", failing.address));
+
+    Box::new(failing).stop().await;
+    log::info!("All done :)");
+}
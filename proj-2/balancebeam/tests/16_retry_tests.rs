@@ -0,0 +1,240 @@
+mod common;
+
+use common::{
+    free_address, init_logging, with_upstreams, BalanceBeam, ConfigFile, EchoServer, HealthServer,
+    Server,
+};
+use std::sync::{atomic, Arc};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+
+/// Starts balancebeam with round-robin balancing over the given upstreams and the given [retry]
+/// settings. The active health checks and circuit breaker are configured so that they stay out of
+/// the way. Returns balancebeam, its config file and the address of its admin API.
+async fn with_retry(upstreams: &[&str], retry: &str) -> (BalanceBeam, ConfigFile, String) {
+    let admin_address = free_address();
+    let upstreams: Vec<(&str, &str)> = upstreams.iter().map(|upstream| (*upstream, "")).collect();
+    let (balancebeam, config) = with_upstreams(
+        &format!(
+            "strategy = \"round-robin\"\nadmin_listener = \"{}\"\n",
+            admin_address
+        ),
+        &upstreams,
+        &format!(
+            "[circuit_breaker]\nconsecutive_failures = 1000\n\n[retry]\n{}",
+            retry
+        ),
+    )
+    .await;
+    (balancebeam, config, admin_address)
+}
+
+/// Starts a server that reads whatever is sent to it and hangs up without answering. Returns its
+/// address and a count of the connections it has accepted.
+async fn start_hang_up_server() -> (String, Arc<atomic::AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(atomic::AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counter.fetch_add(1, atomic::Ordering::SeqCst);
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+        }
+    });
+    (address, connections)
+}
+
+/// Sends a request to balancebeam and returns the status and body of the response
+async fn send(
+    balancebeam: &BalanceBeam,
+    method: reqwest::Method,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> (u16, String) {
+    let mut request = reqwest::Client::new()
+        .request(method, format!("http://{}/", balancebeam.address))
+        .body(body.to_string());
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+/// Idempotent requests that get a status in retry_on should be retried on another upstream
+#[tokio::test]
+async fn test_retry_on_status() {
+    init_logging();
+    let healthy = HealthServer::new().await;
+    let failing = HealthServer::new().await;
+    failing.set_status(503);
+    let (balancebeam, _config, admin_address) = with_retry(
+        &[&healthy.address, &failing.address],
+        "max_retries = 1\nretry_on = [\"5xx\"]\n",
+    )
+    .await;
+
+    for _ in 0..6 {
+        let (status, body) = send(&balancebeam, reqwest::Method::GET, &[], "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body, format!("hello from {}", healthy.address));
+    }
+    assert!(failing.requests() >= 3);
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        metrics.contains(&format!(
+            "balancebeam_upstream_retries_total{{upstream=\"{}\",reason=\"status\"}} {}",
+            failing.address,
+            failing.requests()
+        )),
+        "Metrics should count the retries: {}",
+        metrics
+    );
+
+    for upstream in [healthy, failing] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// POST requests should only be retried if they carry one of the safe headers
+#[tokio::test]
+async fn test_post_needs_safe_header() {
+    init_logging();
+    let healthy = HealthServer::new().await;
+    let failing = HealthServer::new().await;
+    failing.set_status(500);
+    let (balancebeam, _config, _admin_address) = with_retry(
+        &[&healthy.address, &failing.address],
+        "max_retries = 1\nretry_on = [500]\nsafe_headers = [\"x-safe-to-retry\"]\n",
+    )
+    .await;
+
+    let mut errors = 0;
+    for _ in 0..6 {
+        let (status, _) = send(&balancebeam, reqwest::Method::POST, &[], "data").await;
+        if status == 500 {
+            errors += 1;
+        }
+    }
+    assert_eq!(
+        errors,
+        failing.requests(),
+        "POST requests should not be retried"
+    );
+    assert!(errors > 0);
+
+    for _ in 0..6 {
+        let (status, _) = send(
+            &balancebeam,
+            reqwest::Method::POST,
+            &[("x-safe-to-retry", "1")],
+            "data",
+        )
+        .await;
+        assert_eq!(
+            status, 200,
+            "POST requests with the safe header should be retried"
+        );
+    }
+
+    for upstream in [healthy, failing] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// A request whose upstream hangs up without answering should be sent again, body and all, to
+/// another upstream
+#[tokio::test]
+async fn test_retry_after_connection_error() {
+    init_logging();
+    let echo = EchoServer::new().await;
+    let (hang_up_address, hang_up_connections) = start_hang_up_server().await;
+    let (balancebeam, _config, _admin_address) =
+        with_retry(&[&hang_up_address, &echo.address], "max_retries = 1\n").await;
+
+    for i in 0..4 {
+        let body = format!("please retry me {}", i);
+        let (status, echoed) = send(&balancebeam, reqwest::Method::PUT, &[], &body).await;
+        assert_eq!(status, 200);
+        assert!(
+            echoed.starts_with("PUT / HTTP/1.1") && echoed.ends_with(&body),
+            "Unexpected response: {}",
+            echoed
+        );
+    }
+    assert!(hang_up_connections.load(atomic::Ordering::SeqCst) >= 2);
+
+    Box::new(echo).stop().await;
+    log::info!("All done :)");
+}
+
+/// Requests shouldn't be retried unless max_retries is set
+#[tokio::test]
+async fn test_no_retries_by_default() {
+    init_logging();
+    let echo = EchoServer::new().await;
+    let (hang_up_address, hang_up_connections) = start_hang_up_server().await;
+    let (balancebeam, _config, _admin_address) =
+        with_retry(&[&hang_up_address, &echo.address], "retry_on = [\"5xx\"]\n").await;
+
+    let mut errors = 0;
+    for _ in 0..4 {
+        let (status, _) = send(&balancebeam, reqwest::Method::GET, &[], "").await;
+        if status == 502 {
+            errors += 1;
+        }
+    }
+    assert_eq!(errors, 2, "Half of the requests should have failed");
+    assert_eq!(hang_up_connections.load(atomic::Ordering::SeqCst), 2);
+
+    assert_eq!(Box::new(echo).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Requests should be retried at most max_retries times
+#[tokio::test]
+async fn test_retry_budget() {
+    init_logging();
+    let upstreams = [
+        HealthServer::new().await,
+        HealthServer::new().await,
+        HealthServer::new().await,
+    ];
+    for upstream in &upstreams {
+        upstream.set_status(502);
+    }
+    let addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let (balancebeam, _config, _admin_address) = with_retry(
+        &addresses,
+        "max_retries = 1\nbackoff_ms = 10\nretry_on = [502]\n",
+    )
+    .await;
+
+    for _ in 0..5 {
+        let (status, _) = send(&balancebeam, reqwest::Method::GET, &[], "").await;
+        assert_eq!(status, 502);
+    }
+    let total: usize = upstreams.iter().map(HealthServer::requests).sum();
+    assert_eq!(total, 10, "Each request should be sent twice");
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use common::{init_logging, with_timeouts, BalanceBeam, HealthServer, Server};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts a server that accepts connections and reads from them, but never sends anything back.
+/// Returns its address.
+async fn start_silent_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Reads everything balancebeam sends until it closes the connection (giving up after 5 seconds)
+async fn read_until_close(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not close the connection")
+        .expect("Error reading from balancebeam");
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Sends a GET request and returns the response status, along with how long it took
+async fn timed_get(balancebeam: &BalanceBeam) -> (u16, Duration) {
+    let started = Instant::now();
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    (response.status().as_u16(), started.elapsed())
+}
+
+/// An upstream that doesn't send response headers in time should get the client a 504
+#[tokio::test]
+async fn test_upstream_header_timeout() {
+    init_logging();
+    let upstream = start_silent_server().await;
+    let (balancebeam, _config) = with_timeouts(&upstream, "header_read_ms = 500\n").await;
+
+    let (status, elapsed) = timed_get(&balancebeam).await;
+    assert_eq!(status, 504);
+    assert!(elapsed < Duration::from_secs(3), "Took {:?}", elapsed);
+    log::info!("All done :)");
+}
+
+/// An upstream that can't be connected to in time (here, because its TLS handshake never
+/// finishes) should get the client a 504
+#[tokio::test]
+async fn test_connect_timeout() {
+    init_logging();
+    let upstream = format!("https://{}", start_silent_server().await);
+    let (balancebeam, _config) = with_timeouts(&upstream, "connect_ms = 300\n").await;
+
+    let (status, elapsed) = timed_get(&balancebeam).await;
+    assert_eq!(status, 504);
+    assert!(elapsed < Duration::from_secs(3), "Took {:?}", elapsed);
+    log::info!("All done :)");
+}
+
+/// A request that isn't done by the total request timeout should get a 504, even if no other
+/// timeout has run out
+#[tokio::test]
+async fn test_total_request_timeout() {
+    init_logging();
+    let upstream = start_silent_server().await;
+    let (balancebeam, _config) =
+        with_timeouts(&upstream, "header_read_ms = 60000\nrequest_ms = 500\n").await;
+
+    let (status, elapsed) = timed_get(&balancebeam).await;
+    assert_eq!(status, 504);
+    assert!(elapsed < Duration::from_secs(3), "Took {:?}", elapsed);
+    log::info!("All done :)");
+}
+
+/// A client that starts sending a request but doesn't finish its headers in time should get a 408
+#[tokio::test]
+async fn test_client_header_timeout() {
+    init_logging();
+    let upstream = HealthServer::new().await;
+    let (balancebeam, _config) = with_timeouts(&upstream.address, "header_read_ms = 500\n").await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n")
+        .await
+        .unwrap();
+    let response = read_until_close(&mut stream).await;
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Unexpected response: {}",
+        response
+    );
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// A client that stops sending its request body should get a 408
+#[tokio::test]
+async fn test_client_body_timeout() {
+    init_logging();
+    let upstream = HealthServer::new().await;
+    let (balancebeam, _config) = with_timeouts(&upstream.address, "body_read_ms = 500\n").await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 10\r\n\r\nabc")
+        .await
+        .unwrap();
+    let response = read_until_close(&mut stream).await;
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Unexpected response: {}",
+        response
+    );
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Keep-alive client connections should be closed once they have been idle for too long
+#[tokio::test]
+async fn test_idle_timeout() {
+    init_logging();
+    let upstream = HealthServer::new().await;
+    let (balancebeam, _config) = with_timeouts(&upstream.address, "idle_ms = 500\n").await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let started = Instant::now();
+    let response = read_until_close(&mut stream).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        response
+    );
+    assert!(
+        started.elapsed() >= Duration::from_millis(400),
+        "The connection should stay open for a while after the response"
+    );
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
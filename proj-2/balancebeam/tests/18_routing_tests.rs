@@ -0,0 +1,132 @@
+mod common;
+
+use common::{init_logging, with_upstreams, BalanceBeam, ConfigFile, HealthServer, Server};
+
+/// Starts balancebeam with the given upstreams (address and pool) and routes (as TOML). The active
+/// health checks stay out of the way.
+async fn with_routes(upstreams: &[(&str, &str)], routes: &str) -> (BalanceBeam, ConfigFile) {
+    let pools: Vec<String> = upstreams
+        .iter()
+        .map(|(_, pool)| format!("pool = \"{}\"\n", pool))
+        .collect();
+    let upstreams: Vec<(&str, &str)> = upstreams
+        .iter()
+        .zip(&pools)
+        .map(|((address, _), pool)| (*address, pool.as_str()))
+        .collect();
+    with_upstreams("", &upstreams, routes).await
+}
+
+/// Sends a request to balancebeam and returns the status and body of the response
+async fn send(
+    balancebeam: &BalanceBeam,
+    method: reqwest::Method,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> (u16, String) {
+    let mut request =
+        reqwest::Client::new().request(method, format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+/// Requests should go to the pool of the first route that matches their host or path, and to the
+/// default pool otherwise
+#[tokio::test]
+async fn test_route_by_host_and_path() {
+    init_logging();
+    let api = HealthServer::new().await;
+    let assets = HealthServer::new().await;
+    let fallback = HealthServer::new().await;
+    let (balancebeam, _config) = with_routes(
+        &[
+            (&api.address, "api"),
+            (&assets.address, "assets"),
+            (&fallback.address, "default"),
+        ],
+        "[[routes]]\npool = \"api\"\nhost = \"api.example.com\"\n\n\
+         [[routes]]\npool = \"assets\"\nhost = \"*.cdn.example.com\"\n\n\
+         [[routes]]\npool = \"assets\"\npath_prefix = \"/static/\"\n\n\
+         [[routes]]\npool = \"api\"\npath_regex = \"^/v[0-9]+/\"\n",
+    )
+    .await;
+
+    let cases = [
+        ("/", "API.example.com:8080", &api),
+        ("/static/app.js", "api.example.com", &api),
+        ("/", "img.cdn.example.com", &assets),
+        ("/", "cdn.example.com", &fallback),
+        ("/static/app.js", "www.example.com", &assets),
+        ("/v2/users", "www.example.com", &api),
+        ("/users/v2/", "www.example.com", &fallback),
+    ];
+    for (path, host, expected) in cases {
+        let (status, body) =
+            send(&balancebeam, reqwest::Method::GET, path, &[("Host", host)]).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            format!("hello from {}", expected.address),
+            "GET {} for {} went to the wrong pool",
+            path,
+            host
+        );
+    }
+
+    for upstream in [api, assets, fallback] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Routes can also match on the method and on header values, and requests that no route matches
+/// get a 404 when there is no default pool
+#[tokio::test]
+async fn test_route_by_method_and_headers() {
+    init_logging();
+    let writes = HealthServer::new().await;
+    let canary = HealthServer::new().await;
+    let (balancebeam, _config) = with_routes(
+        &[(&writes.address, "writes"), (&canary.address, "canary")],
+        "[[routes]]\npool = \"canary\"\n[routes.headers]\nx-canary = \"yes\"\n\n\
+         [[routes]]\npool = \"writes\"\nmethods = [\"POST\", \"PUT\"]\n",
+    )
+    .await;
+
+    let (status, body) = send(&balancebeam, reqwest::Method::POST, "/", &[]).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, format!("hello from {}", writes.address));
+
+    let (status, body) = send(
+        &balancebeam,
+        reqwest::Method::GET,
+        "/",
+        &[("x-canary", "yes")],
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body, format!("hello from {}", canary.address));
+
+    let (status, _) = send(
+        &balancebeam,
+        reqwest::Method::GET,
+        "/",
+        &[("x-canary", "no")],
+    )
+    .await;
+    assert_eq!(status, 404);
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "/", &[]).await;
+    assert_eq!(status, 404);
+    assert_eq!(writes.requests() + canary.requests(), 2);
+
+    for upstream in [writes, canary] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
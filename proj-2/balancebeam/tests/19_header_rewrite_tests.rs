@@ -0,0 +1,158 @@
+mod common;
+
+use common::{init_logging, with_config, BalanceBeam, EchoServer, Server};
+
+/// Sends a GET request with the given headers, returning the response (whose body is the request
+/// as the echo server received it)
+async fn get(balancebeam: &BalanceBeam, path: &str, headers: &[(&str, &str)]) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// Requests should only get X-Forwarded-For by default, and the other proxy headers (and a Via
+/// header on responses) when they are turned on
+#[tokio::test]
+async fn test_proxy_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancebeam, _config) = with_config(&upstream.address, "").await;
+    let response = get(&balancebeam, "/", &[]).await;
+    assert!(response.headers().get("via").is_none());
+    let echoed = response.text().await.unwrap();
+    assert!(echoed.contains("x-forwarded-for: 127.0.0.1\n"));
+    assert!(!echoed.contains("x-forwarded-proto") && !echoed.contains("forwarded:"));
+    assert!(!echoed.contains("via:"));
+
+    let (balancebeam, _config) = with_config(
+        &upstream.address,
+        "[headers]\nforwarded = true\nvia = true\n",
+    )
+    .await;
+
+    let response = get(&balancebeam, "/", &[("Via", "1.1 edge")]).await;
+    assert_eq!(
+        response.headers().get("via").unwrap(),
+        "1.1 balancebeam",
+        "The response should say it went through balancebeam"
+    );
+    let echoed = response.text().await.unwrap();
+    for expected in [
+        "x-forwarded-for: 127.0.0.1\n".to_string(),
+        "x-forwarded-proto: http\n".to_string(),
+        format!("x-forwarded-host: {}\n", balancebeam.address),
+        format!(
+            "forwarded: for=127.0.0.1;proto=http;host=\"{}\"\n",
+            balancebeam.address
+        ),
+        "via: 1.1 edge, 1.1 balancebeam\n".to_string(),
+    ] {
+        assert!(
+            echoed.contains(&expected),
+            "Missing {:?} in request:\n{}",
+            expected,
+            echoed
+        );
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Header rules should add, set and remove request and response headers, filling in variables
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancebeam, _config) = with_config(
+        &upstream.address,
+        "[headers]\nrequest_id_header = \"x-request-id\"\n\n\
+         [headers.request]\nremove = [\"x-secret\"]\n\
+         [headers.request.set]\nx-sent-by = \"proxy for ${client_ip}\"\n\
+         [headers.request.add]\nx-route = \"${method} ${path} to ${upstream} in ${pool}\"\n\n\
+         [headers.response]\nremove = [\"content-type\"]\n\
+         [headers.response.set]\nx-served-by = \"${upstream}\"\nx-price = \"$$5\"\n",
+    )
+    .await;
+
+    let response = get(
+        &balancebeam,
+        "/page?q=1",
+        &[("x-secret", "hunter2"), ("x-request-id", "abc123")],
+    )
+    .await;
+    let headers = response.headers().clone();
+    assert_eq!(
+        headers.get("x-served-by").unwrap(),
+        upstream.address.as_str()
+    );
+    assert_eq!(headers.get("x-price").unwrap(), "$5");
+    assert_eq!(headers.get("x-request-id").unwrap(), "abc123");
+    assert!(headers.get("content-type").is_none());
+    let echoed = response.text().await.unwrap();
+    assert!(!echoed.contains("hunter2"), "x-secret should be removed");
+    assert!(echoed.contains("x-request-id: abc123\n"));
+    assert!(
+        echoed.contains("x-sent-by: proxy for 127.0.0.1\n"),
+        "x-sent-by should be replaced: {}",
+        echoed
+    );
+    assert!(echoed.contains(&format!(
+        "x-route: GET /page?q=1 to {} in default\n",
+        upstream.address
+    )));
+
+    // Requests without an ID should get a new one each
+    let first = get(&balancebeam, "/", &[]).await;
+    let second = get(&balancebeam, "/", &[]).await;
+    let first_id = first.headers().get("x-request-id").unwrap().clone();
+    let second_id = second.headers().get("x-request-id").unwrap().clone();
+    assert_ne!(first_id, second_id);
+    let echoed = first.text().await.unwrap();
+    assert!(echoed.contains(&format!("x-request-id: {}\n", first_id.to_str().unwrap())));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Routes should be able to strip their path prefix or rewrite the path with their regex, keeping
+/// the query string
+#[tokio::test]
+async fn test_path_rewrite() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancebeam, _config) = with_config(
+        &upstream.address,
+        "[[routes]]\npool = \"default\"\npath_prefix = \"/api\"\nstrip_prefix = true\n\n\
+         [[routes]]\npool = \"default\"\npath_regex = \"^/users/([0-9]+)$\"\n\
+         rewrite_path = \"/profiles/$1/\"\n",
+    )
+    .await;
+
+    for (path, expected) in [
+        ("/api/v1/items?limit=5", "GET /v1/items?limit=5 HTTP/1.1"),
+        ("/api", "GET / HTTP/1.1"),
+        (
+            "/users/42?tab=posts",
+            "GET /profiles/42/?tab=posts HTTP/1.1",
+        ),
+        ("/other", "GET /other HTTP/1.1"),
+    ] {
+        let echoed = get(&balancebeam, path, &[]).await.text().await.unwrap();
+        assert!(
+            echoed.starts_with(expected),
+            "{} should be forwarded as {}, not:\n{}",
+            path,
+            expected,
+            echoed
+        );
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,447 @@
+mod common;
+
+use common::{init_logging, is_closed, read_response, BalanceBeam, ConfigFile, EchoServer, Server};
+use std::sync::{atomic, Arc};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts an upstream that answers every request with the given raw response, closing the
+/// connection afterwards if close is true. Returns its address and a count of the connections it
+/// has accepted.
+async fn start_raw_server(
+    response: &'static str,
+    close: bool,
+) -> (String, Arc<atomic::AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(atomic::AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counter.fetch_add(1, atomic::Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                while let Ok(bytes_read) = stream.read(&mut buffer).await {
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buffer[..bytes_read]);
+                    // The requests in these tests have no body
+                    while let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                        received.drain(..end + 4);
+                        if stream.write_all(response.as_bytes()).await.is_err() || close {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Hop-by-hop headers from the client, including any named in its Connection header, should not
+/// reach the upstream
+#[tokio::test]
+async fn test_request_hop_by_hop_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, x-hop\r\n\
+              Keep-Alive: timeout=5\r\nX-Hop: secret\r\nProxy-Authorization: Basic Zm9vOmJhcg==\r\n\
+              Proxy-Connection: keep-alive\r\nTE: trailers\r\nUpgrade: example/1\r\n\
+              X-End-To-End: yes\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, echoed) = read_response(&mut stream).await;
+    assert!(
+        head.starts_with("http/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    let echoed = echoed.to_lowercase();
+    for header in [
+        "connection:",
+        "keep-alive:",
+        "x-hop:",
+        "proxy-authorization:",
+        "proxy-connection:",
+        "te:",
+        "upgrade:",
+    ] {
+        assert!(
+            !echoed.contains(&format!("\n{}", header)),
+            "{} should not be forwarded:\n{}",
+            header,
+            echoed
+        );
+    }
+    assert!(echoed.contains("\nx-end-to-end: yes\n"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Naming Transfer-Encoding or Content-Length in the Connection header shouldn't strip them, or the
+/// body would be forwarded without anything saying where it ends, and the receiver could take part
+/// of it as another message
+#[tokio::test]
+async fn test_connection_cannot_remove_framing() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "POST / HTTP/1.1\r\nHost: example.com\r\nConnection: transfer-encoding\r\n\
+                 Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                smuggled.len(),
+                smuggled
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let (head, echoed) = read_response(&mut stream).await;
+    assert!(
+        head.starts_with("http/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(
+        echoed.contains("\ntransfer-encoding: chunked\n") && echoed.ends_with(smuggled),
+        "The body should have reached the upstream as a chunked body:\n{}",
+        echoed
+    );
+    assert_eq!(
+        Box::new(upstream).stop().await,
+        1,
+        "The upstream should only have seen one request"
+    );
+
+    let (upstream, _connections) = start_raw_server(
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: content-length\r\n\r\nok",
+        false,
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(head.contains("\ncontent-length: 2\r\n"), "{}", head);
+    assert_eq!(body, "ok");
+    log::info!("All done :)");
+}
+
+/// Messages with Content-Length headers that disagree should be refused, since the receiver might
+/// believe a different one than balancebeam did, while repeats of the same value should be
+/// forwarded as a single header
+#[tokio::test]
+async fn test_conflicting_content_length() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+              Content-Length: 40\r\n\r\nhelloGET /smuggled HTTP/1.1\r\nHost: x\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut stream).await;
+    assert!(head.starts_with("http/1.1 400"), "{}", head);
+    assert!(is_closed(&mut stream).await);
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+              Content-Length: 5, 5\r\n\r\nhello",
+        )
+        .await
+        .unwrap();
+    let (head, echoed) = read_response(&mut stream).await;
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert_eq!(
+        echoed.matches("content-length:").count(),
+        1,
+        "Exactly one Content-Length should be forwarded:\n{}",
+        echoed
+    );
+    assert!(echoed.contains("\ncontent-length: 5\n") && echoed.ends_with("\n\nhello"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    let (upstream, _connections) = start_raw_server(
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nContent-Length: 20\r\n\r\nok",
+        false,
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut stream).await;
+    assert!(head.starts_with("http/1.1 502"), "{}", head);
+    log::info!("All done :)");
+}
+
+/// Hop-by-hop headers from the upstream should not reach the client
+#[tokio::test]
+async fn test_response_hop_by_hop_headers() {
+    init_logging();
+    let (upstream, _connections) = start_raw_server(
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: x-private\r\nX-Private: secret\r\n\
+         Keep-Alive: timeout=5\r\nProxy-Authenticate: Basic\r\nUpgrade: example/1\r\n\
+         X-Public: yes\r\n\r\nok",
+        false,
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert_eq!(body, "ok");
+    for header in [
+        "connection:",
+        "x-private:",
+        "keep-alive:",
+        "proxy-authenticate:",
+        "upgrade:",
+    ] {
+        assert!(
+            !head.contains(&format!("\n{}", header)),
+            "{} should not be forwarded:\n{}",
+            header,
+            head
+        );
+    }
+    assert!(head.contains("\nx-public: yes\r\n"));
+    log::info!("All done :)");
+}
+
+/// A client's Connection: close should be answered in kind and the connection closed after the
+/// response, while an upstream's Connection: close should only stop balancebeam from reusing that
+/// upstream connection
+#[tokio::test]
+async fn test_connection_close() {
+    init_logging();
+    let (upstream, connections) = start_raw_server(
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        true,
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..3 {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, body) = read_response(&mut stream).await;
+        assert!(
+            head.starts_with("http/1.1 200"),
+            "Unexpected response: {}",
+            head
+        );
+        assert!(!head.contains("\nconnection:"));
+        assert_eq!(body, "ok");
+    }
+    assert_eq!(
+        connections.load(atomic::Ordering::SeqCst),
+        3,
+        "Upstream connections that are being closed should not be reused"
+    );
+
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(
+        head.contains("\nconnection: close\r\n"),
+        "Unexpected response: {}",
+        head
+    );
+    assert_eq!(body, "ok");
+    assert!(
+        is_closed(&mut stream).await,
+        "The connection should be closed"
+    );
+    log::info!("All done :)");
+}
+
+/// Clients sending Expect: 100-continue should be told to go ahead before they send the body, and
+/// other expectations should be refused
+#[tokio::test]
+async fn test_expect_continue() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+              Expect: 100-continue\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        (String::from_utf8_lossy(&head).to_string(), ())
+    })
+    .await
+    .expect("Timed out waiting for 100 Continue");
+    assert_eq!(head, "HTTP/1.1 100 Continue\r\n\r\n");
+    stream.write_all(b"hello").await.unwrap();
+    let (head, echoed) = read_response(&mut stream).await;
+    assert!(
+        head.starts_with("http/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(echoed.ends_with("\n\nhello"), "Unexpected echo: {}", echoed);
+    assert!(!echoed.to_lowercase().contains("\nexpect:"));
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+              Expect: something-else\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut stream).await;
+    assert!(
+        head.starts_with("http/1.1 417"),
+        "Unexpected response: {}",
+        head
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// HTTP/1.0 clients should be served over HTTP/1.1 upstream connections, with their connections
+/// closed after each response unless they ask for keep-alive
+#[tokio::test]
+async fn test_http10_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // The scheme is left out of the Host header
+    let config = ConfigFile::new(
+        "toml",
+        &format!(
+            "[[upstreams]]\naddress = \"http://{}\"\n\n[headers]\nvia = true\n",
+            upstream.address
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+
+    // Without a Host header, the upstream's address is used
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /old HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, echoed) = read_response(&mut stream).await;
+    assert!(
+        head.starts_with("http/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(head.contains("\nconnection: close\r\n"));
+    assert!(
+        echoed.starts_with("GET /old HTTP/1.1\n"),
+        "Unexpected echo: {}",
+        echoed
+    );
+    assert!(echoed.contains(&format!("\nhost: {}\n", upstream.address)));
+    assert!(echoed.contains("\nvia: 1.0 balancebeam\n"));
+    assert!(
+        is_closed(&mut stream).await,
+        "The connection should be closed"
+    );
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nHost: example.com\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut stream).await;
+        assert!(
+            head.starts_with("http/1.1 200"),
+            "Unexpected response: {}",
+            head
+        );
+        assert!(head.contains("\nconnection: keep-alive\r\n"));
+    }
+
+    // HTTP/1.1 requests have to have a Host header
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let (head, _) = read_response(&mut stream).await;
+    assert!(
+        head.starts_with("http/1.1 400"),
+        "Unexpected response: {}",
+        head
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Chunked responses can't be sent to HTTP/1.0 clients, so they should be decoded and delimited by
+/// closing the connection
+#[tokio::test]
+async fn test_http10_client_chunked_response() {
+    init_logging();
+    let (upstream, _connections) = start_raw_server(
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        false,
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(
+        head.starts_with("http/1.1 200"),
+        "Unexpected response: {}",
+        head
+    );
+    assert!(!head.contains("transfer-encoding"));
+    assert!(head.contains("\nconnection: close\r\n"));
+    assert_eq!(body, "hello world");
+    log::info!("All done :)");
+}
@@ -0,0 +1,293 @@
+mod common;
+
+use common::{
+    free_address, init_logging, is_closed, with_config, with_upstreams, BalanceBeam, EchoServer,
+    Server,
+};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Sec-WebSocket-Key from the example in RFC 6455, and the accept value that goes with it
+const WEBSOCKET_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const WEBSOCKET_ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+/// Reads a message head (up to and including the blank line) from a connection, lowercased
+async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.expect("Connection closed mid-head"));
+    }
+    String::from_utf8_lossy(&head).to_lowercase()
+}
+
+/// Starts a WebSocket echo server, which sends every text frame it receives back to its sender.
+/// It only knows the RFC 6455 example key, and answers anything that isn't an upgrade with 400.
+async fn start_websocket_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let head = read_head(&mut stream).await;
+                if !head.contains("upgrade: websocket\r\n")
+                    || !head.contains(&format!(
+                        "sec-websocket-key: {}",
+                        WEBSOCKET_KEY.to_lowercase()
+                    ))
+                {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    return;
+                }
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    WEBSOCKET_ACCEPT
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                while let Ok(message) = read_client_frame(&mut stream).await {
+                    if stream.write_all(&server_frame(&message)).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Reads a masked text frame sent by a WebSocket client, returning its payload
+async fn read_client_frame(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let _opcode = stream.read_u8().await?;
+    let length = (stream.read_u8().await? & 0x7f) as usize;
+    let mut mask = [0_u8; 4];
+    stream.read_exact(&mut mask).await?;
+    let mut payload = vec![0_u8; length];
+    stream.read_exact(&mut payload).await?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok(payload)
+}
+
+/// Encodes a short text frame as a WebSocket client sends it (masked)
+fn client_frame(message: &[u8]) -> Vec<u8> {
+    let mask: [u8; 4] = rand::thread_rng().gen();
+    let mut frame = vec![0x81, 0x80 | message.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        message
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    frame
+}
+
+/// Encodes a short text frame as a WebSocket server sends it (unmasked)
+fn server_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81, message.len() as u8];
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// Starts a server that accepts every CONNECT request and then echoes whatever it receives
+async fn start_connect_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let head = read_head(&mut stream).await;
+                assert!(head.starts_with("connect example.com:443 http/1.1\r\n"));
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                    .await
+                    .unwrap();
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    address
+}
+
+/// Sends a WebSocket upgrade request through balancebeam, returning the connection and the head
+/// of the response
+async fn upgrade(balancebeam: &BalanceBeam) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let request = format!(
+        "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+         Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        WEBSOCKET_KEY
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let head = tokio::time::timeout(Duration::from_secs(5), read_head(&mut stream))
+        .await
+        .expect("Timed out waiting for the upgrade response");
+    (stream, head)
+}
+
+/// Returns the number of active connections to an upstream, according to the admin API
+async fn active_connections(admin_address: &str, upstream: &str) -> u64 {
+    let listing = reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    let listing: serde_json::Value = serde_json::from_str(&listing).unwrap();
+    listing["upstreams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|info| info["address"] == upstream)
+        .unwrap()["active_connections"]
+        .as_u64()
+        .unwrap()
+}
+
+/// A WebSocket upgrade should be passed on to the upstream, after which messages should flow both
+/// ways, with the connection counted as active until it closes
+#[tokio::test]
+async fn test_websocket_upgrade() {
+    init_logging();
+    let upstream = start_websocket_server().await;
+    let admin_address = free_address();
+    let (balancebeam, _config) = with_upstreams(
+        &format!("admin_listener = \"{}\"\n", admin_address),
+        &[(&upstream, "")],
+        "",
+    )
+    .await;
+
+    let (mut stream, head) = upgrade(&balancebeam).await;
+    assert!(
+        head.starts_with("http/1.1 101 "),
+        "The upgrade should be accepted:\n{}",
+        head
+    );
+    assert!(head.contains("upgrade: websocket\r\n"));
+    assert!(head.contains("connection: upgrade\r\n"));
+    assert!(head.contains(&format!(
+        "sec-websocket-accept: {}\r\n",
+        WEBSOCKET_ACCEPT.to_lowercase()
+    )));
+
+    for message in [&b"hello"[..], b"how are you?"] {
+        stream.write_all(&client_frame(message)).await.unwrap();
+        let mut echoed = vec![0_u8; message.len() + 2];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+            .await
+            .expect("Timed out waiting for the echoed message")
+            .unwrap();
+        assert_eq!(echoed, server_frame(message));
+    }
+    assert_eq!(active_connections(&admin_address, &upstream).await, 1);
+
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(active_connections(&admin_address, &upstream).await, 0);
+
+    log::info!("All done :)");
+}
+
+/// If the upstream doesn't agree to an upgrade, the connection should carry on as HTTP. The
+/// upstream should still be asked to upgrade.
+#[tokio::test]
+async fn test_upgrade_declined() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (mut stream, head) = upgrade(&balancebeam).await;
+    assert!(head.starts_with("http/1.1 200 "), "{}", head);
+    let length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut echoed = vec![0_u8; length];
+    stream.read_exact(&mut echoed).await.unwrap();
+    let echoed = String::from_utf8(echoed).unwrap().to_lowercase();
+    assert!(echoed.contains("upgrade: websocket\n"), "{}", echoed);
+    assert!(echoed.contains("connection: upgrade\n"), "{}", echoed);
+
+    stream
+        .write_all(b"GET /next HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_head(&mut stream).await;
+    assert!(head.starts_with("http/1.1 200 "), "{}", head);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A tunnel should be closed once nothing has been sent either way for the idle timeout
+#[tokio::test]
+async fn test_tunnel_idle_timeout() {
+    init_logging();
+    let upstream = start_websocket_server().await;
+    let (balancebeam, _config) = with_config(&upstream, "[timeouts]\nidle_ms = 500\n").await;
+
+    let (mut stream, head) = upgrade(&balancebeam).await;
+    assert!(head.starts_with("http/1.1 101 "));
+    // Traffic keeps the tunnel open past the idle timeout
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        stream.write_all(&client_frame(b"ping")).await.unwrap();
+        let mut echoed = [0_u8; 6];
+        stream.read_exact(&mut echoed).await.unwrap();
+    }
+    assert!(
+        is_closed(&mut stream).await,
+        "The idle tunnel should be closed"
+    );
+
+    log::info!("All done :)");
+}
+
+/// A CONNECT request should open a tunnel to whatever the upstream connects it to
+#[tokio::test]
+async fn test_connect() {
+    init_logging();
+    let upstream = start_connect_server().await;
+    let (balancebeam, _config) = with_config(&upstream, "").await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+        .await
+        .unwrap();
+    let head = tokio::time::timeout(Duration::from_secs(5), read_head(&mut stream))
+        .await
+        .expect("Timed out waiting for the CONNECT response");
+    assert!(
+        head.starts_with("http/1.1 200 "),
+        "The CONNECT should succeed:\n{}",
+        head
+    );
+    assert!(!head.contains("connection: close"));
+
+    for message in ["some bytes", "that aren't HTTP\r\n\r\n"] {
+        stream.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0_u8; message.len()];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+            .await
+            .expect("Timed out waiting for the echoed bytes")
+            .unwrap();
+        assert_eq!(echoed, message.as_bytes());
+    }
+
+    // Closing our end should close the upstream's, and with it the tunnel
+    stream.shutdown().await.unwrap();
+    assert!(is_closed(&mut stream).await);
+
+    log::info!("All done :)");
+}
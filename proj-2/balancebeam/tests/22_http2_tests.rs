@@ -0,0 +1,232 @@
+mod common;
+
+use bytes::Bytes;
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server, TestCertificate};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+
+/// What an HTTP/2 client sends first
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Returns a client that speaks HTTP/2 over cleartext without asking first
+fn h2c_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap()
+}
+
+/// Requests sent as HTTP/2 streams should be forwarded to HTTP/1.1 upstreams, each balanced on its
+/// own even though they share a connection
+#[tokio::test]
+async fn test_h2c_prior_knowledge() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address, &second.address],
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    let client = h2c_client();
+    let mut requests = Vec::new();
+    for i in 0..10 {
+        let client = client.clone();
+        let url = format!("http://{}/stream-{}", balancebeam.address, i);
+        requests.push(tokio::spawn(async move {
+            client
+                .get(url)
+                .header("x-stream", i.to_string())
+                .send()
+                .await
+                .expect("Error sending HTTP/2 request to balancebeam")
+        }));
+    }
+    for (i, request) in requests.into_iter().enumerate() {
+        let response = request.await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.status(), 200);
+        let response_text = response.text().await.unwrap();
+        assert!(
+            response_text.contains(&format!("GET /stream-{} HTTP/1.1", i)),
+            "{}",
+            response_text
+        );
+        assert!(response_text.contains(&format!("x-stream: {}\n", i)));
+    }
+
+    assert_eq!(Box::new(first).stop().await, 5);
+    assert_eq!(Box::new(second).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// HTTP/2 request bodies should be forwarded too, and HTTP/1.1 clients should be unaffected
+#[tokio::test]
+async fn test_h2c_body_and_http1() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response_text = h2c_client()
+        .post(format!("http://{}/upload", balancebeam.address))
+        .body("sent over HTTP/2")
+        .send()
+        .await
+        .expect("Error sending HTTP/2 request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("POST /upload HTTP/1.1"));
+    assert!(response_text.ends_with("\n\nsent over HTTP/2"));
+
+    let response_text = balancebeam.get("/plain").await.unwrap();
+    assert!(response_text.contains("GET /plain HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Reads a frame, returning its type, flags, stream ID and payload
+async fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+    let mut header = [0_u8; 9];
+    stream.read_exact(&mut header).await.unwrap();
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+    let mut payload = vec![0_u8; length];
+    stream.read_exact(&mut payload).await.unwrap();
+    (header[3], header[4], stream_id, payload)
+}
+
+/// A client that upgrades to h2c should get the response to its upgrade request on stream 1
+#[tokio::test]
+async fn test_h2c_upgrade() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"GET /upgraded HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+              Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\nX-Before: upgrade\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    assert!(head.starts_with("http/1.1 101 "), "{}", head);
+    assert!(head.contains("upgrade: h2c\r\n"));
+
+    // The connection preface, and an empty SETTINGS frame
+    stream.write_all(PREFACE).await.unwrap();
+    stream
+        .write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+    let mut status = None;
+    let mut body = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let (frame_type, flags, stream_id, payload) = read_frame(&mut stream).await;
+            if stream_id != 1 {
+                continue;
+            }
+            match frame_type {
+                // HEADERS, which should start with the indexed :status 200
+                1 => status = payload.first().copied(),
+                // DATA
+                0 => body.extend_from_slice(&payload),
+                _ => {}
+            }
+            if flags & 0x1 != 0 {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("Timed out waiting for the response on stream 1");
+    assert_eq!(status, Some(0x88), "The response should be 200 OK");
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.contains("GET /upgraded HTTP/1.1"), "{}", body);
+    assert!(body.contains("x-before: upgrade\n"));
+    assert!(body.contains("host: example.com\n"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Clients that negotiate h2 with ALPN should be served HTTP/2 over TLS, with bodies of unknown
+/// length passed on in chunks
+#[tokio::test]
+async fn test_alpn_h2() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certificate = TestCertificate::new(&["localhost"]);
+    let config = ConfigFile::new(
+        "toml",
+        &format!(
+            "[[upstreams]]\naddress = \"{}\"\n\n[headers]\nforwarded = true\n\n            [[tls.certificates]]\ncert = \"{}\"\nkey = \"{}\"\n",
+            upstream.address,
+            certificate.cert_path(),
+            certificate.key_path()
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut certificate.cert_pem.as_bytes()) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let tcp = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let server_name = "localhost".try_into().unwrap();
+    let tls = connector.connect(server_name, tcp).await.unwrap();
+    assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let (client, connection) = h2::client::handshake(tls).await.unwrap();
+    tokio::spawn(connection);
+    let mut client = client.ready().await.unwrap();
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("https://localhost/secure")
+        .body(())
+        .unwrap();
+    let (response, mut send) = client.send_request(request, false).unwrap();
+    send.send_data(Bytes::from_static(b"first, "), false)
+        .unwrap();
+    send.send_data(Bytes::from_static(b"second"), true).unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(5), response)
+        .await
+        .expect("Timed out waiting for the HTTP/2 response")
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let mut body = response.into_body();
+    let mut response_text = Vec::new();
+    while let Some(data) = body.data().await {
+        let data = data.unwrap();
+        let _ = body.flow_control().release_capacity(data.len());
+        response_text.extend_from_slice(&data);
+    }
+    let response_text = String::from_utf8(response_text).unwrap();
+    assert!(response_text.contains("POST /secure HTTP/1.1"));
+    assert!(response_text.contains("transfer-encoding: chunked\n"));
+    assert!(response_text.contains("x-forwarded-proto: https\n"));
+    assert!(response_text.ends_with("\n\nfirst, second"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
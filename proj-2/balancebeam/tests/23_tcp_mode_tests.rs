@@ -0,0 +1,164 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, Server, TcpEchoServer, TestCertificate};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+
+/// Sends bytes over a connection and checks that the same bytes come back
+async fn assert_echoes<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) {
+    stream.write_all(message).await.unwrap();
+    let mut echoed = vec![0_u8; message.len()];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+        .await
+        .expect("Timed out waiting for the upstream to echo")
+        .expect("Error reading from balancebeam");
+    assert_eq!(echoed, message);
+}
+
+/// Connections should be balanced between upstreams, with bytes copied both ways unchanged
+#[tokio::test]
+async fn test_tcp_mode_balances_connections() {
+    init_logging();
+    let first = TcpEchoServer::new().await;
+    let second = TcpEchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address, &second.address],
+        &["--mode", "tcp", "--strategy", "round-robin"],
+    )
+    .await;
+    // The connection BalanceBeam makes to check that it is up gets passed on too; make sure it has
+    // taken its turn in the round robin before counting
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    for i in 0..4 {
+        let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+        // Not HTTP, and sent in more than one piece
+        assert_echoes(&mut stream, b"\x00\x01 not http \xff").await;
+        assert_echoes(&mut stream, format!("connection {}", i).as_bytes()).await;
+        // The upstream's end should be closed once the client closes its own
+        stream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    assert_eq!(Box::new(first).stop().await, 2);
+    assert_eq!(Box::new(second).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Health checks should only need a connection to succeed, so upstreams that don't speak HTTP stay
+/// live, while upstreams that can't be connected to are taken out of the rotation
+#[tokio::test]
+async fn test_tcp_mode_health_checks() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    // An address that nothing is listening on
+    let dead_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_address, &upstream.address],
+        &[
+            "--mode",
+            "tcp",
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    // Wait for a few rounds of health checks
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for _ in 0..4 {
+        let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+        assert_echoes(&mut stream, b"still live").await;
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// Clients shouldn't be able to have more than --max-connections-per-ip connections open at once
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--mode", "tcp", "--max-connections-per-ip", "2"],
+    )
+    .await;
+
+    let mut first = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_echoes(&mut first, b"first").await;
+    let mut second = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_echoes(&mut second, b"second").await;
+
+    // The third connection should be closed without being passed on
+    let mut third = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let _ = third.write_all(b"third").await;
+    let mut buf = [0_u8; 16];
+    let read = tokio::time::timeout(Duration::from_secs(5), third.read(&mut buf))
+        .await
+        .expect("The connection over the limit should have been closed");
+    assert!(matches!(read, Ok(0) | Err(_)), "{:?}", read);
+
+    // Closing a connection should make room for another
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut fourth = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_echoes(&mut fourth, b"fourth").await;
+    assert_echoes(&mut second, b"second again").await;
+
+    drop(second);
+    drop(fourth);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// TLS should still be terminated in tcp mode, but without offering HTTP protocols over ALPN, since
+/// what is inside the connection might not be HTTP
+#[tokio::test]
+async fn test_tcp_mode_tls_without_alpn() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let certificate = TestCertificate::new(&["localhost"]);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--mode",
+            "tcp",
+            "--tls-cert",
+            certificate.cert_path(),
+            "--tls-key",
+            certificate.key_path(),
+        ],
+    )
+    .await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut certificate.cert_pem.as_bytes()) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let tcp = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let server_name = "localhost".try_into().unwrap();
+    let mut tls = connector.connect(server_name, tcp).await.unwrap();
+    assert_eq!(tls.get_ref().1.alpn_protocol(), None);
+    assert_echoes(&mut tls, b"not http over tls").await;
+    drop(tls);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use common::{init_logging, with_upstreams, BalanceBeam, ConfigFile, HealthServer, Server};
+use std::collections::HashSet;
+
+/// Starts balancebeam with round-robin balancing over the given upstreams, health checking them
+/// every second, with the given [session_affinity] settings
+async fn with_affinity(
+    upstreams: &[&HealthServer],
+    session_affinity: &str,
+) -> (BalanceBeam, ConfigFile) {
+    let upstreams: Vec<(&str, &str)> = upstreams
+        .iter()
+        .map(|upstream| (upstream.address.as_str(), ""))
+        .collect();
+    with_upstreams(
+        "strategy = \"round-robin\"\n",
+        &upstreams,
+        &format!(
+            "[health_check]\ninterval = 1\npath = \"/health\"\n\n[session_affinity]\n{}",
+            session_affinity
+        ),
+    )
+    .await
+}
+
+/// Sends a request with the given extra header, returning the address of the upstream that
+/// answered it and the Set-Cookie header of the response (if any)
+async fn send(balancebeam: &BalanceBeam, header: Option<(&str, &str)>) -> (String, Option<String>) {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    if let Some((name, value)) = header {
+        request = request.header(name, value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 200);
+    let set_cookie = response
+        .headers()
+        .get("set-cookie")
+        .map(|value| value.to_str().unwrap().to_string());
+    let answered_by = response
+        .text()
+        .await
+        .unwrap()
+        .trim_start_matches("hello from ")
+        .to_string();
+    (answered_by, set_cookie)
+}
+
+/// Returns the upstream that the given address belongs to
+fn server<'a>(upstreams: &[&'a HealthServer], address: &str) -> &'a HealthServer {
+    upstreams
+        .iter()
+        .find(|upstream| upstream.address == address)
+        .expect("Response from an unknown upstream")
+}
+
+/// Clients should be given a cookie that keeps them on the upstream that first answered them, and a
+/// new one when that upstream goes down
+#[tokio::test]
+async fn test_inserted_cookie() {
+    init_logging();
+    let first = HealthServer::new().await;
+    let second = HealthServer::new().await;
+    let third = HealthServer::new().await;
+    let upstreams = [&first, &second, &third];
+    let (balancebeam, _config) = with_affinity(
+        &upstreams,
+        "type = \"cookie\"\nname = \"sticky\"\nmax_age = 3600\n",
+    )
+    .await;
+
+    let (pinned, set_cookie) = send(&balancebeam, None).await;
+    let set_cookie = set_cookie.expect("The first response should set the affinity cookie");
+    assert!(set_cookie.starts_with("sticky="), "{}", set_cookie);
+    assert!(set_cookie.contains("; Max-Age=3600"), "{}", set_cookie);
+    assert!(
+        !set_cookie.contains(&pinned),
+        "The cookie shouldn't give the address away"
+    );
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    for _ in 0..6 {
+        let (answered_by, set_cookie) = send(&balancebeam, Some(("cookie", &cookie))).await;
+        assert_eq!(answered_by, pinned);
+        assert_eq!(set_cookie, None, "The cookie is already right");
+    }
+
+    // Once the pinned upstream fails its health check, the client should be moved to another one
+    let pinned_server = server(&upstreams, &pinned);
+    pinned_server.set_health(500, "");
+    let checks = pinned_server.health_checks().len();
+    pinned_server.wait_for_health_checks(checks + 1).await;
+    let (moved_to, set_cookie) = send(
+        &balancebeam,
+        Some(("cookie", &format!("other=1; {}", cookie))),
+    )
+    .await;
+    assert_ne!(moved_to, pinned);
+    let set_cookie = set_cookie.expect("The client should be pinned to its new upstream");
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    for _ in 0..4 {
+        let (answered_by, _) = send(&balancebeam, Some(("cookie", &cookie))).await;
+        assert_eq!(answered_by, moved_to);
+    }
+    for upstream in [first, second, third] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Requests with the same value for the configured header should go to the same upstream, and
+/// requests without it should be balanced as usual
+#[tokio::test]
+async fn test_header_hash() {
+    init_logging();
+    let first = HealthServer::new().await;
+    let second = HealthServer::new().await;
+    let third = HealthServer::new().await;
+    let (balancebeam, _config) = with_affinity(
+        &[&first, &second, &third],
+        "type = \"header\"\nname = \"x-tenant\"\n",
+    )
+    .await;
+
+    let mut pinned_to = HashSet::new();
+    for tenant in 0..12 {
+        let tenant = format!("tenant-{}", tenant);
+        let (pinned, set_cookie) = send(&balancebeam, Some(("x-tenant", &tenant))).await;
+        assert_eq!(set_cookie, None);
+        for _ in 0..3 {
+            let (answered_by, _) = send(&balancebeam, Some(("x-tenant", &tenant))).await;
+            assert_eq!(answered_by, pinned);
+        }
+        pinned_to.insert(pinned);
+    }
+    assert!(
+        pinned_to.len() > 1,
+        "Tenants should be spread over the upstreams"
+    );
+
+    let mut answered_by = HashSet::new();
+    for _ in 0..3 {
+        answered_by.insert(send(&balancebeam, None).await.0);
+    }
+    assert_eq!(answered_by.len(), 3);
+    for upstream in [first, second, third] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Requests with the same application session cookie should go to the same upstream while it is
+/// healthy, and all move to the same other upstream when it isn't
+#[tokio::test]
+async fn test_app_cookie_fallback() {
+    init_logging();
+    let first = HealthServer::new().await;
+    let second = HealthServer::new().await;
+    let upstreams = [&first, &second];
+    let (balancebeam, _config) =
+        with_affinity(&upstreams, "type = \"app-cookie\"\nname = \"SESSIONID\"\n").await;
+
+    let cookie = "theme=dark; SESSIONID=abc123";
+    let (pinned, set_cookie) = send(&balancebeam, Some(("cookie", cookie))).await;
+    assert_eq!(
+        set_cookie, None,
+        "balancebeam shouldn't set cookies of its own"
+    );
+    for _ in 0..4 {
+        assert_eq!(send(&balancebeam, Some(("cookie", cookie))).await.0, pinned);
+    }
+
+    let pinned_server = server(&upstreams, &pinned);
+    pinned_server.set_health(500, "");
+    let checks = pinned_server.health_checks().len();
+    pinned_server.wait_for_health_checks(checks + 1).await;
+    let moved_to = send(&balancebeam, Some(("cookie", cookie))).await.0;
+    assert_ne!(moved_to, pinned);
+    for _ in 0..4 {
+        assert_eq!(
+            send(&balancebeam, Some(("cookie", cookie))).await.0,
+            moved_to
+        );
+    }
+
+    // Back to the original upstream once it recovers
+    pinned_server.set_health(200, "");
+    let checks = pinned_server.health_checks().len();
+    pinned_server.wait_for_health_checks(checks + 1).await;
+    assert_eq!(send(&balancebeam, Some(("cookie", cookie))).await.0, pinned);
+    for upstream in [first, second] {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
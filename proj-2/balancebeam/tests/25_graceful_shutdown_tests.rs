@@ -0,0 +1,121 @@
+mod common;
+
+use common::{init_logging, read_response, with_timeouts, EchoServer, Server};
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts an upstream that waits for the given time before answering each request with "slow"
+async fn slow_upstream(delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read_u8().await {
+                        Ok(byte) => request.push(byte),
+                        Err(_) => return,
+                    }
+                }
+                tokio::time::sleep(delay).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                    .await;
+            });
+        }
+    });
+    address
+}
+
+/// Requests in flight when SIGTERM arrives should be finished (and told that the connection is
+/// closing), while new connections are refused
+#[tokio::test]
+async fn test_in_flight_requests_finish() {
+    init_logging();
+    let upstream = slow_upstream(Duration::from_millis(1500)).await;
+    let (mut balancebeam, _config) = with_timeouts(&upstream, "shutdown_ms = 10000\n").await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    balancebeam.send_signal(Signal::SIGTERM);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(
+        TcpStream::connect(&balancebeam.address).await.is_err(),
+        "New connections should be refused once shutdown has started"
+    );
+
+    let (head, body) = read_response(&mut stream).await;
+    assert!(head.starts_with("http/1.1 200 "), "{}", head);
+    assert!(head.contains("connection: close\r\n"), "{}", head);
+    assert_eq!(body, "slow");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+
+    assert_eq!(
+        balancebeam.wait_for_exit(Duration::from_secs(5)).await,
+        Some(0),
+        "balancebeam should report that draining completed"
+    );
+    log::info!("All done :)");
+}
+
+/// Idle keep-alive connections should be closed straight away on SIGINT, rather than holding up
+/// the shutdown
+#[tokio::test]
+async fn test_idle_connections_closed() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (mut balancebeam, _config) = with_timeouts(&upstream.address, "").await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(!head.contains("connection: close"), "{}", head);
+    assert!(body.contains("GET /first HTTP/1.1"));
+
+    balancebeam.send_signal(Signal::SIGINT);
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest))
+        .await
+        .expect("The idle connection should have been closed")
+        .unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(
+        balancebeam.wait_for_exit(Duration::from_secs(2)).await,
+        Some(0)
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// If connections are still open when the shutdown timeout runs out, balancebeam should exit anyway
+/// and say that draining didn't complete
+#[tokio::test]
+async fn test_shutdown_timeout() {
+    init_logging();
+    let upstream = slow_upstream(Duration::from_secs(30)).await;
+    let (mut balancebeam, _config) = with_timeouts(&upstream, "shutdown_ms = 500\n").await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /stuck HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    balancebeam.send_signal(Signal::SIGTERM);
+    let exit_code = balancebeam.wait_for_exit(Duration::from_secs(3)).await;
+    assert_eq!(exit_code, Some(1), "Draining should not have completed");
+    log::info!("All done :)");
+}
@@ -2,11 +2,11 @@ use rand::Rng;
 // use std::time::Duration;
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
 use tokio::process::{Child, Command};
 use tokio::time::sleep;
 
 pub struct BalanceBeam {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
 }
@@ -20,10 +20,29 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
+    ) -> BalanceBeam {
+        let mut args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any extra command-line arguments
+    /// through unchanged (e.g. `--strategy round-robin`)
+    pub async fn new_with_args<S: AsRef<std::ffi::OsStr>>(
+        upstreams: &[&str],
+        extra_args: &[S],
     ) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
@@ -32,21 +51,16 @@ impl BalanceBeam {
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
-        }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -82,14 +96,41 @@ impl BalanceBeam {
 
         // Hack: wait for executable to start running
         sleep(Duration::from_secs(1)).await;
+        // When lots of tests are running at once, starting up can take longer than that, so also
+        // wait (for a while) until it is accepting connections
+        for _ in 0..50 {
+            if TcpStream::connect(&address).await.is_ok() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
         BalanceBeam { child, address }
     }
 
+    /// Sends a signal (e.g. SIGHUP) to the balancebeam process
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id().unwrap() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Could not send signal to balancebeam");
+    }
+
+    /// Waits for the balancebeam process to exit, returning its exit code, or None if it is still
+    /// running after the given time
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> Option<i32> {
+        let status = tokio::time::timeout(timeout, self.child.wait())
+            .await
+            .ok()?;
+        status
+            .expect("Error waiting for balancebeam to exit")
+            .code()
+    }
+
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .get(&format!("http://{}{}", self.address, path))
+            .get(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await?
@@ -101,7 +142,7 @@ impl BalanceBeam {
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}{}", self.address, path))
+            .post(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .body(body.to_string())
             .send()
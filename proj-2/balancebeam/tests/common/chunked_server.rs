@@ -0,0 +1,139 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use rand::Rng;
+use std::sync::{atomic, Arc};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// Number of chunks in each response
+const NUM_CHUNKS: usize = 5;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Returns the body that ChunkedServer sends in response to a request for path, as the client
+/// should see it once the chunks have been put back together
+pub fn expected_body(path: &str) -> String {
+    (0..NUM_CHUNKS)
+        .map(|i| format!("chunk {} of {}\n", i, path))
+        .collect()
+}
+
+/// Answers every request with a body sent using chunked transfer encoding, a bit at a time like a
+/// streaming API would. Chunks carry a chunk extension, and the body ends with an x-trailer trailer
+/// field. hyper doesn't let us control any of that, so this speaks HTTP over a raw TcpStream.
+pub struct ChunkedServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl ChunkedServer {
+    pub async fn new() -> ChunkedServer {
+        let mut rng = rand::thread_rng();
+        ChunkedServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+    }
+
+    pub async fn new_at_address(bind_addr_string: String) -> ChunkedServer {
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("ChunkedServer could not bind");
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown_rx => return,
+                    accepted = listener.accept() => {
+                        if let Ok((conn, _)) = accepted {
+                            tokio::spawn(serve_connection(conn, server_task_state.clone()));
+                        }
+                    }
+                }
+            }
+        });
+
+        ChunkedServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+async fn serve_connection(mut conn: TcpStream, server_state: Arc<ServerState>) {
+    let mut buffer = Vec::new();
+    loop {
+        // Read a request's headers. Requests sent to this server never have bodies.
+        let headers_end = loop {
+            if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            let mut chunk = [0_u8; 1024];
+            match conn.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(bytes_read) => buffer.extend_from_slice(&chunk[..bytes_read]),
+            }
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        if request.parse(&buffer[..headers_end]).is_err() {
+            return;
+        }
+        let path = request.path.unwrap_or("/").to_string();
+        buffer.drain(..headers_end);
+        server_state
+            .requests_received
+            .fetch_add(1, atomic::Ordering::SeqCst);
+
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+                    Transfer-Encoding: chunked\r\nTrailer: x-trailer\r\n\r\n";
+        if conn.write_all(head.as_bytes()).await.is_err() {
+            return;
+        }
+        for i in 0..NUM_CHUNKS {
+            let data = format!("chunk {} of {}\n", i, path);
+            let chunk = format!("{:x};index={}\r\n{}\r\n", data.len(), i, data);
+            if conn.write_all(chunk.as_bytes()).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        if conn
+            .write_all(b"0\r\nx-trailer: done\r\n\r\n")
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl Server for ChunkedServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the accept loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("ChunkedServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,35 @@
+use rand::Rng;
+use std::path::PathBuf;
+
+/// A config file in the temp directory that is deleted when dropped
+pub struct ConfigFile {
+    path: PathBuf,
+}
+
+impl ConfigFile {
+    pub fn new(extension: &str, contents: &str) -> ConfigFile {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "balancebeam-test-{}.{}",
+            rand::thread_rng().gen::<u64>(),
+            extension
+        ));
+        let config_file = ConfigFile { path };
+        config_file.write(contents);
+        config_file
+    }
+
+    pub fn write(&self, contents: &str) {
+        std::fs::write(&self.path, contents).expect("Could not write config file");
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
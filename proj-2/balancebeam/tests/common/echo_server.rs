@@ -83,6 +83,11 @@ impl EchoServer {
             address: bind_addr_string,
         }
     }
+
+    /// Returns the number of requests received so far, without stopping the server
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,181 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::{atomic, Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// How the server answers health checks
+struct HealthResponse {
+    status: u16,
+    body: String,
+    delay: Duration,
+    /// If set, health checks without this x-health-token header get 401 Unauthorized
+    token: Option<String>,
+}
+
+struct ServerState {
+    address: String,
+    health: Mutex<HealthResponse>,
+    /// Method of each health check received
+    health_checks: Mutex<Vec<String>>,
+    /// Status of the responses to any other request
+    status: atomic::AtomicU16,
+    requests_received: atomic::AtomicUsize,
+}
+
+/// An upstream whose answer to health checks (requests to /health) can be changed while it runs.
+/// Any other request gets "hello from <address>" (with a status that can also be changed), so tests
+/// can tell which upstream answered.
+pub struct HealthServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+async fn handle(
+    state: Arc<ServerState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    if req.uri().path() != "/health" {
+        state
+            .requests_received
+            .fetch_add(1, atomic::Ordering::SeqCst);
+        return Ok(Response::builder()
+            .status(state.status.load(atomic::Ordering::SeqCst))
+            .body(Body::from(format!("hello from {}", state.address)))
+            .unwrap());
+    }
+    let (status, body, delay, token) = {
+        let health = state.health.lock().unwrap();
+        (
+            health.status,
+            health.body.clone(),
+            health.delay,
+            health.token.clone(),
+        )
+    };
+    tokio::time::sleep(delay).await;
+    state
+        .health_checks
+        .lock()
+        .unwrap()
+        .push(req.method().to_string());
+    let authorized = token.is_none_or(|token| {
+        req.headers()
+            .get("x-health-token")
+            .is_some_and(|value| value.as_bytes() == token.as_bytes())
+    });
+    Ok(Response::builder()
+        .status(if authorized { status } else { 401 })
+        .body(Body::from(body))
+        .unwrap())
+}
+
+impl HealthServer {
+    pub async fn new() -> HealthServer {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let state = Arc::new(ServerState {
+            address: address.clone(),
+            health: Mutex::new(HealthResponse {
+                status: 200,
+                body: String::new(),
+                delay: Duration::ZERO,
+                token: None,
+            }),
+            health_checks: Mutex::new(Vec::new()),
+            status: atomic::AtomicU16::new(200),
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        handle(server_task_state.clone(), req)
+                    }))
+                }
+            });
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            if let Err(e) = server.await {
+                log::error!("Error in HealthServer: {}", e);
+            }
+        });
+        HealthServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            address,
+            state,
+        }
+    }
+
+    /// Changes the status and body that health checks get
+    pub fn set_health(&self, status: u16, body: &str) {
+        let mut health = self.state.health.lock().unwrap();
+        health.status = status;
+        health.body = body.to_string();
+    }
+
+    /// Makes health checks wait this long for a response
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.health.lock().unwrap().delay = delay;
+    }
+
+    /// Makes health checks fail unless they have this x-health-token header
+    pub fn require_token(&self, token: &str) {
+        self.state.health.lock().unwrap().token = Some(token.to_string());
+    }
+
+    /// Changes the status of the responses to requests other than health checks
+    pub fn set_status(&self, status: u16) {
+        self.state.status.store(status, atomic::Ordering::SeqCst);
+    }
+
+    /// Returns the number of requests other than health checks received so far
+    pub fn requests(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Returns the methods of the health checks received so far
+    pub fn health_checks(&self) -> Vec<String> {
+        self.state.health_checks.lock().unwrap().clone()
+    }
+
+    /// Waits until n health checks in total have been answered
+    pub async fn wait_for_health_checks(&self, n: usize) {
+        for _ in 0..200 {
+            if self.health_checks().len() >= n {
+                // Give balancebeam a moment to act on the result
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("Timed out waiting for {} health checks", n);
+    }
+}
+
+#[async_trait]
+impl Server for HealthServer {
+    async fn stop(self: Box<Self>) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task
+            .await
+            .expect("HealthServer server task panicked");
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
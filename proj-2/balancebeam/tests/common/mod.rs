@@ -1,14 +1,62 @@
 mod balancebeam;
+// Only some test crates use ChunkedServer
+#[allow(dead_code)]
+mod chunked_server;
+// Only some test crates use ConfigFile
+#[allow(dead_code)]
+mod config_file;
+// Only some test crates use EchoServer
+#[allow(dead_code)]
 mod echo_server;
 mod error_server;
+// Only some test crates use HealthServer
+#[allow(dead_code)]
+mod health_server;
+// Only some test crates read raw responses
+#[allow(dead_code)]
+mod raw_http;
+// Only some test crates use RedisServer
+#[allow(dead_code)]
+mod redis_server;
 mod server;
+// Only some test crates use the setup helpers
+#[allow(dead_code)]
+mod setup;
+// Only some test crates use TcpEchoServer
+#[allow(dead_code)]
+mod tcp_echo_server;
+// Only some test crates use TestCertificate
+#[allow(dead_code)]
+mod tls;
 
 use std::sync;
 
+#[allow(unused_imports)]
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use chunked_server::{expected_body, ChunkedServer};
+#[allow(unused_imports)]
+pub use config_file::ConfigFile;
+#[allow(unused_imports)]
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use health_server::HealthServer;
+#[allow(unused_imports)]
+pub use raw_http::{is_closed, read_response};
+#[allow(unused_imports)]
+pub use redis_server::RedisServer;
 pub use server::Server;
+#[allow(unused_imports)]
+pub use setup::{
+    free_address, upstreams_in_rotation, with_config, with_health_check, with_timeouts,
+    with_upstreams,
+};
+#[allow(unused_imports)]
+pub use tcp_echo_server::TcpEchoServer;
+#[allow(unused_imports)]
+pub use tls::TestCertificate;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
@@ -0,0 +1,46 @@
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+/// Reads one response from a connection to balancebeam, returning its head (status line and
+/// headers, lowercased) and its body. The body is delimited by Content-Length, or else by
+/// balancebeam closing the connection.
+pub async fn read_response(stream: &mut TcpStream) -> (String, String) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let byte = stream
+                .read_u8()
+                .await
+                .expect("Connection closed mid-response");
+            head.push(byte);
+        }
+        let head = String::from_utf8_lossy(&head).to_lowercase();
+        let content_length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map(|length| length.trim().parse::<usize>().unwrap());
+        let mut body = Vec::new();
+        match content_length {
+            Some(length) => {
+                body.resize(length, 0);
+                stream.read_exact(&mut body).await.unwrap();
+            }
+            None => {
+                stream.read_to_end(&mut body).await.unwrap();
+            }
+        }
+        (head, String::from_utf8_lossy(&body).to_string())
+    })
+    .await
+    .expect("Timed out waiting for a response")
+}
+
+/// Returns whether balancebeam has closed a connection (giving it a moment to do so)
+pub async fn is_closed(stream: &mut TcpStream) -> bool {
+    let mut buffer = [0_u8; 1];
+    matches!(
+        tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buffer)).await,
+        Ok(Ok(0)) | Ok(Err(_))
+    )
+}
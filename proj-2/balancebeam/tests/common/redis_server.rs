@@ -0,0 +1,125 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{atomic, Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A stand-in for a Redis server that understands just enough of the protocol for balancebeam's
+/// rate limit store: AUTH, INCRBY, PEXPIRE and GET. Keys never actually expire.
+pub struct RedisServer {
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+struct ServerState {
+    password: Option<String>,
+    values: Mutex<HashMap<String, i64>>,
+    increments_received: atomic::AtomicUsize,
+}
+
+impl RedisServer {
+    /// Starts a server on a free port, requiring the given password if any
+    pub async fn new(password: Option<&str>) -> RedisServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(ServerState {
+            password: password.map(str::to_string),
+            values: Mutex::new(HashMap::new()),
+            increments_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = state.clone();
+        let server_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, server_task_state.clone()));
+            }
+        });
+        RedisServer {
+            server_task,
+            address,
+            state,
+        }
+    }
+
+    /// Returns the value of a key, if it has been set
+    pub fn get(&self, key: &str) -> Option<i64> {
+        self.state.values.lock().unwrap().get(key).copied()
+    }
+
+    /// Returns all keys that have been set
+    pub fn keys(&self) -> Vec<String> {
+        self.state.values.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// Reads a command (an array of bulk strings), or returns None if the client hung up
+async fn read_command(stream: &mut BufStream<TcpStream>) -> Option<Vec<String>> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        stream.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).ok()?);
+    }
+    Some(args)
+}
+
+async fn serve(stream: TcpStream, state: Arc<ServerState>) {
+    let mut stream = BufStream::new(stream);
+    let mut authenticated = state.password.is_none();
+    while let Some(args) = read_command(&mut stream).await {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let reply = match args.as_slice() {
+            ["AUTH", password] => {
+                authenticated = state.password.as_deref() == Some(*password);
+                if authenticated {
+                    "+OK\r\n".to_string()
+                } else {
+                    "-WRONGPASS invalid password\r\n".to_string()
+                }
+            }
+            _ if !authenticated => "-NOAUTH Authentication required.\r\n".to_string(),
+            ["INCRBY", key, delta] => {
+                state
+                    .increments_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                let mut values = state.values.lock().unwrap();
+                let value = values.entry(key.to_string()).or_insert(0);
+                *value += delta.parse::<i64>().unwrap();
+                format!(":{}\r\n", value)
+            }
+            ["PEXPIRE", _, _] => ":1\r\n".to_string(),
+            ["GET", key] => match state.values.lock().unwrap().get(*key) {
+                Some(value) => format!("${}\r\n{}\r\n", value.to_string().len(), value),
+                None => "$-1\r\n".to_string(),
+            },
+            _ => "-ERR unknown command\r\n".to_string(),
+        };
+        if stream.write_all(reply.as_bytes()).await.is_err() || stream.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl Server for RedisServer {
+    /// Stops the server, returning the number of INCRBY commands it received
+    async fn stop(self: Box<Self>) -> usize {
+        self.server_task.abort();
+        let _ = self.server_task.await;
+        self.state
+            .increments_received
+            .load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
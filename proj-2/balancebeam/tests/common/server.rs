@@ -3,5 +3,6 @@ use async_trait::async_trait;
 #[async_trait]
 pub trait Server {
     async fn stop(self: Box<Self>) -> usize;
+    #[allow(dead_code)]
     fn address(&self) -> String;
 }
@@ -0,0 +1,82 @@
+use crate::common::balancebeam::BalanceBeam;
+use crate::common::config_file::ConfigFile;
+use crate::common::health_server::HealthServer;
+use std::collections::HashSet;
+
+/// Returns a local address that nothing is listening on, e.g. for balancebeam's admin API
+pub fn free_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Starts balancebeam with round-robin balancing over the given upstreams, health checking them
+/// every second with the given [health_check] settings. Each upstream comes with extra lines for
+/// its table (e.g. per-upstream overrides).
+pub async fn with_health_check(
+    upstreams: &[(&HealthServer, &str)],
+    health_check: &str,
+) -> (BalanceBeam, ConfigFile) {
+    let upstreams: Vec<(&str, &str)> = upstreams
+        .iter()
+        .map(|(upstream, upstream_settings)| (upstream.address.as_str(), *upstream_settings))
+        .collect();
+    with_upstreams(
+        "strategy = \"round-robin\"\n",
+        &upstreams,
+        &format!(
+            "[health_check]\ninterval = 1\npath = \"/health\"\n{}",
+            health_check
+        ),
+    )
+    .await
+}
+
+/// Sends a few requests and returns the addresses of the upstreams that answered them
+pub async fn upstreams_in_rotation(balancebeam: &BalanceBeam) -> HashSet<String> {
+    let mut answered_by = HashSet::new();
+    for _ in 0..4 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        answered_by.insert(response_text.trim_start_matches("hello from ").to_string());
+    }
+    answered_by
+}
+
+/// Starts balancebeam from a config file with the given upstreams and extra config (as TOML) after
+/// them. Top-level settings (e.g. `strategy`) go in `settings`, since TOML needs them before any
+/// table, and each upstream comes with extra lines for its table (e.g. its pool). The active
+/// health checks stay out of the way, unless `extra` has its own [health_check] table.
+pub async fn with_upstreams(
+    settings: &str,
+    upstreams: &[(&str, &str)],
+    extra: &str,
+) -> (BalanceBeam, ConfigFile) {
+    let mut config = format!("{}\n", settings);
+    for (address, upstream_settings) in upstreams {
+        config += &format!(
+            "[[upstreams]]\naddress = \"{}\"\n{}\n",
+            address, upstream_settings
+        );
+    }
+    if !extra.contains("[health_check]") {
+        config += "[health_check]\ninterval = 60\npath = \"/health\"\n\n";
+    }
+    config += extra;
+    let config = ConfigFile::new("toml", &config);
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", config.path()]).await;
+    (balancebeam, config)
+}
+
+/// Starts balancebeam in front of the given upstream with the given extra config (as TOML). The
+/// active health checks stay out of the way.
+pub async fn with_config(upstream: &str, extra: &str) -> (BalanceBeam, ConfigFile) {
+    with_upstreams("", &[(upstream, "")], extra).await
+}
+
+/// Starts balancebeam in front of the given upstream with the given [timeouts] settings. The
+/// active health checks stay out of the way.
+pub async fn with_timeouts(upstream: &str, timeouts: &str) -> (BalanceBeam, ConfigFile) {
+    with_config(upstream, &format!("[timeouts]\n{}", timeouts)).await
+}
@@ -0,0 +1,64 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A server that doesn't speak HTTP at all: it sends back whatever each connection sends it
+pub struct TcpEchoServer {
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    /// Connections that have sent something (as opposed to health checks, which only connect)
+    connections_used: Arc<atomic::AtomicUsize>,
+}
+
+impl TcpEchoServer {
+    /// Starts a server on a free port
+    pub async fn new() -> TcpEchoServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections_used = Arc::new(atomic::AtomicUsize::new(0));
+        let server_task_connections = connections_used.clone();
+        let server_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(echo(stream, server_task_connections.clone()));
+            }
+        });
+        TcpEchoServer {
+            server_task,
+            address,
+            connections_used,
+        }
+    }
+}
+
+async fn echo(mut stream: TcpStream, connections_used: Arc<atomic::AtomicUsize>) {
+    let mut buf = [0_u8; 4096];
+    let mut used = false;
+    while let Ok(n) = stream.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        if !used {
+            used = true;
+            connections_used.fetch_add(1, atomic::Ordering::SeqCst);
+        }
+        if stream.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[async_trait]
+impl Server for TcpEchoServer {
+    /// Stops the server, returning the number of connections that sent it something
+    async fn stop(self: Box<Self>) -> usize {
+        self.server_task.abort();
+        let _ = self.server_task.await;
+        self.connections_used.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,58 @@
+use rand::Rng;
+use std::path::PathBuf;
+
+/// A self-signed certificate and its private key, written to PEM files in the temp directory that
+/// are deleted when dropped
+pub struct TestCertificate {
+    pub cert_pem: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TestCertificate {
+    /// Generates a certificate that is valid for the given server names
+    pub fn new(server_names: &[&str]) -> TestCertificate {
+        let server_names: Vec<String> = server_names.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(server_names)
+            .expect("Could not generate certificate");
+        let id = rand::thread_rng().gen::<u64>();
+        let mut cert_path = std::env::temp_dir();
+        cert_path.push(format!("balancebeam-test-{}.crt", id));
+        let mut key_path = std::env::temp_dir();
+        key_path.push(format!("balancebeam-test-{}.key", id));
+        let cert_pem = generated.cert.pem();
+        std::fs::write(&cert_path, &cert_pem).expect("Could not write certificate");
+        std::fs::write(&key_path, generated.key_pair.serialize_pem()).expect("Could not write key");
+        TestCertificate {
+            cert_pem,
+            cert_path,
+            key_path,
+        }
+    }
+
+    pub fn cert_path(&self) -> &str {
+        self.cert_path.to_str().unwrap()
+    }
+
+    pub fn key_path(&self) -> &str {
+        self.key_path.to_str().unwrap()
+    }
+
+    /// Returns a client that only trusts this certificate, and that connects to the given address
+    /// whenever it is asked for server_name
+    pub fn client(&self, server_name: &str, address: &str) -> reqwest::Client {
+        reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(self.cert_pem.as_bytes()).unwrap())
+            .tls_built_in_root_certs(false)
+            .resolve(server_name, address.parse().unwrap())
+            .build()
+            .unwrap()
+    }
+}
+
+impl Drop for TestCertificate {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.cert_path);
+        let _ = std::fs::remove_file(&self.key_path);
+    }
+}